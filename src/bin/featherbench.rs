@@ -0,0 +1,725 @@
+//! featherbench runs YCSB-style workloads against the key-value storage engines, or against MVCC
+//! transactions on top of them, in a temporary directory, and reports the throughput and the
+//! latency percentiles of every kind of operation.
+//!
+//! ```text
+//! featherbench --engine lsm --workload a --records 100000 --operations 100000 --threads 4
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use featherengine::error::{Error, Result};
+use featherengine::storage::kv::{
+    Bitcask, DiskBPlusTree, KvStore, LsmStorage, Range, ShardedKvStore, StdBPlusTree,
+};
+use featherengine::{Mode, Transaction, MVCC};
+
+const USAGE: &str = "\
+Usage: featherbench [OPTIONS]
+
+Runs a YCSB workload against a storage engine in a temporary directory.
+
+Options:
+  --engine <ENGINE>        lsm, bitcask, bplustree, stdmemory or stdpersistent [default: lsm]
+  --shards <N>             Hash-partitions the keys across N instances of the engine [default: 1]
+  --mvcc                   Runs every operation in its own MVCC transaction
+  --serializable           Uses Serializable Snapshot Isolation for the MVCC transactions
+  --workload <WORKLOAD>    a (50% read, 50% update), b (95% read, 5% update), c (100% read),
+                           d (95% read latest, 5% insert), e (95% scan, 5% insert),
+                           f (50% read, 50% read-modify-write) [default: a]
+  --distribution <DIST>    zipfian, uniform or latest [default: the workload's]
+  --records <N>            Records loaded before the run [default: 100000]
+  --operations <N>         Operations of the run, split between the threads [default: 100000]
+  --threads <N>            Client threads [default: 1]
+  --value-size <BYTES>     Size of the values [default: 100]
+  --max-scan-length <N>    Maximum entries read by a scan [default: 100]
+  --seed <SEED>            Seed of the random generators [default: 0]
+  -h, --help               Prints this help
+";
+
+/// The storage engines a benchmark can run against.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Engine {
+    Lsm,
+    Bitcask,
+    BPlusTree,
+    StdMemory,
+    StdPersistent,
+}
+
+impl Engine {
+    /// Opens an empty store of the engine in the given directory.
+    fn open(self, dir: &Path) -> Result<Box<dyn KvStore>> {
+        Ok(match self {
+            Self::Lsm => Box::new(LsmStorage::open(dir)?),
+            Self::Bitcask => Box::new(Bitcask::open(dir)?),
+            Self::BPlusTree => {
+                std::fs::create_dir_all(dir)?;
+                Box::new(DiskBPlusTree::open(dir.join("tree"))?)
+            }
+            Self::StdMemory => Box::new(StdBPlusTree::new()),
+            Self::StdPersistent => Box::new(StdBPlusTree::open(dir)?),
+        })
+    }
+}
+
+impl FromStr for Engine {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lsm" => Ok(Self::Lsm),
+            "bitcask" => Ok(Self::Bitcask),
+            "bplustree" => Ok(Self::BPlusTree),
+            "stdmemory" => Ok(Self::StdMemory),
+            "stdpersistent" => Ok(Self::StdPersistent),
+            _ => Err(Error::Value(format!("Unknown engine {}", s))),
+        }
+    }
+}
+
+/// How the keys of the operations are picked among the records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Distribution {
+    /// A few keys are much hotter than the others.
+    Zipfian,
+    /// All keys are equally likely.
+    Uniform,
+    /// The most recently inserted keys are the hottest.
+    Latest,
+}
+
+impl FromStr for Distribution {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "zipfian" => Ok(Self::Zipfian),
+            "uniform" => Ok(Self::Uniform),
+            "latest" => Ok(Self::Latest),
+            _ => Err(Error::Value(format!("Unknown distribution {}", s))),
+        }
+    }
+}
+
+/// The kinds of operations of a workload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Op {
+    Read,
+    Update,
+    Insert,
+    Scan,
+    ReadModifyWrite,
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::Read => write!(f, "read"),
+            Op::Update => write!(f, "update"),
+            Op::Insert => write!(f, "insert"),
+            Op::Scan => write!(f, "scan"),
+            Op::ReadModifyWrite => write!(f, "read-modify-write"),
+        }
+    }
+}
+
+/// A YCSB core workload: the proportions of its operations and its default key distribution.
+#[derive(Clone, Debug, PartialEq)]
+struct Workload {
+    name: char,
+    mix: Vec<(Op, f64)>,
+    distribution: Distribution,
+}
+
+impl Workload {
+    /// Picks the next operation according to the mix.
+    fn pick(&self, rng: &mut StdRng) -> Op {
+        let mut point = rng.gen::<f64>();
+        for (op, proportion) in &self.mix {
+            if point < *proportion {
+                return *op;
+            }
+            point -= proportion;
+        }
+        self.mix.last().expect("a workload has operations").0
+    }
+}
+
+impl FromStr for Workload {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.to_ascii_lowercase();
+        let (mix, distribution) = match name.as_str() {
+            "a" => (vec![(Op::Read, 0.5), (Op::Update, 0.5)], Distribution::Zipfian),
+            "b" => (vec![(Op::Read, 0.95), (Op::Update, 0.05)], Distribution::Zipfian),
+            "c" => (vec![(Op::Read, 1.0)], Distribution::Zipfian),
+            "d" => (vec![(Op::Read, 0.95), (Op::Insert, 0.05)], Distribution::Latest),
+            "e" => (vec![(Op::Scan, 0.95), (Op::Insert, 0.05)], Distribution::Zipfian),
+            "f" => (vec![(Op::Read, 0.5), (Op::ReadModifyWrite, 0.5)], Distribution::Zipfian),
+            _ => return Err(Error::Value(format!("Unknown workload {}", s))),
+        };
+        Ok(Self { name: name.chars().next().unwrap_or('a'), mix, distribution })
+    }
+}
+
+/// The options of a benchmark, parsed from the command line.
+#[derive(Clone, Debug, PartialEq)]
+struct Options {
+    engine: Engine,
+    shards: usize,
+    mvcc: bool,
+    serializable: bool,
+    workload: Workload,
+    distribution: Distribution,
+    records: u64,
+    operations: u64,
+    threads: usize,
+    value_size: usize,
+    max_scan_length: usize,
+    seed: u64,
+}
+
+impl Options {
+    /// Parses the command line arguments, without the program name. Returns None for --help.
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Option<Self>> {
+        let mut args = args.into_iter();
+        let mut engine = Engine::Lsm;
+        let mut shards = 1;
+        let mut mvcc = false;
+        let mut serializable = false;
+        let mut workload: Workload = "a".parse()?;
+        let mut distribution = None;
+        let mut records = 100_000;
+        let mut operations = 100_000;
+        let mut threads = 1;
+        let mut value_size = 100;
+        let mut max_scan_length = 100;
+        let mut seed = 0;
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next().ok_or_else(|| Error::Value(format!("Missing value for {}", arg)))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--mvcc" => mvcc = true,
+                "--serializable" => serializable = true,
+                "--engine" => engine = value()?.parse()?,
+                "--shards" => shards = parse_number(&value()?)?,
+                "--workload" => workload = value()?.parse()?,
+                "--distribution" => distribution = Some(value()?.parse()?),
+                "--records" => records = parse_number(&value()?)?,
+                "--operations" => operations = parse_number(&value()?)?,
+                "--threads" => threads = parse_number(&value()?)?,
+                "--value-size" => value_size = parse_number(&value()?)?,
+                "--max-scan-length" => max_scan_length = parse_number(&value()?)?,
+                "--seed" => seed = parse_number(&value()?)?,
+                _ => return Err(Error::Value(format!("Unknown argument {}", arg))),
+            }
+        }
+        if shards == 0 || threads == 0 || max_scan_length == 0 {
+            return Err(Error::Value("Shards, threads and scan lengths must be positive".into()));
+        }
+        if records == 0 {
+            return Err(Error::Value("At least one record must be loaded".into()));
+        }
+        if serializable && !mvcc {
+            return Err(Error::Value("--serializable requires --mvcc".into()));
+        }
+        let distribution = distribution.unwrap_or(workload.distribution);
+        Ok(Some(Self {
+            engine, shards, mvcc, serializable, workload, distribution, records, operations,
+            threads, value_size, max_scan_length, seed,
+        }))
+    }
+}
+
+fn parse_number<T: FromStr>(s: &str) -> Result<T> {
+    s.parse().map_err(|_| Error::Value(format!("Invalid number {}", s)))
+}
+
+/// The YCSB zipfian generator (Gray et al., "Quickly Generating Billion-Record Synthetic
+/// Databases"), picking item 0 most often. The zeta constant is extended incrementally as
+/// records are inserted.
+struct Zipfian {
+    theta: f64,
+    alpha: f64,
+    zeta2: f64,
+    items: u64,
+    zetan: f64,
+}
+
+impl Zipfian {
+    const THETA: f64 = 0.99;
+
+    fn new(items: u64) -> Self {
+        let theta = Self::THETA;
+        let mut zipfian = Self {
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zeta2: 1.0 + 0.5f64.powf(theta),
+            items: 0,
+            zetan: 0.0,
+        };
+        zipfian.grow(items);
+        zipfian
+    }
+
+    fn grow(&mut self, items: u64) {
+        for i in self.items..items {
+            self.zetan += 1.0 / ((i + 1) as f64).powf(self.theta);
+        }
+        self.items = self.items.max(items);
+    }
+
+    /// Picks an item below the given number of items.
+    fn next(&mut self, rng: &mut StdRng, items: u64) -> u64 {
+        self.grow(items);
+        let eta = (1.0 - (2.0 / items as f64).powf(1.0 - self.theta))
+            / (1.0 - self.zeta2 / self.zetan);
+        let u = rng.gen::<f64>();
+        let uz = u * self.zetan;
+        let item = if uz < 1.0 {
+            0
+        } else if uz < self.zeta2 {
+            1
+        } else {
+            (items as f64 * (eta * u - eta + 1.0).powf(self.alpha)) as u64
+        };
+        item.min(items - 1)
+    }
+}
+
+/// Picks the records of the operations of a client thread.
+struct KeyChooser {
+    distribution: Distribution,
+    zipfian: Zipfian,
+}
+
+impl KeyChooser {
+    fn new(distribution: Distribution, records: u64) -> Self {
+        Self { distribution, zipfian: Zipfian::new(records) }
+    }
+
+    /// Picks a record number below the given number of records.
+    fn next(&mut self, rng: &mut StdRng, records: u64) -> u64 {
+        match self.distribution {
+            Distribution::Uniform => rng.gen_range(0..records),
+            // Like YCSB, the hot items are scattered across the key space.
+            Distribution::Zipfian => fnv1a(self.zipfian.next(rng, records)) % records,
+            Distribution::Latest => records - 1 - self.zipfian.next(rng, records),
+        }
+    }
+}
+
+fn fnv1a(n: u64) -> u64 {
+    n.to_be_bytes().iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The key of a record. Hashing the record number spreads inserts across the key space, so that
+/// the inserts of workloads D and E do not all append to the end of it.
+fn record_key(record: u64) -> Vec<u8> {
+    format!("user{:020}", fnv1a(record)).into_bytes()
+}
+
+fn random_value(rng: &mut StdRng, size: usize) -> Vec<u8> {
+    (0..size).map(|_| rng.gen_range(b'a'..=b'z')).collect()
+}
+
+/// The target of the operations: a plain store, or MVCC transactions on top of it.
+enum Target {
+    Store(Box<dyn KvStore>),
+    Mvcc(MVCC),
+}
+
+/// The outcome of an operation: the entries it read, and the transactions retried on
+/// serialization failures.
+#[derive(Default)]
+struct Outcome {
+    entries: usize,
+    retries: u64,
+}
+
+impl Target {
+    fn read(&self, key: &[u8]) -> Result<Outcome> {
+        let entries = match self {
+            Target::Store(store) => store.get(key)?,
+            Target::Mvcc(mvcc) => {
+                // Even read-only transactions are active until committed.
+                let txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+                let value = txn.get(key)?;
+                txn.commit()?;
+                value
+            }
+        };
+        Ok(Outcome { entries: entries.iter().count(), retries: 0 })
+    }
+
+    fn write(&self, key: &[u8], value: Vec<u8>) -> Result<Outcome> {
+        match self {
+            Target::Store(store) => {
+                store.set(key, value)?;
+                Ok(Outcome::default())
+            }
+            Target::Mvcc(mvcc) => Self::retry(mvcc, |txn| {
+                txn.set(key, value.clone())?;
+                Ok(0)
+            }),
+        }
+    }
+
+    fn scan(&self, key: &[u8], length: usize) -> Result<Outcome> {
+        let range = Range::from(key.to_vec()..);
+        let entries = match self {
+            Target::Store(store) => store.scan(range)?.take(length).try_fold(0, count)?,
+            Target::Mvcc(mvcc) => {
+                let txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+                let entries = txn.scan(key.to_vec()..)?.take(length).try_fold(0, count)?;
+                txn.commit()?;
+                entries
+            }
+        };
+        Ok(Outcome { entries, retries: 0 })
+    }
+
+    fn read_modify_write(&self, key: &[u8], value: Vec<u8>) -> Result<Outcome> {
+        match self {
+            Target::Store(store) => {
+                let entries = store.get(key)?.iter().count();
+                store.set(key, value)?;
+                Ok(Outcome { entries, retries: 0 })
+            }
+            Target::Mvcc(mvcc) => Self::retry(mvcc, |txn| {
+                let entries = txn.get(key)?.iter().count();
+                txn.set(key, value.clone())?;
+                Ok(entries)
+            }),
+        }
+    }
+
+    /// Runs a read-write transaction until it does not fail with a serialization error, rolling
+    /// back the failed attempts.
+    fn retry(mvcc: &MVCC, f: impl Fn(&Transaction) -> Result<usize>) -> Result<Outcome> {
+        let mut retries = 0;
+        loop {
+            let txn = mvcc.begin()?;
+            let id = txn.id();
+            let result = f(&txn).and_then(|entries| txn.commit().map(|_| entries));
+            match result {
+                Ok(entries) => return Ok(Outcome { entries, retries }),
+                Err(Error::Serialization) => {
+                    mvcc.resume(id)?.rollback()?;
+                    retries += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+fn count(entries: usize, entry: Result<(Vec<u8>, Vec<u8>)>) -> Result<usize> {
+    entry.map(|_| entries + 1)
+}
+
+/// The operations of a kind completed by the benchmark.
+#[derive(Default)]
+struct OpStats {
+    /// The latency of every operation, in nanoseconds.
+    latencies: Vec<u64>,
+    entries: u64,
+    retries: u64,
+}
+
+impl OpStats {
+    fn record(&mut self, latency: Duration, outcome: Outcome) {
+        self.latencies.push(latency.as_nanos() as u64);
+        self.entries += outcome.entries as u64;
+        self.retries += outcome.retries;
+    }
+
+    fn merge(&mut self, other: OpStats) {
+        self.latencies.extend(other.latencies);
+        self.entries += other.entries;
+        self.retries += other.retries;
+    }
+
+    /// Gets the latency at the given percentile. The latencies must be sorted.
+    fn percentile(&self, percentile: f64) -> Duration {
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        let latency = self.latencies[rank.clamp(1, self.latencies.len()) - 1];
+        Duration::from_nanos(latency)
+    }
+}
+
+/// Loads the records into the target with the given number of threads, returning the time it
+/// took.
+fn load(target: &Target, options: &Options) -> Result<Duration> {
+    let start = Instant::now();
+    let threads = (options.threads as u64).min(options.records);
+    std::thread::scope(|scope| {
+        let handles = (0..threads).map(|thread| {
+            scope.spawn(move || -> Result<()> {
+                let mut rng = StdRng::seed_from_u64(options.seed.wrapping_add(thread));
+                for record in (thread..options.records).step_by(threads as usize) {
+                    target.write(&record_key(record), random_value(&mut rng, options.value_size))?;
+                }
+                Ok(())
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter()
+            .try_for_each(|handle| handle.join().expect("the load thread panicked"))
+    })?;
+    match target {
+        Target::Store(store) => store.flush()?,
+        Target::Mvcc(_) => {}
+    }
+    Ok(start.elapsed())
+}
+
+/// Runs the operations of the workload on the loaded target, returning the time it took and the
+/// statistics of every kind of operation.
+fn run(target: &Target, options: &Options) -> Result<(Duration, BTreeMap<Op, OpStats>)> {
+    // The records acknowledged so far; inserted records are only read once acknowledged.
+    let records = AtomicU64::new(options.records);
+    let next_record = AtomicU64::new(options.records);
+    let threads = options.threads as u64;
+    let start = Instant::now();
+    let results = std::thread::scope(|scope| {
+        let (records, next_record) = (&records, &next_record);
+        let handles = (0..threads).map(|thread| {
+            scope.spawn(move || -> Result<BTreeMap<Op, OpStats>> {
+                let seed = options.seed.wrapping_add(threads).wrapping_add(thread);
+                let mut rng = StdRng::seed_from_u64(seed);
+                let mut chooser = KeyChooser::new(options.distribution, options.records);
+                let mut stats = BTreeMap::<Op, OpStats>::new();
+                let operations = options.operations / threads
+                    + (thread < options.operations % threads) as u64;
+                for _ in 0..operations {
+                    let op = options.workload.pick(&mut rng);
+                    let key = record_key(chooser.next(&mut rng, records.load(Ordering::Acquire)));
+                    let start = Instant::now();
+                    let outcome = match op {
+                        Op::Read => target.read(&key)?,
+                        Op::Update => {
+                            target.write(&key, random_value(&mut rng, options.value_size))?
+                        }
+                        Op::Insert => {
+                            let record = next_record.fetch_add(1, Ordering::AcqRel);
+                            let value = random_value(&mut rng, options.value_size);
+                            let outcome = target.write(&record_key(record), value)?;
+                            records.fetch_max(record + 1, Ordering::AcqRel);
+                            outcome
+                        }
+                        Op::Scan => {
+                            let length = rng.gen_range(1..=options.max_scan_length);
+                            target.scan(&key, length)?
+                        }
+                        Op::ReadModifyWrite => {
+                            let value = random_value(&mut rng, options.value_size);
+                            target.read_modify_write(&key, value)?
+                        }
+                    };
+                    stats.entry(op).or_default().record(start.elapsed(), outcome);
+                }
+                Ok(stats)
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| handle.join().expect("the client thread panicked"))
+            .collect::<Result<Vec<_>>>()
+    })?;
+    let elapsed = start.elapsed();
+
+    let mut stats = BTreeMap::<Op, OpStats>::new();
+    for (op, op_stats) in results.into_iter().flatten() {
+        stats.entry(op).or_default().merge(op_stats);
+    }
+    for op_stats in stats.values_mut() {
+        op_stats.latencies.sort_unstable();
+    }
+    Ok((elapsed, stats))
+}
+
+/// Formats a throughput in operations per second.
+fn throughput(operations: u64, elapsed: Duration) -> String {
+    format!("{:.0} ops/s", operations as f64 / elapsed.as_secs_f64().max(f64::EPSILON))
+}
+
+fn report(
+    options: &Options,
+    load_time: Duration,
+    run_time: Duration,
+    stats: &BTreeMap<Op, OpStats>,
+) {
+    println!(
+        "engine {:?}, {} shard(s){}, workload {}, {:?} keys, {} thread(s)",
+        options.engine,
+        options.shards,
+        match (options.mvcc, options.serializable) {
+            (false, _) => "",
+            (true, false) => ", MVCC snapshot isolation",
+            (true, true) => ", MVCC serializable",
+        },
+        options.workload.name.to_ascii_uppercase(),
+        options.distribution,
+        options.threads,
+    );
+    println!(
+        "load: {} records in {:.3?} ({})",
+        options.records, load_time, throughput(options.records, load_time),
+    );
+    println!(
+        "run: {} operations in {:.3?} ({})",
+        options.operations, run_time, throughput(options.operations, run_time),
+    );
+    println!(
+        "{:<18} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "operation", "count", "avg", "p50", "p95", "p99", "max", "retries",
+    );
+    for (op, op_stats) in stats {
+        let count = op_stats.latencies.len() as u64;
+        let total = op_stats.latencies.iter().sum::<u64>();
+        let avg = Duration::from_nanos(total / count.max(1));
+        println!(
+            "{:<18} {:>10} {:>10.1?} {:>10.1?} {:>10.1?} {:>10.1?} {:>10.1?} {:>10}",
+            op.to_string(),
+            count,
+            avg,
+            op_stats.percentile(50.0),
+            op_stats.percentile(95.0),
+            op_stats.percentile(99.0),
+            op_stats.percentile(100.0),
+            op_stats.retries,
+        );
+    }
+}
+
+/// Opens the target of the benchmark in the given directory.
+fn open(options: &Options, dir: &Path) -> Result<Target> {
+    let mut shards = (0..options.shards)
+        .map(|shard| options.engine.open(&dir.join(format!("shard-{}", shard))))
+        .collect::<Result<Vec<_>>>()?;
+    let store = match shards.len() {
+        1 => shards.remove(0),
+        _ => Box::new(ShardedKvStore::hash(shards)?),
+    };
+    Ok(match options.mvcc {
+        true => Target::Mvcc(MVCC::new(store, options.serializable)),
+        false => Target::Store(store),
+    })
+}
+
+fn bench(options: &Options) -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let target = open(options, dir.path())?;
+    let load_time = load(&target, options)?;
+    let (run_time, stats) = run(&target, options)?;
+    report(options, load_time, run_time, &stats);
+    // The stores are closed before their directory is removed.
+    drop(target);
+    dir.close()?;
+    Ok(())
+}
+
+fn main() {
+    let result = Options::parse(std::env::args().skip(1)).and_then(|options| match options {
+        Some(options) => bench(&options),
+        None => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+    });
+    if let Err(err) = result {
+        eprintln!("featherbench: {}", err);
+        std::process::exit(1);
+    }
+}
+
+
+
+#[cfg(test)]
+fn args(args: &str) -> Vec<String> {
+    args.split_whitespace().map(String::from).collect()
+}
+
+#[test]
+fn test_parse_options() -> Result<()> {
+    let options = Options::parse(args("--engine bitcask --workload D --threads 2 --mvcc"))?
+        .expect("not --help");
+    assert_eq!(options.engine, Engine::Bitcask);
+    assert_eq!(options.workload.name, 'd');
+    assert_eq!(options.distribution, Distribution::Latest);
+    assert_eq!(options.threads, 2);
+    assert!(options.mvcc);
+
+    let options = Options::parse(args("--workload e --distribution uniform"))?.expect("not --help");
+    assert_eq!(options.distribution, Distribution::Uniform);
+    assert_eq!(options.engine, Engine::Lsm);
+
+    assert_eq!(Options::parse(args("--records 10 -h"))?, None);
+    assert!(Options::parse(args("--engine rocksdb")).is_err());
+    assert!(Options::parse(args("--workload g")).is_err());
+    assert!(Options::parse(args("--records")).is_err());
+    assert!(Options::parse(args("--threads 0")).is_err());
+    assert!(Options::parse(args("--serializable")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_key_distributions() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut zipfian = Zipfian::new(1000);
+    let mut counts = vec![0; 1000];
+    for _ in 0..100_000 {
+        counts[zipfian.next(&mut rng, 1000) as usize] += 1;
+    }
+    // The first items are by far the most popular ones.
+    assert!(counts[0] > counts[1] && counts[1] > counts[10] && counts[10] > counts[999]);
+    assert!(counts[0] > 10_000);
+
+    // Inserted records become the hottest with the latest distribution.
+    let mut latest = KeyChooser::new(Distribution::Latest, 1000);
+    let picks = (0..1000).map(|_| latest.next(&mut rng, 2000)).collect::<Vec<_>>();
+    assert!(picks.iter().all(|record| *record < 2000));
+    assert!(picks.iter().filter(|record| **record >= 1990).count() > 300);
+
+    let mut uniform = KeyChooser::new(Distribution::Uniform, 1000);
+    assert!((0..1000).all(|_| uniform.next(&mut rng, 10) < 10));
+}
+
+#[test]
+fn test_bench_workloads() -> Result<()> {
+    for workload in ["a", "b", "c", "d", "e", "f"] {
+        for target in ["--engine lsm --shards 2", "--engine stdmemory --mvcc --serializable"] {
+            let options = Options::parse(args(&format!(
+                "{} --workload {} --records 200 --operations 400 --threads 2",
+                target, workload,
+            )))?.expect("not --help");
+            let dir = tempfile::tempdir()?;
+            let target = open(&options, dir.path())?;
+            load(&target, &options)?;
+            let (_, stats) = run(&target, &options)?;
+            assert_eq!(stats.values().map(|s| s.latencies.len()).sum::<usize>(), 400);
+            for (op, op_stats) in &stats {
+                assert!(options.workload.mix.iter().any(|(mix_op, _)| mix_op == op));
+                assert!(op_stats.latencies.windows(2).all(|w| w[0] <= w[1]));
+            }
+            if workload == "c" {
+                // Every read finds its record.
+                assert_eq!(stats[&Op::Read].entries, 400);
+            }
+        }
+    }
+    Ok(())
+}
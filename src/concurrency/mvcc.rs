@@ -5,7 +5,7 @@ use dashmap::DashMap;
 use parking_lot::RwLock;
 
 use crate::error::{Result, Error};
-use crate::storage::kv::KvStore;
+use crate::storage::kv::{format_raw_key, KvStore};
 use super::{Mode, Transaction};
 
 /// An MVCC-based transactional key-value store.
@@ -57,6 +57,26 @@ impl MVCC {
         let session = self.store.write();
         session.set(&MvccKey::Metadata(key.into()).encode(), value)
     }
+
+    /// Formats a raw key of the underlying store, decoding it as an MVCC key if possible. It is
+    /// a `KeyFormatter` for `InstrumentedKvStore`.
+    pub fn format_key(key: &[u8]) -> String {
+        use super::transaction::MvccKey;
+        match MvccKey::decode(key) {
+            Ok(MvccKey::TxnNext) => "TxnNext".into(),
+            Ok(MvccKey::TxnActive(id)) => format!("TxnActive({})", id),
+            Ok(MvccKey::TxnSnapshot(version)) => format!("TxnSnapshot({})", version),
+            // The key of an update marker is the encoded key it updated.
+            Ok(MvccKey::TxnUpdate(id, key)) => {
+                format!("TxnUpdate({}, {})", id, Self::format_key(&key))
+            }
+            Ok(MvccKey::Record(key, version)) => {
+                format!("Record({}, {})", format_raw_key(&key), version)
+            }
+            Ok(MvccKey::Metadata(key)) => format!("Metadata({})", format_raw_key(&key)),
+            Err(_) => format_raw_key(key),
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -2,7 +2,9 @@
 use tempfile::{tempdir, TempDir};
 
 use super::*;
-use crate::storage::kv::LsmStorage;
+use crate::storage::kv::{
+    InstrumentedKvStore, InstrumentedKvStoreOptions, KvOp, LsmStorage, StdBPlusTree,
+};
 use crate::error::{Result, Error};
 
 fn setup() -> Result<(MVCC, TempDir)> {
@@ -674,4 +676,34 @@ fn test_metadata() -> Result<()> {
     mvcc.set_metadata(b"foo", b"baz".to_vec())?;
     assert_eq!(Some(b"baz".to_vec()), mvcc.get_metadata(b"foo")?);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_instrumented_store_keys() -> Result<()> {
+    let store = InstrumentedKvStore::with_options(
+        Box::new(StdBPlusTree::new()),
+        InstrumentedKvStoreOptions {
+            trace_capacity: 100,
+            key_formatter: MVCC::format_key,
+            ..Default::default()
+        },
+    );
+    let mvcc = MVCC::new(Box::new(store.clone()), false);
+    let txn = mvcc.begin()?;
+    store.clear_traces();
+    txn.set(b"a", b"1".to_vec())?;
+    txn.commit()?;
+
+    let writes = store.traces().into_iter()
+        .filter(|trace| trace.op == KvOp::Set || trace.op == KvOp::Delete)
+        .map(|trace| format!("{} {}", trace.op, trace.key))
+        .collect::<Vec<_>>();
+    assert_eq!(writes, vec![
+        "set TxnUpdate(1, Record(\"a\", 1))",
+        "set Record(\"a\", 1)",
+        "delete TxnActive(1)",
+    ]);
+    assert!(store.stats(KvOp::Set).calls >= 4);
+    assert_eq!(MVCC::format_key(b"\xfe"), "\"\\xfe\"");
+    Ok(())
+}
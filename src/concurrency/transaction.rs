@@ -263,23 +263,8 @@ impl Transaction {
         if prefix.is_empty() {
             return Err(Error::Internal("Scan prefix cannot be empty".into()));
         }
-        let start = prefix.to_vec();
-        let mut end = start.clone();
-        for i in (0..end.len()).rev() {
-            match end[i] {
-                // If all 0xff we could in principle use Range::Unbounded, but it won't happen
-                0xff if i == 0 => return Err(Error::Internal("Invalid prefix scan range".into())),
-                0xff => {
-                    end[i] = 0x00;
-                    continue;
-                }
-                v => {
-                    end[i] = v + 1;
-                    break;
-                }
-            }
-        }
-        self.scan(start..end)
+        let scan = self.store.read().scan_prefix(&MvccKey::encode_record_prefix(prefix))?;
+        Ok(Box::new(MvccScan::new(scan, self.snapshot.clone())))
     }
 }
 
@@ -386,8 +371,16 @@ impl<'a> MvccKey<'a> {
         }
     }
 
+    /// Encodes the prefix shared by the `Record`s of all keys starting with `prefix`.
+    fn encode_record_prefix(prefix: &[u8]) -> Vec<u8> {
+        let mut encoded = MvccKey::Record(prefix.into(), 0).encode();
+        // Strip the key terminator (2B) and the version (8B).
+        encoded.truncate(encoded.len() - 10);
+        encoded
+    }
+
     /// Decodes a key from a byte representation.
-    fn decode(mut bytes: &[u8]) -> Result<Self> {
+    pub(super) fn decode(mut bytes: &[u8]) -> Result<Self> {
         use crate::encoding::*;
         let bytes = &mut bytes;
         let key = match take_byte(bytes)? {
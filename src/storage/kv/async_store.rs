@@ -0,0 +1,237 @@
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use futures::Stream;
+use parking_lot::Mutex;
+
+use crate::error::{Error, Result};
+use super::{KvScan, KvStore, LsmStorage, Range};
+
+/// Stream over a key/value range.
+pub type KvStream = Pin<Box<dyn Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>>;
+
+/// Number of entries a scan stream reads per job on the blocking pool.
+const SCAN_BATCH_SIZE: usize = 128;
+
+/// A key/value store for async callers, whose operations never block the executor.
+pub trait AsyncKvStore: Send + Sync {
+    /// Sets a value for a key, replacing the existing value if any.
+    fn set(&self, key: &[u8], value: Vec<u8>) -> impl Future<Output = Result<()>> + Send;
+
+    /// Gets a value for a key, if it exists.
+    fn get(&self, key: &[u8]) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    /// Deletes a key, doing nothing if it does not exist.
+    fn delete(&self, key: &[u8]) -> impl Future<Output = Result<()>> + Send;
+
+    /// Streams an ordered range of key/value pairs.
+    fn scan(&self, range: Range) -> KvStream;
+
+    /// Flushes any buffered data to the underlying storage medium.
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send;
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A dedicated pool of threads running blocking jobs for async callers, independent of the
+/// runtime the callers use.
+struct BlockingPool {
+    sender: Mutex<Option<std::sync::mpsc::Sender<Job>>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl BlockingPool {
+    fn new(num_threads: usize) -> Self {
+        let (sender, receiver) = std::sync::mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..num_threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                std::thread::spawn(move || loop {
+                    // Release the receiver before running the job, so other threads can pick
+                    // up the next ones.
+                    let job = receiver.lock().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        Self { sender: Mutex::new(Some(sender)), threads }
+    }
+
+    /// Runs a blocking job on the pool, resolving to its result. A panicking job resolves to an
+    /// error, and does not take its thread down with it.
+    fn run<R: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> R + Send + 'static,
+    ) -> impl Future<Output = Result<R>> + Send + 'static {
+        let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+        let sent = match self.sender.lock().as_ref() {
+            Some(sender) => sender.send(Box::new(move || {
+                let _ = result_sender.send(std::panic::catch_unwind(AssertUnwindSafe(job)));
+            })).is_ok(),
+            None => false,
+        };
+        async move {
+            match sent {
+                true => result_receiver.await?.map_err(|panic| {
+                    let message = match panic.downcast_ref::<&str>() {
+                        Some(message) => message.to_string(),
+                        None => panic.downcast_ref::<String>().cloned().unwrap_or_default(),
+                    };
+                    Error::Internal(format!("Blocking job panicked: {}", message))
+                }),
+                false => Err(Error::Internal("Blocking pool is shut down".into())),
+            }
+        }
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        self.sender.lock().take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// An `LsmStorage` for async callers. Every operation, including reading the blocks of a scan,
+/// runs on a dedicated pool of threads, so that tokio executor threads never wait for the disk.
+pub struct AsyncLsmStorage {
+    storage: Arc<LsmStorage>,
+    pool: Arc<BlockingPool>,
+}
+
+impl AsyncLsmStorage {
+    /// Wraps a storage, running its operations on a pool of `num_threads` threads.
+    pub fn new(storage: LsmStorage, num_threads: usize) -> Self {
+        Self { storage: Arc::new(storage), pool: Arc::new(BlockingPool::new(num_threads)) }
+    }
+
+    /// Gets the underlying storage, e.g. for blocking maintenance operations.
+    pub fn storage(&self) -> &Arc<LsmStorage> {
+        &self.storage
+    }
+
+    /// Runs a blocking operation on the storage in the pool.
+    fn run<R: Send + 'static>(
+        &self,
+        op: impl FnOnce(&LsmStorage) -> Result<R> + Send + 'static,
+    ) -> impl Future<Output = Result<R>> + Send + 'static {
+        let storage = self.storage.clone();
+        let result = self.pool.run(move || op(&storage));
+        async move { result.await? }
+    }
+}
+
+impl AsyncKvStore for AsyncLsmStorage {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> impl Future<Output = Result<()>> + Send {
+        let key = key.to_vec();
+        self.run(move |storage| storage.set(&key, value))
+    }
+
+    fn get(&self, key: &[u8]) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send {
+        let key = key.to_vec();
+        self.run(move |storage| storage.get(&key))
+    }
+
+    fn delete(&self, key: &[u8]) -> impl Future<Output = Result<()>> + Send {
+        let key = key.to_vec();
+        self.run(move |storage| storage.delete(&key))
+    }
+
+    /// Streams the range in batches of `SCAN_BATCH_SIZE` entries, each read on the pool.
+    fn scan(&self, range: Range) -> KvStream {
+        use futures::StreamExt;
+
+        let storage = self.storage.clone();
+        let pool = self.pool.clone();
+        let batches = futures::stream::unfold(None, move |state: Option<Option<KvScan>>| {
+            let (storage, pool) = (storage.clone(), pool.clone());
+            let range = range.clone();
+            async move {
+                // The state is None before the scan starts, and Some(None) once it ended.
+                let scan = match state {
+                    Some(None) => return None,
+                    Some(Some(scan)) => Some(scan),
+                    None => None,
+                };
+                let batch = pool.run(move || {
+                    let mut scan = match scan {
+                        Some(scan) => scan,
+                        None => storage.scan(range)?,
+                    };
+                    let batch = scan.by_ref().take(SCAN_BATCH_SIZE).collect::<Result<Vec<_>>>()?;
+                    let scan = (batch.len() == SCAN_BATCH_SIZE).then_some(scan);
+                    Ok((batch, scan))
+                }).await;
+                match batch {
+                    Ok(Ok((batch, scan))) => Some((Ok(batch), Some(scan))),
+                    Ok(Err(err)) | Err(err) => Some((Err(err), Some(None))),
+                }
+            }
+        });
+        Box::pin(batches.flat_map(|batch| {
+            let entries = match batch {
+                Ok(batch) => batch.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(err) => vec![Err(err)],
+            };
+            futures::stream::iter(entries)
+        }))
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send {
+        self.run(|storage| storage.flush())
+    }
+}
+
+
+
+#[cfg(test)]
+use futures::StreamExt;
+
+#[test]
+fn test_async_lsm_storage() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let storage = AsyncLsmStorage::new(LsmStorage::open(&dir)?, 2);
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        for i in 0..300_u32 {
+            storage.set(&i.to_be_bytes(), i.to_string().into_bytes()).await?;
+        }
+        storage.delete(&7_u32.to_be_bytes()).await?;
+        storage.flush().await?;
+        assert_eq!(storage.get(&1_u32.to_be_bytes()).await?, Some(b"1".to_vec()));
+        assert_eq!(storage.get(&7_u32.to_be_bytes()).await?, None);
+
+        // Scans are streamed in several batches.
+        let entries = storage.scan(Range::from(..)).collect::<Vec<_>>().await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 299);
+        assert_eq!(entries[7], (8_u32.to_be_bytes().to_vec(), b"8".to_vec()));
+        let range = Range::from(10_u32.to_be_bytes().to_vec()..20_u32.to_be_bytes().to_vec());
+        assert_eq!(storage.scan(range).count().await, 10);
+        Ok(())
+    })
+}
+
+#[test]
+fn test_blocking_pool_panic() -> Result<()> {
+    let pool = BlockingPool::new(1);
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        let result = pool.run(|| -> u32 { panic!("job failed") }).await;
+        assert!(matches!(result, Err(Error::Internal(message)) if message.contains("job failed")));
+
+        // The only thread of the pool survives the panic.
+        assert_eq!(pool.run(|| 1).await?, 1);
+        Ok(())
+    })
+}
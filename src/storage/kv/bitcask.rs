@@ -0,0 +1,722 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut};
+use parking_lot::{Condvar, Mutex, RwLock};
+
+use super::{KvScan, KvStore, Range};
+use crate::error::{Error, Result};
+use crate::storage::log::{LogReader, LogWriter, RecordPos};
+
+const ENTRY_VALUE: u8 = 0x00;
+const ENTRY_TOMBSTONE: u8 = 0x01;
+
+/// Name of the file listing the data files a merge replaced, until they are all deleted.
+const MERGE_MARKER: &str = "MERGE";
+
+/// Options for opening a `Bitcask`.
+#[derive(Clone, Debug)]
+pub struct BitcaskOptions {
+    /// Size at which the active data file is closed and a new one started, in bytes.
+    pub max_file_size: u64,
+    /// Ratio of stale bytes in the closed data files that starts a merge in the background when
+    /// a data file is closed. None only merges on `merge`.
+    pub merge_trigger: Option<f64>,
+    /// Syncs the active data file after every write.
+    pub sync_writes: bool,
+}
+
+impl Default for BitcaskOptions {
+    fn default() -> Self {
+        Self { max_file_size: 64 << 20, merge_trigger: Some(0.5), sync_writes: false }
+    }
+}
+
+/// The location of the latest entry of a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Location {
+    file_id: u64,
+    pos: RecordPos,
+    seq: u64,
+}
+
+impl Location {
+    /// Bytes taken by the entry in its data file.
+    fn record_size(&self) -> u64 {
+        self.pos.len as u64 + 4
+    }
+}
+
+/// An entry of a data file, which is a record of the `storage::log` format. Sequence numbers
+/// order the entries across data files, as merges rewrite old entries to new files.
+///
+/// Data alignment:
+///
+/// ```text
+///     | seq (8B) | kind (1B) | key_len (4B) | key | value |
+/// ```
+struct Entry<'a> {
+    seq: u64,
+    tombstone: bool,
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(13 + self.key.len() + self.value.len());
+        buffer.put_u64(self.seq);
+        buffer.put_u8(if self.tombstone { ENTRY_TOMBSTONE } else { ENTRY_VALUE });
+        buffer.put_u32(self.key.len() as u32);
+        buffer.put_slice(self.key);
+        buffer.put_slice(self.value);
+        buffer
+    }
+
+    fn decode(mut payload: &'a [u8]) -> Result<Self> {
+        let invalid = || Error::Internal("Invalid Bitcask entry".into());
+        if payload.len() < 13 {
+            return Err(invalid());
+        }
+        let seq = payload.get_u64();
+        let tombstone = match payload.get_u8() {
+            ENTRY_VALUE => false,
+            ENTRY_TOMBSTONE => true,
+            _ => return Err(invalid()),
+        };
+        let key_len = payload.get_u32() as usize;
+        if payload.len() < key_len {
+            return Err(invalid());
+        }
+        let (key, value) = payload.split_at(key_len);
+        Ok(Self { seq, tombstone, key, value })
+    }
+}
+
+/// A hint file entry, pointing at a live entry of the data file with the same ID. Merges write
+/// hint files along with their data files, so that opening the store reads the keys only.
+///
+/// Data alignment:
+///
+/// ```text
+///     | seq (8B) | offset (8B) | len (4B) | key |
+/// ```
+fn encode_hint(key: &[u8], location: &Location) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(20 + key.len());
+    buffer.put_u64(location.seq);
+    buffer.put_u64(location.pos.offset);
+    buffer.put_u32(location.pos.len);
+    buffer.put_slice(key);
+    buffer
+}
+
+fn decode_hint(file_id: u64, mut hint: &[u8]) -> Result<(Vec<u8>, Location)> {
+    if hint.len() < 20 {
+        return Err(Error::Internal(format!("Invalid hint in Bitcask file {}", file_id)));
+    }
+    let seq = hint.get_u64();
+    let pos = RecordPos { offset: hint.get_u64(), len: hint.get_u32() };
+    Ok((hint.to_vec(), Location { file_id, pos, seq }))
+}
+
+struct State {
+    /// The location of every live key.
+    keydir: HashMap<Vec<u8>, Location>,
+    /// The live keys in order, for scans.
+    keys: BTreeSet<Vec<u8>>,
+    /// Readers of all data files, including the active one.
+    readers: BTreeMap<u64, Arc<LogReader>>,
+    /// Bytes of live entries per data file.
+    live_bytes: HashMap<u64, u64>,
+    /// Size of the closed data files.
+    file_sizes: HashMap<u64, u64>,
+    active_id: u64,
+    active: LogWriter,
+    next_file_id: u64,
+    next_seq: u64,
+}
+
+struct BitcaskInner {
+    path: PathBuf,
+    options: BitcaskOptions,
+    state: RwLock<State>,
+    /// Serializes merges.
+    merge_lock: Mutex<()>,
+    /// The error of the last background merge, returned by the next flush.
+    merge_error: Mutex<Option<Error>>,
+    /// Set when the store is dropped, so that no background merge starts after it.
+    closed: AtomicBool,
+    /// Number of background merge threads not finished yet.
+    background_merges: Mutex<usize>,
+    /// Notified when the last background merge thread finishes.
+    background_merges_done: Condvar,
+}
+
+/// A log-structured hash table (as in Bitcask): writes are appended to the active data file,
+/// and an in-memory key directory points at the latest entry of every key, so that a lookup
+/// reads the disk once. An ordered index of the keys serves scans. Stale entries are dropped by
+/// merging the closed data files into new ones, along with hint files for a fast startup.
+///
+/// Every key must fit in memory, which suits small key spaces with heavy point lookups.
+pub struct Bitcask {
+    inner: Arc<BitcaskInner>,
+}
+
+impl Bitcask {
+    /// Opens or creates a store in the given directory with the default options.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(path, BitcaskOptions::default())
+    }
+
+    /// Opens or creates a store in the given directory, reading the hint files, or the data
+    /// files without one, to rebuild the key directory. Writes go to a new data file.
+    pub fn open_with_options(path: impl AsRef<Path>, options: BitcaskOptions) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        std::fs::create_dir_all(&path)?;
+        BitcaskInner::finish_merge(&path)?;
+
+        let mut file_ids = vec![];
+        for entry in std::fs::read_dir(&path)? {
+            let file_name = entry?.file_name();
+            let file_name = file_name.to_string_lossy();
+            if let Some(Ok(id)) = file_name.strip_suffix(".data").map(|id| id.parse::<u64>()) {
+                file_ids.push(id);
+            }
+        }
+        file_ids.sort_unstable();
+
+        // The entry with the highest sequence number of each key wins, tombstones included.
+        let mut latest: HashMap<Vec<u8>, (Location, bool)> = HashMap::new();
+        let mut readers = BTreeMap::new();
+        let mut file_sizes = HashMap::new();
+        for file_id in file_ids.iter().copied() {
+            let reader = LogReader::open(BitcaskInner::path_of_data(&path, file_id))?;
+            let hint_path = BitcaskInner::path_of_hint(&path, file_id);
+            let mut entries = vec![];
+            match hint_path.exists() {
+                true => {
+                    for record in LogReader::open(&hint_path)?.iter()? {
+                        let (key, location) = decode_hint(file_id, &record?.1)?;
+                        entries.push((key, location, false));
+                    }
+                }
+                false => {
+                    for record in reader.iter()? {
+                        let (pos, payload) = record?;
+                        let entry = Entry::decode(&payload)?;
+                        let location = Location { file_id, pos, seq: entry.seq };
+                        entries.push((entry.key.to_vec(), location, entry.tombstone));
+                    }
+                }
+            }
+            for (key, location, tombstone) in entries {
+                match latest.get(&key) {
+                    Some((existing, _)) if existing.seq >= location.seq => {}
+                    _ => { latest.insert(key, (location, tombstone)); }
+                }
+            }
+            let data_path = BitcaskInner::path_of_data(&path, file_id);
+            file_sizes.insert(file_id, std::fs::metadata(data_path)?.len());
+            readers.insert(file_id, Arc::new(reader));
+        }
+
+        let next_seq = latest.values().map(|(location, _)| location.seq + 1).max().unwrap_or(1);
+        let mut keydir = HashMap::new();
+        let mut live_bytes = HashMap::new();
+        for (key, (location, tombstone)) in latest {
+            if !tombstone {
+                *live_bytes.entry(location.file_id).or_insert(0) += location.record_size();
+                keydir.insert(key, location);
+            }
+        }
+        let keys = keydir.keys().cloned().collect();
+        let active_id = file_ids.last().map_or(1, |id| id + 1);
+        let active = LogWriter::create(BitcaskInner::path_of_data(&path, active_id))?;
+        readers.insert(active_id, Arc::new(LogReader::open(active.path())?));
+        let state = State {
+            keydir,
+            keys,
+            readers,
+            live_bytes,
+            file_sizes,
+            active_id,
+            active,
+            next_file_id: active_id + 1,
+            next_seq,
+        };
+        Ok(Self {
+            inner: Arc::new(BitcaskInner {
+                path,
+                options,
+                state: RwLock::new(state),
+                merge_lock: Mutex::new(()),
+                merge_error: Mutex::new(None),
+                closed: AtomicBool::new(false),
+                background_merges: Mutex::new(0),
+                background_merges_done: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Merges the closed data files, and the active one which is closed first, into new data
+    /// files with only the live entries, deleting the old files.
+    pub fn merge(&self) -> Result<()> {
+        let _merge_guard = self.inner.merge_lock.lock();
+        self.inner.merge()
+    }
+
+    /// IDs of the data files, for tests.
+    #[cfg(test)]
+    fn file_ids(&self) -> Vec<u64> {
+        self.inner.state.read().readers.keys().copied().collect()
+    }
+}
+
+impl BitcaskInner {
+    fn path_of_data(path: &Path, file_id: u64) -> PathBuf {
+        path.join(format!("{:05}.data", file_id))
+    }
+
+    fn path_of_hint(path: &Path, file_id: u64) -> PathBuf {
+        path.join(format!("{:05}.hint", file_id))
+    }
+
+    /// Appends an entry to the active data file, closing it if it is full.
+    fn write(self: &Arc<Self>, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
+        let mut state = self.state.write();
+        let seq = state.next_seq;
+        let entry = Entry {
+            seq,
+            tombstone: value.is_none(),
+            key,
+            value: value.as_deref().unwrap_or_default(),
+        };
+        let pos = state.active.append(&entry.encode())?;
+        if self.options.sync_writes {
+            state.active.sync()?;
+        }
+        state.next_seq += 1;
+        let old = match value {
+            Some(_) => {
+                let location = Location { file_id: state.active_id, pos, seq };
+                *state.live_bytes.entry(location.file_id).or_insert(0) += location.record_size();
+                state.keys.insert(key.to_vec());
+                state.keydir.insert(key.to_vec(), location)
+            }
+            None => {
+                state.keys.remove(key);
+                state.keydir.remove(key)
+            }
+        };
+        if let Some(old) = old {
+            if let Some(live_bytes) = state.live_bytes.get_mut(&old.file_id) {
+                *live_bytes -= old.record_size();
+            }
+        }
+        if state.active.size() >= self.options.max_file_size {
+            Self::rotate(&self.path, &mut state)?;
+            self.maybe_merge_in_background(&state);
+        }
+        Ok(())
+    }
+
+    /// Closes the active data file and starts a new one.
+    fn rotate(path: &Path, state: &mut State) -> Result<()> {
+        let active_id = state.next_file_id;
+        let active = LogWriter::create(Self::path_of_data(path, active_id))?;
+        let reader = Arc::new(LogReader::open(active.path())?);
+        let mut closed = std::mem::replace(&mut state.active, active);
+        closed.sync()?;
+        state.file_sizes.insert(state.active_id, closed.size());
+        state.readers.insert(active_id, reader);
+        state.active_id = active_id;
+        state.next_file_id += 1;
+        Ok(())
+    }
+
+    /// Ratio of stale bytes in the closed data files.
+    fn garbage_ratio(state: &State) -> f64 {
+        let total = state.file_sizes.values().sum::<u64>();
+        let live = state.file_sizes.keys()
+            .map(|file_id| state.live_bytes.get(file_id).copied().unwrap_or(0))
+            .sum::<u64>();
+        match total {
+            0 => 0.0,
+            _ => (total - live) as f64 / total as f64,
+        }
+    }
+
+    fn maybe_merge_in_background(self: &Arc<Self>, state: &State) {
+        let Some(merge_trigger) = self.options.merge_trigger else { return };
+        if Self::garbage_ratio(state) < merge_trigger {
+            return;
+        }
+        let inner = self.clone();
+        *self.background_merges.lock() += 1;
+        std::thread::spawn(move || {
+            // Skip the merge if one is already running, or if the store is closed.
+            if let Some(_merge_guard) = inner.merge_lock.try_lock() {
+                if !inner.closed.load(Ordering::SeqCst) {
+                    if let Err(err) = inner.merge() {
+                        *inner.merge_error.lock() = Some(err);
+                    }
+                }
+            }
+            let mut background_merges = inner.background_merges.lock();
+            *background_merges -= 1;
+            if *background_merges == 0 {
+                inner.background_merges_done.notify_all();
+            }
+        });
+    }
+
+    /// Waits for the background merge threads to finish, including those that skip their merge.
+    fn wait_for_background_merges(&self) {
+        let mut background_merges = self.background_merges.lock();
+        while *background_merges > 0 {
+            self.background_merges_done.wait(&mut background_merges);
+        }
+    }
+
+    /// Merges all data files but a new active one. The caller holds the merge lock.
+    fn merge(&self) -> Result<()> {
+        let inputs = {
+            let mut state = self.state.write();
+            if state.active.size() > 0 {
+                Self::rotate(&self.path, &mut state)?;
+            }
+            state.readers.range(..state.active_id)
+                .map(|(file_id, reader)| (*file_id, reader.clone()))
+                .collect::<Vec<_>>()
+        };
+        if inputs.is_empty() {
+            return Ok(());
+        }
+
+        // Copy the live entries, which keep their sequence numbers, to new data files.
+        let mut outputs = vec![];
+        let mut output: Option<(u64, LogWriter, LogWriter)> = None;
+        let mut moved = vec![];
+        for (file_id, reader) in inputs.iter() {
+            for record in reader.iter()? {
+                let (pos, payload) = record?;
+                let entry = Entry::decode(&payload)?;
+                let old = Location { file_id: *file_id, pos, seq: entry.seq };
+                if entry.tombstone || self.state.read().keydir.get(entry.key) != Some(&old) {
+                    continue;
+                }
+                if output.is_none() {
+                    let output_id = {
+                        let mut state = self.state.write();
+                        state.next_file_id += 1;
+                        state.next_file_id - 1
+                    };
+                    let writer = LogWriter::create(Self::path_of_data(&self.path, output_id))?;
+                    let hint_writer = LogWriter::create(
+                        Self::path_of_hint(&self.path, output_id).with_extension("hint.tmp")
+                    )?;
+                    output = Some((output_id, writer, hint_writer));
+                }
+                let (output_id, writer, hint_writer) = output.as_mut().expect("output is open");
+                let pos = writer.append(&payload)?;
+                let new = Location { file_id: *output_id, pos, seq: old.seq };
+                hint_writer.append(&encode_hint(entry.key, &new))?;
+                moved.push((entry.key.to_vec(), old, new));
+                if writer.size() >= self.options.max_file_size {
+                    outputs.push(self.finish_output(output.take().expect("output is open"))?);
+                }
+            }
+        }
+        if let Some(output) = output {
+            outputs.push(self.finish_output(output)?);
+        }
+
+        // Point the keys that were not written in the meantime at their new location.
+        {
+            let mut state = self.state.write();
+            for (output_id, size) in outputs {
+                let reader = LogReader::open(Self::path_of_data(&self.path, output_id))?;
+                state.readers.insert(output_id, Arc::new(reader));
+                state.file_sizes.insert(output_id, size);
+            }
+            for (key, old, new) in moved {
+                if state.keydir.get(&key) == Some(&old) {
+                    *state.live_bytes.entry(new.file_id).or_insert(0) += new.record_size();
+                    state.keydir.insert(key, new);
+                }
+            }
+            for (file_id, _) in inputs.iter() {
+                state.readers.remove(file_id);
+                state.live_bytes.remove(file_id);
+                state.file_sizes.remove(file_id);
+            }
+        }
+
+        // Record the replaced files before deleting them, so that a crash in between does not
+        // leave only some of them, where tombstones may be gone but not the values they delete.
+        let mut marker = vec![];
+        for (file_id, _) in inputs.iter() {
+            marker.put_u64(*file_id);
+        }
+        let marker_tmp = self.path.join(format!("{}.tmp", MERGE_MARKER));
+        std::fs::write(&marker_tmp, marker)?;
+        std::fs::File::open(&marker_tmp)?.sync_all()?;
+        std::fs::rename(&marker_tmp, self.path.join(MERGE_MARKER))?;
+        Self::finish_merge(&self.path)
+    }
+
+    /// Syncs a merge output and moves its hint file in place, returning its ID and size.
+    fn finish_output(
+        &self,
+        (output_id, mut writer, mut hint_writer): (u64, LogWriter, LogWriter),
+    ) -> Result<(u64, u64)> {
+        writer.sync()?;
+        hint_writer.sync()?;
+        std::fs::rename(hint_writer.path(), Self::path_of_hint(&self.path, output_id))?;
+        Ok((output_id, writer.size()))
+    }
+
+    /// Deletes the data files replaced by a merge, if the merge marker exists.
+    fn finish_merge(path: &Path) -> Result<()> {
+        let marker_path = path.join(MERGE_MARKER);
+        if !marker_path.exists() {
+            return Ok(());
+        }
+        let mut marker = &std::fs::read(&marker_path)?[..];
+        while marker.remaining() >= 8 {
+            let file_id = marker.get_u64();
+            let file_paths = [Self::path_of_data(path, file_id), Self::path_of_hint(path, file_id)];
+            for file_path in file_paths {
+                if file_path.exists() {
+                    std::fs::remove_file(file_path)?;
+                }
+            }
+        }
+        std::fs::remove_file(marker_path)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (location, reader) = {
+            let state = self.state.read();
+            let Some(location) = state.keydir.get(key).copied() else { return Ok(None) };
+            (location, state.readers[&location.file_id].clone())
+        };
+        let payload = reader.read(location.pos)?;
+        Ok(Some(Entry::decode(&payload)?.value.to_vec()))
+    }
+}
+
+impl Drop for Bitcask {
+    /// Waits for the background merges, so that the directory can be reopened.
+    fn drop(&mut self) {
+        self.inner.closed.store(true, Ordering::SeqCst);
+        self.inner.wait_for_background_merges();
+    }
+}
+
+impl Display for Bitcask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bitcask")
+    }
+}
+
+impl KvStore for Bitcask {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.inner.write(key, Some(value))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        if !self.inner.state.read().keydir.contains_key(key) {
+            return Ok(());
+        }
+        self.inner.write(key, None)
+    }
+
+    /// Takes the keys of the range from the ordered index, and reads their values lazily. Keys
+    /// deleted in the meantime are skipped.
+    fn scan(&self, range: Range) -> Result<KvScan> {
+        let keys = self.inner.state.read().keys.range(range).cloned().collect::<Vec<_>>();
+        let inner = self.inner.clone();
+        Ok(Box::new(keys.into_iter().filter_map(move |key| {
+            inner.get(&key).transpose().map(|value| value.map(|value| (key, value)))
+        })))
+    }
+
+    /// Syncs the active data file, returning the error of the last background merge if any.
+    fn flush(&self) -> Result<()> {
+        if let Some(err) = self.inner.merge_error.lock().take() {
+            return Err(err);
+        }
+        self.inner.state.write().active.sync()
+    }
+}
+
+
+
+/// A Bitcask owning its temporary directory, which is deleted after the Bitcask is dropped.
+#[cfg(test)]
+struct TempBitcask {
+    bitcask: Bitcask,
+    _dir: tempfile::TempDir,
+}
+
+#[cfg(test)]
+impl Display for TempBitcask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.bitcask.fmt(f)
+    }
+}
+
+#[cfg(test)]
+impl KvStore for TempBitcask {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.bitcask.set(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.bitcask.get(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        self.bitcask.delete(key)
+    }
+
+    fn scan(&self, range: Range) -> Result<KvScan> {
+        self.bitcask.scan(range)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.bitcask.flush()
+    }
+}
+
+#[cfg(test)]
+impl super::TestSuite<TempBitcask> for TempBitcask {
+    fn setup() -> Result<Self> {
+        // Small files, so that the suite closes files and merges them in the background.
+        let dir = tempfile::tempdir()?;
+        let options = BitcaskOptions { max_file_size: 4 << 10, ..BitcaskOptions::default() };
+        let bitcask = Bitcask::open_with_options(dir.path(), options)?;
+        Ok(Self { bitcask, _dir: dir })
+    }
+}
+
+#[test]
+fn tests() -> Result<()> {
+    use super::TestSuite;
+    TempBitcask::test()
+}
+
+#[test]
+fn test_bitcask_recovery_and_merge() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let options = BitcaskOptions {
+        max_file_size: 1 << 10, merge_trigger: None, sync_writes: false
+    };
+    let bitcask = Bitcask::open_with_options(&dir, options.clone())?;
+    for round in 0..5_u8 {
+        for i in 0..100_u32 {
+            bitcask.set(&i.to_be_bytes(), vec![round; 20])?;
+        }
+    }
+    for i in 0..50_u32 {
+        bitcask.delete(&i.to_be_bytes())?;
+    }
+    bitcask.flush()?;
+    assert!(bitcask.file_ids().len() > 10);
+    drop(bitcask);
+
+    // The data files are replayed in order, tombstones included.
+    let bitcask = Bitcask::open_with_options(&dir, options.clone())?;
+    assert_eq!(bitcask.get(&10_u32.to_be_bytes())?, None);
+    assert_eq!(bitcask.get(&60_u32.to_be_bytes())?, Some(vec![4; 20]));
+
+    // Merging keeps the live entries only, in files with hints.
+    bitcask.merge()?;
+    let file_ids = bitcask.file_ids();
+    assert!(file_ids.len() <= 4, "{:?}", file_ids);
+    assert_eq!(bitcask.scan(Range::from(..))?.count(), 50);
+    bitcask.set(&60_u32.to_be_bytes(), vec![9])?;
+    drop(bitcask);
+    let hints = std::fs::read_dir(&dir)?
+        .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == "hint")
+        .count();
+    assert!(hints >= 1);
+
+    let bitcask = Bitcask::open_with_options(&dir, options)?;
+    let entries = bitcask.scan(Range::from(..))?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 50);
+    assert_eq!(entries[0], (50_u32.to_be_bytes().to_vec(), vec![4; 20]));
+    assert_eq!(entries[10], (60_u32.to_be_bytes().to_vec(), vec![9]));
+    Ok(())
+}
+
+#[test]
+fn test_bitcask_background_merge() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let options = BitcaskOptions {
+        max_file_size: 1 << 10, merge_trigger: Some(0.5), sync_writes: false
+    };
+    let bitcask = Bitcask::open_with_options(&dir, options)?;
+    for round in 0..50_u8 {
+        for i in 0..20_u32 {
+            bitcask.set(&i.to_be_bytes(), vec![round; 20])?;
+        }
+    }
+
+    // The first trigger always merges, as no other merge runs yet. Later ones may be skipped
+    // while a merge runs, so files closed meanwhile can remain.
+    bitcask.inner.wait_for_background_merges();
+    bitcask.flush()?;
+    let file_ids = bitcask.file_ids();
+    assert!(file_ids[0] > 1, "{:?}", file_ids);
+    for i in 0..20_u32 {
+        assert_eq!(bitcask.get(&i.to_be_bytes())?, Some(vec![49; 20]));
+    }
+    Ok(())
+}
+
+/// Runs Bitcasks in temporary directories, with small files so that reopening reads hint files.
+#[cfg(test)]
+#[derive(Default)]
+struct BitcaskEngine {
+    dir: Option<tempfile::TempDir>,
+}
+
+#[cfg(test)]
+impl super::TestEngine for BitcaskEngine {
+    type Store = Bitcask;
+
+    fn create(&mut self) -> Result<Bitcask> {
+        let dir = self.dir.insert(tempfile::tempdir()?);
+        let options = BitcaskOptions { max_file_size: 1 << 10, ..BitcaskOptions::default() };
+        Bitcask::open_with_options(dir.path(), options)
+    }
+
+    fn reopen(&mut self, store: Bitcask) -> Result<Bitcask> {
+        drop(store);
+        let dir = self.dir.as_ref().expect("a store was created");
+        let options = BitcaskOptions { max_file_size: 1 << 10, ..BitcaskOptions::default() };
+        Bitcask::open_with_options(dir.path(), options)
+    }
+
+    fn compact(&mut self, store: &Bitcask) -> Result<()> {
+        store.merge()
+    }
+}
+
+#[test]
+fn test_bitcask_model() -> Result<()> {
+    for seed in 0..10 {
+        super::ModelTest { seed, ..Default::default() }.run(&mut BitcaskEngine::default())?;
+    }
+    Ok(())
+}
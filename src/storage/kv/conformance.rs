@@ -0,0 +1,431 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::Bound;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::{KvStore, Range};
+use crate::error::{Error, Result};
+
+/// The bytes keys are made of, so that random keys share prefixes, are overwritten, and hit the
+/// edge cases of prefix ranges.
+const KEY_BYTES: [u8; 4] = [0x00, 0x01, b'a', 0xff];
+
+/// A storage engine under a model test, which creates and reopens its stores.
+pub trait TestEngine {
+    type Store: KvStore;
+
+    /// Creates an empty store, independent of the ones created before (e.g. in a new
+    /// directory).
+    fn create(&mut self) -> Result<Self::Store>;
+
+    /// Reopens the store last created, which has been flushed. Engines not persisting their
+    /// data return it as is.
+    fn reopen(&mut self, store: Self::Store) -> Result<Self::Store> {
+        Ok(store)
+    }
+
+    /// Compacts the store, if the engine has any compaction.
+    fn compact(&mut self, _store: &Self::Store) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The order in which a scan takes its entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanOrder {
+    Forward,
+    Reverse,
+    /// Alternates between the front and the back, starting with the front.
+    Alternating,
+}
+
+/// An operation of a model test.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ModelOp {
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Get(Vec<u8>),
+    Scan(Bound<Vec<u8>>, Bound<Vec<u8>>, ScanOrder),
+    ScanPrefix(Vec<u8>),
+    Flush,
+    /// Flushes and reopens the store.
+    Reopen,
+    Compact,
+}
+
+impl Display for ModelOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let key = |key: &[u8]| format!("\"{}\"", key.escape_ascii());
+        let bound = |bound: &Bound<Vec<u8>>| match bound {
+            Bound::Included(k) => format!("={}", key(k)),
+            Bound::Excluded(k) => key(k),
+            Bound::Unbounded => String::new(),
+        };
+        match self {
+            Self::Set(k, value) => write!(f, "set {} = {}", key(k), key(value)),
+            Self::Delete(k) => write!(f, "delete {}", key(k)),
+            Self::Get(k) => write!(f, "get {}", key(k)),
+            Self::Scan(start, end, order) => {
+                write!(f, "scan {}..{} {:?}", bound(start), bound(end), order)
+            }
+            Self::ScanPrefix(prefix) => write!(f, "scan_prefix {}", key(prefix)),
+            Self::Flush => write!(f, "flush"),
+            Self::Reopen => write!(f, "reopen"),
+            Self::Compact => write!(f, "compact"),
+        }
+    }
+}
+
+/// A failed model test: the operations leading to the failure, and how the last one failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModelFailure {
+    pub ops: Vec<ModelOp>,
+    pub message: String,
+}
+
+impl Display for ModelFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Model test failed after {} operations: {}", self.ops.len(), self.message)?;
+        for (idx, op) in self.ops.iter().enumerate() {
+            writeln!(f, "    {:>4}: {}", idx, op)?;
+        }
+        Ok(())
+    }
+}
+
+/// A randomized test running a sequence of operations against a store and a `BTreeMap` model,
+/// failing on the first result that differs or errors. Failing sequences are shrunk to a
+/// minimal one that still fails, by dropping operations.
+#[derive(Clone, Debug)]
+pub struct ModelTest {
+    /// Seed of the operation sequence.
+    pub seed: u64,
+    pub num_ops: usize,
+    /// Keys are 1 to `max_key_len` bytes.
+    pub max_key_len: usize,
+    /// Values are 1 to `max_value_len` bytes.
+    pub max_value_len: usize,
+    /// Whether to reopen the store.
+    pub reopen: bool,
+    /// Whether to compact the store.
+    pub compact: bool,
+    /// Maximum number of sequences run while shrinking a failing one.
+    pub max_shrink_runs: usize,
+}
+
+impl Default for ModelTest {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            num_ops: 1000,
+            max_key_len: 3,
+            max_value_len: 32,
+            reopen: true,
+            compact: true,
+            max_shrink_runs: 1000,
+        }
+    }
+}
+
+impl ModelTest {
+    /// Generates the operation sequence of the seed.
+    pub fn generate(&self) -> Vec<ModelOp> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let key = |rng: &mut StdRng| {
+            let len = rng.gen_range(1..=self.max_key_len.max(1));
+            (0..len).map(|_| KEY_BYTES[rng.gen_range(0..KEY_BYTES.len())]).collect::<Vec<_>>()
+        };
+        let mut ops = Vec::with_capacity(self.num_ops);
+        while ops.len() < self.num_ops {
+            let op = match rng.gen_range(0..100) {
+                0..=34 => {
+                    let len = rng.gen_range(1..=self.max_value_len.max(1));
+                    let value = (0..len).map(|_| rng.gen()).collect();
+                    ModelOp::Set(key(&mut rng), value)
+                }
+                35..=49 => ModelOp::Delete(key(&mut rng)),
+                50..=69 => ModelOp::Get(key(&mut rng)),
+                70..=84 => {
+                    let (mut low, mut high) = (key(&mut rng), key(&mut rng));
+                    if low > high {
+                        std::mem::swap(&mut low, &mut high);
+                    }
+                    let mut bound = |key: Vec<u8>| match rng.gen_range(0..3) {
+                        0 => Bound::Included(key),
+                        1 => Bound::Excluded(key),
+                        _ => Bound::Unbounded,
+                    };
+                    let (mut start, mut end) = (bound(low), bound(high));
+                    // A range excluding both ends of a single key is invalid.
+                    if let (Bound::Excluded(start_key), Bound::Excluded(end_key)) = (&start, &end) {
+                        if start_key == end_key {
+                            start = Bound::Included(start_key.clone());
+                            end = Bound::Included(end_key.clone());
+                        }
+                    }
+                    let order = match rng.gen_range(0..3) {
+                        0 => ScanOrder::Forward,
+                        1 => ScanOrder::Reverse,
+                        _ => ScanOrder::Alternating,
+                    };
+                    ModelOp::Scan(start, end, order)
+                }
+                85..=89 => ModelOp::ScanPrefix(key(&mut rng)),
+                90..=93 => ModelOp::Flush,
+                94..=96 if self.reopen => ModelOp::Reopen,
+                97..=99 if self.compact => ModelOp::Compact,
+                _ => continue,
+            };
+            ops.push(op);
+        }
+        ops
+    }
+
+    /// Runs the test, returning an error describing the shrunk failing sequence if it fails.
+    pub fn run<E: TestEngine>(&self, engine: &mut E) -> Result<()> {
+        match self.check(engine)? {
+            Some(failure) => {
+                Err(Error::Internal(format!("Seed {}: {}", self.seed, failure)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the test, returning the shrunk failing sequence if it fails. Errors are the ones
+    /// creating stores.
+    pub fn check<E: TestEngine>(&self, engine: &mut E) -> Result<Option<ModelFailure>> {
+        match Self::execute(engine, &self.generate())? {
+            Some(failure) => Ok(Some(self.shrink(engine, failure)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Shrinks a failing sequence by dropping chunks of operations, halving the chunks until
+    /// no single operation can be dropped.
+    fn shrink<E: TestEngine>(&self, engine: &mut E, mut failure: ModelFailure)
+        -> Result<ModelFailure>
+    {
+        let mut runs = 0;
+        let mut chunk = failure.ops.len() / 2;
+        while chunk > 0 && runs < self.max_shrink_runs {
+            let mut start = 0;
+            let mut dropped = false;
+            while start < failure.ops.len() && runs < self.max_shrink_runs {
+                let mut ops = failure.ops.clone();
+                ops.drain(start..(start + chunk).min(ops.len()));
+                runs += 1;
+                match Self::execute(engine, &ops)? {
+                    Some(shrunk) => {
+                        failure = shrunk;
+                        dropped = true;
+                    }
+                    None => start += chunk,
+                }
+            }
+            if !dropped {
+                chunk /= 2;
+            }
+        }
+        Ok(failure)
+    }
+
+    /// Runs operations against a new store and the model, returning the operations up to the
+    /// first one failing, if any.
+    pub fn execute<E: TestEngine>(engine: &mut E, ops: &[ModelOp])
+        -> Result<Option<ModelFailure>>
+    {
+        let mut store = Some(engine.create()?);
+        let mut model = BTreeMap::new();
+        for (idx, op) in ops.iter().enumerate() {
+            let fail = |message: String| ModelFailure { ops: ops[..=idx].to_vec(), message };
+            if let Err(err) = Self::apply(engine, &mut store, &mut model, op) {
+                return Ok(Some(fail(err.to_string())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Applies an operation to the store and the model, erroring if their results differ.
+    fn apply<E: TestEngine>(
+        engine: &mut E,
+        store: &mut Option<E::Store>,
+        model: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+        op: &ModelOp,
+    ) -> Result<()> {
+        let Some(current) = store.as_ref() else {
+            return Err(Error::Internal("The store was lost by a failed reopen".into()));
+        };
+        let expect = |what: &str, actual: String, expected: String| match actual == expected {
+            true => Ok(()),
+            false => Err(Error::Internal(format!(
+                "{} returned {}, expected {}", what, actual, expected
+            ))),
+        };
+        match op {
+            ModelOp::Set(key, value) => {
+                current.set(key, value.clone())?;
+                model.insert(key.clone(), value.clone());
+            }
+            ModelOp::Delete(key) => {
+                current.delete(key)?;
+                model.remove(key);
+            }
+            ModelOp::Get(key) => {
+                expect("get", format_value(current.get(key)?), format_value(model.get(key)))?;
+            }
+            ModelOp::Scan(start, end, order) => {
+                let range = (start.clone(), end.clone());
+                let actual = drain(current.scan(Range::from(range.clone()))?, *order)?;
+                let expected = drain(model.range(range).map(Ok), *order)?;
+                expect("scan", format_entries(&actual), format_entries(&expected))?;
+            }
+            ModelOp::ScanPrefix(prefix) => {
+                let actual = drain(current.scan_prefix(prefix)?, ScanOrder::Forward)?;
+                let expected = model.range(Range::prefix(prefix)).map(Ok);
+                let expected = drain(expected, ScanOrder::Forward)?;
+                expect("scan_prefix", format_entries(&actual), format_entries(&expected))?;
+            }
+            ModelOp::Flush => current.flush()?,
+            ModelOp::Reopen => {
+                current.flush()?;
+                let taken = store.take().expect("the store is open");
+                *store = Some(engine.reopen(taken)?);
+            }
+            ModelOp::Compact => engine.compact(current)?,
+        }
+        Ok(())
+    }
+}
+
+/// Takes all the entries of a scan in the given order.
+fn drain<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    mut scan: impl DoubleEndedIterator<Item = Result<(K, V)>>,
+    order: ScanOrder,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut entries = vec![];
+    let mut front = true;
+    loop {
+        let entry = match (order, front) {
+            (ScanOrder::Forward, _) | (ScanOrder::Alternating, true) => scan.next(),
+            (ScanOrder::Reverse, _) | (ScanOrder::Alternating, false) => scan.next_back(),
+        };
+        let Some(entry) = entry.transpose()? else { break };
+        entries.push((entry.0.as_ref().to_vec(), entry.1.as_ref().to_vec()));
+        front = !front;
+    }
+    Ok(entries)
+}
+
+fn format_value(value: Option<impl AsRef<[u8]>>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.as_ref().escape_ascii()),
+        None => "None".into(),
+    }
+}
+
+fn format_entries(entries: &[(Vec<u8>, Vec<u8>)]) -> String {
+    let entries = entries.iter()
+        .map(|(key, value)| format!("\"{}\": \"{}\"", key.escape_ascii(), value.escape_ascii()))
+        .collect::<Vec<_>>();
+    format!("[{}]", entries.join(", "))
+}
+
+#[cfg(test)]
+use super::StdBPlusTree;
+
+/// Runs `StdBPlusTree`s in memory.
+#[cfg(test)]
+struct MemoryEngine;
+
+#[cfg(test)]
+impl TestEngine for MemoryEngine {
+    type Store = StdBPlusTree;
+
+    fn create(&mut self) -> Result<StdBPlusTree> {
+        Ok(StdBPlusTree::new())
+    }
+}
+
+/// Runs a store forgetting the deletes of keys starting with 0xff.
+#[cfg(test)]
+struct BuggyEngine;
+
+#[cfg(test)]
+struct BuggyStore(StdBPlusTree);
+
+#[cfg(test)]
+impl Display for BuggyStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "buggy")
+    }
+}
+
+#[cfg(test)]
+impl KvStore for BuggyStore {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.0.get(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        match key.first() {
+            Some(0xff) => Ok(()),
+            _ => self.0.delete(key),
+        }
+    }
+
+    fn scan(&self, range: Range) -> Result<super::KvScan> {
+        self.0.scan(range)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+impl TestEngine for BuggyEngine {
+    type Store = BuggyStore;
+
+    fn create(&mut self) -> Result<BuggyStore> {
+        Ok(BuggyStore(StdBPlusTree::new()))
+    }
+}
+
+
+
+#[test]
+fn test_model_generate() {
+    let test = ModelTest { seed: 7, num_ops: 500, reopen: false, ..Default::default() };
+    let ops = test.generate();
+    assert_eq!(ops.len(), 500);
+    assert_eq!(ops, test.generate());
+    assert!(!ops.contains(&ModelOp::Reopen));
+    assert!(ops.iter().any(|op| matches!(op, ModelOp::Scan(_, _, ScanOrder::Alternating))));
+    assert_ne!(ops, ModelTest { seed: 8, ..test }.generate());
+}
+
+#[test]
+fn test_model_memory() -> Result<()> {
+    for seed in 0..10 {
+        ModelTest { seed, ..Default::default() }.run(&mut MemoryEngine)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_model_shrink() -> Result<()> {
+    let failure = ModelTest::default().check(&mut BuggyEngine)?.expect("the store is buggy");
+    // The minimal failure sets a key, deletes it, and reads it.
+    assert_eq!(failure.ops.len(), 3, "{}", failure);
+    assert!(matches!(&failure.ops[0], ModelOp::Set(key, _) if key[0] == 0xff));
+    assert!(matches!(&failure.ops[1], ModelOp::Delete(key) if key[0] == 0xff));
+    assert!(failure.to_string().contains("   2: "));
+    assert!(ModelTest::default().run(&mut BuggyEngine).is_err());
+    Ok(())
+}
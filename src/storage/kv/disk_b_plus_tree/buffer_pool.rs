@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut};
+
+use crate::error::{Error, Result};
+use super::node::{Node, PageId, PAGE_SIZE};
+
+/// Magic number starting the header page.
+const MAGIC: &[u8; 8] = b"FEATHBPT";
+
+/// Header state of a file whose pages all belong to the tree of the header.
+const STATE_FLUSHED: u8 = 0;
+/// Header state of a file with pages written in place since the last flush, which may belong to
+/// a newer tree than the one of the header.
+const STATE_UNFLUSHED: u8 = 1;
+
+/// The tree metadata kept in page 0.
+///
+/// Data alignment:
+///
+/// ```text
+///     | magic (8B) | page_size (4B) | root (8B) | free list head (8B) | num_pages (8B) |
+///     | state (1B) |
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub root: PageId,
+    /// First page of the free list, linked through `Node::Free`.
+    pub free_head: PageId,
+    /// Number of pages in the file, including the header.
+    pub num_pages: u64,
+}
+
+impl Header {
+    fn encode(&self, state: u8) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        page.put_slice(MAGIC);
+        page.put_u32(PAGE_SIZE as u32);
+        page.put_u64(self.root);
+        page.put_u64(self.free_head);
+        page.put_u64(self.num_pages);
+        page.put_u8(state);
+        page.resize(PAGE_SIZE, 0);
+        page
+    }
+
+    fn decode(mut page: &[u8]) -> Result<Self> {
+        if !page.starts_with(MAGIC) {
+            return Err(Error::Internal("Invalid B+ tree file header".into()));
+        }
+        page.advance(MAGIC.len());
+        let page_size = page.get_u32() as usize;
+        if page_size != PAGE_SIZE {
+            return Err(Error::Internal(format!("Unsupported B+ tree page size {}", page_size)));
+        }
+        let header = Self {
+            root: page.get_u64(),
+            free_head: page.get_u64(),
+            num_pages: page.get_u64(),
+        };
+        match page.get_u8() {
+            STATE_FLUSHED => Ok(header),
+            _ => Err(Error::Internal(
+                "B+ tree file was not flushed before being closed, and may be corrupted".into()
+            )),
+        }
+    }
+}
+
+struct Frame {
+    node: Arc<Node>,
+    dirty: bool,
+    /// Tick of the last access, for LRU eviction.
+    last_used: u64,
+}
+
+/// Caches the decoded pages of the tree file, up to a number of pages. Modified pages are
+/// written back when they are evicted, or by `flush`.
+///
+/// Pages are written in place, so the tree of the header on disk is only intact until the first
+/// page is written back after a flush. The header is marked as unflushed before that, so that a
+/// file left by a crash is rejected rather than read as a corrupted tree.
+pub struct BufferPool {
+    file: File,
+    header: Header,
+    /// Whether the header on disk is marked as unflushed.
+    unflushed: bool,
+    frames: HashMap<PageId, Frame>,
+    capacity: usize,
+    tick: u64,
+    /// Number of pages read from the file, for statistics.
+    page_reads: u64,
+}
+
+impl BufferPool {
+    /// Opens the pool over a tree file, initializing it with an empty root leaf if it is empty.
+    pub fn open(mut file: File, capacity: usize) -> Result<Self> {
+        let len = file.metadata()?.len();
+        let header = match len {
+            0 => Header { root: 1, free_head: 0, num_pages: 2 },
+            _ => {
+                let mut page = vec![0; PAGE_SIZE];
+                file.seek(SeekFrom::Start(0))?;
+                file.read_exact(&mut page)?;
+                Header::decode(&page)?
+            }
+        };
+        let mut pool = Self {
+            file,
+            header,
+            unflushed: false,
+            frames: HashMap::new(),
+            capacity: capacity.max(8),
+            tick: 0,
+            page_reads: 0,
+        };
+        if len == 0 {
+            pool.write(1, Node::empty_leaf())?;
+            pool.flush()?;
+        }
+        Ok(pool)
+    }
+
+    /// Gets the tree metadata.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Sets the root page, written to the header by the next flush.
+    pub fn set_root(&mut self, root: PageId) {
+        self.header.root = root;
+    }
+
+    /// Number of pages read from the file since the pool was opened.
+    pub fn page_reads(&self) -> u64 {
+        self.page_reads
+    }
+
+    /// Reads a page, from the file if it is not cached.
+    pub fn read(&mut self, page_id: PageId) -> Result<Arc<Node>> {
+        self.tick += 1;
+        if let Some(frame) = self.frames.get_mut(&page_id) {
+            frame.last_used = self.tick;
+            return Ok(frame.node.clone());
+        }
+        if page_id == 0 || page_id >= self.header.num_pages {
+            return Err(Error::Internal(format!("B+ tree page {} out of bounds", page_id)));
+        }
+        let mut page = vec![0; PAGE_SIZE];
+        self.file.seek(SeekFrom::Start(page_id * PAGE_SIZE as u64))?;
+        self.file.read_exact(&mut page)?;
+        self.page_reads += 1;
+        let node = Arc::new(Node::decode(&page)?);
+        let frame = Frame { node: node.clone(), dirty: false, last_used: self.tick };
+        self.insert_frame(page_id, frame)?;
+        Ok(node)
+    }
+
+    /// Replaces the content of a page.
+    pub fn write(&mut self, page_id: PageId, node: Node) -> Result<()> {
+        self.tick += 1;
+        let frame = Frame { node: Arc::new(node), dirty: true, last_used: self.tick };
+        self.insert_frame(page_id, frame)
+    }
+
+    /// Allocates a page for a node, reusing a free page if any.
+    pub fn allocate(&mut self, node: Node) -> Result<PageId> {
+        let page_id = match self.header.free_head {
+            0 => {
+                self.header.num_pages += 1;
+                self.header.num_pages - 1
+            }
+            page_id => {
+                self.header.free_head = match *self.read(page_id)? {
+                    Node::Free { next } => next,
+                    _ => return Err(Error::Internal(format!(
+                        "B+ tree page {} in the free list is in use", page_id
+                    ))),
+                };
+                page_id
+            }
+        };
+        self.write(page_id, node)?;
+        Ok(page_id)
+    }
+
+    /// Adds a page no longer used by the tree to the free list.
+    pub fn free(&mut self, page_id: PageId) -> Result<()> {
+        self.write(page_id, Node::Free { next: self.header.free_head })?;
+        self.header.free_head = page_id;
+        Ok(())
+    }
+
+    fn insert_frame(&mut self, page_id: PageId, frame: Frame) -> Result<()> {
+        if self.frames.len() >= self.capacity && !self.frames.contains_key(&page_id) {
+            let (&victim, _) = self.frames.iter()
+                .min_by_key(|(_, frame)| frame.last_used)
+                .expect("the pool is full");
+            let victim_frame = self.frames.remove(&victim).expect("victim is cached");
+            if victim_frame.dirty {
+                self.write_page(victim, &victim_frame.node.encode())?;
+            }
+        }
+        self.frames.insert(page_id, frame);
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_id: PageId, page: &[u8]) -> Result<()> {
+        if !self.unflushed {
+            let header = self.header.encode(STATE_UNFLUSHED);
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.write_all(&header)?;
+            self.file.sync_data()?;
+            self.unflushed = true;
+        }
+        self.file.seek(SeekFrom::Start(page_id * PAGE_SIZE as u64))?;
+        self.file.write_all(page)?;
+        Ok(())
+    }
+
+    /// Writes the modified pages and the header to the file, and syncs it.
+    pub fn flush(&mut self) -> Result<()> {
+        let mut dirty = self.frames.iter_mut()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(page_id, frame)| {
+                frame.dirty = false;
+                (*page_id, frame.node.clone())
+            })
+            .collect::<Vec<_>>();
+        dirty.sort_unstable_by_key(|(page_id, _)| *page_id);
+        for (page_id, node) in dirty {
+            self.write_page(page_id, &node.encode())?;
+        }
+        // The pages must be on disk before the header refers to them.
+        self.file.sync_data()?;
+        let header = self.header.encode(STATE_FLUSHED);
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.sync_data()?;
+        self.unflushed = false;
+        Ok(())
+    }
+}
+
+
+
+#[test]
+fn test_buffer_pool() -> Result<()> {
+    let file = tempfile::tempfile()?;
+    let mut pool = BufferPool::open(file.try_clone()?, 8)?;
+    assert_eq!(*pool.header(), Header { root: 1, free_head: 0, num_pages: 2 });
+    assert_eq!(*pool.read(1)?, Node::empty_leaf());
+
+    // Pages beyond the capacity are evicted, and written back if modified.
+    let leaf = |i: u8| Node::Leaf { entries: vec![(vec![i], vec![i])], prev: 0, next: 0 };
+    let ids = (0..20).map(|i| pool.allocate(leaf(i))).collect::<Result<Vec<_>>>()?;
+    assert_eq!(ids, (2..22).collect::<Vec<_>>());
+    assert_eq!(*pool.read(2)?, leaf(0));
+    assert_eq!(pool.page_reads(), 1);
+
+    // Freed pages are reused.
+    pool.free(5)?;
+    pool.free(7)?;
+    assert_eq!(pool.allocate(leaf(100))?, 7);
+    assert_eq!(pool.allocate(leaf(101))?, 5);
+    assert_eq!(pool.allocate(leaf(102))?, 22);
+    pool.free(9)?;
+    pool.set_root(3);
+    pool.flush()?;
+
+    let mut pool = BufferPool::open(file, 8)?;
+    assert_eq!(*pool.header(), Header { root: 3, free_head: 9, num_pages: 23 });
+    assert_eq!(*pool.read(5)?, leaf(101));
+    assert_eq!(*pool.read(21)?, leaf(19));
+    assert!(pool.read(23).is_err());
+    Ok(())
+}
@@ -0,0 +1,3 @@
+pub mod buffer_pool;
+pub mod node;
+pub mod tree;
@@ -0,0 +1,227 @@
+use bytes::{Buf, BufMut};
+
+use crate::error::{Error, Result};
+
+/// ID of a page in the tree file, which is its offset divided by `PAGE_SIZE`. Page 0 is the
+/// header, so 0 also stands for no page.
+pub type PageId = u64;
+
+/// Size of every page of the tree file.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Nodes below this encoded size are merged with a sibling, or borrow from it.
+pub const MIN_NODE_SIZE: usize = PAGE_SIZE / 4;
+
+/// Largest key plus value, so that a split always leaves both halves above `MIN_NODE_SIZE` and
+/// within a page.
+pub const MAX_ENTRY_SIZE: usize = PAGE_SIZE / 4 - 64;
+
+const NODE_FREE: u8 = 0x00;
+const NODE_LEAF: u8 = 0x01;
+const NODE_INTERNAL: u8 = 0x02;
+
+/// Encoded size of the fixed part of a leaf: kind, number of entries, prev and next leaves.
+const LEAF_HEADER_SIZE: usize = 1 + 2 + 8 + 8;
+/// Encoded size of the fixed part of an internal node: kind, number of keys, first child.
+const INTERNAL_HEADER_SIZE: usize = 1 + 2 + 8;
+
+/// A page of the tree, decoded.
+///
+/// Data alignment:
+///
+/// ```text
+///     leaf:     | kind (1B) | num_entries (2B) | prev (8B) | next (8B) | entry | ... |
+///               entry: | key_len (2B) | key | value_len (2B) | value |
+///     internal: | kind (1B) | num_keys (2B) | child (8B) | key_len (2B) | key | child (8B) | ...
+///     free:     | kind (1B) | next free page (8B) |
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    /// Sorted entries, linked to the neighbouring leaves for range scans.
+    Leaf { entries: Vec<(Vec<u8>, Vec<u8>)>, prev: PageId, next: PageId },
+    /// `children[i]` holds the keys below `keys[i]` and at or above `keys[i - 1]`.
+    Internal { keys: Vec<Vec<u8>>, children: Vec<PageId> },
+    /// A page in the free list.
+    Free { next: PageId },
+}
+
+impl Node {
+    /// An empty leaf, which is the root of an empty tree.
+    pub fn empty_leaf() -> Self {
+        Self::Leaf { entries: vec![], prev: 0, next: 0 }
+    }
+
+    /// The size of the node once encoded, which must fit in a page.
+    pub fn encoded_size(&self) -> usize {
+        match self {
+            Self::Leaf { entries, .. } => LEAF_HEADER_SIZE + entries.iter()
+                .map(|(key, value)| 4 + key.len() + value.len())
+                .sum::<usize>(),
+            Self::Internal { keys, .. } => INTERNAL_HEADER_SIZE + keys.iter()
+                .map(|key| 10 + key.len())
+                .sum::<usize>(),
+            Self::Free { .. } => 9,
+        }
+    }
+
+    /// Encodes the node into a full page.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        match self {
+            Self::Leaf { entries, prev, next } => {
+                page.put_u8(NODE_LEAF);
+                page.put_u16(entries.len() as u16);
+                page.put_u64(*prev);
+                page.put_u64(*next);
+                for (key, value) in entries {
+                    page.put_u16(key.len() as u16);
+                    page.put_slice(key);
+                    page.put_u16(value.len() as u16);
+                    page.put_slice(value);
+                }
+            }
+            Self::Internal { keys, children } => {
+                page.put_u8(NODE_INTERNAL);
+                page.put_u16(keys.len() as u16);
+                page.put_u64(children[0]);
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    page.put_u16(key.len() as u16);
+                    page.put_slice(key);
+                    page.put_u64(*child);
+                }
+            }
+            Self::Free { next } => {
+                page.put_u8(NODE_FREE);
+                page.put_u64(*next);
+            }
+        }
+        assert!(page.len() <= PAGE_SIZE, "node of {} bytes overflows its page", page.len());
+        page.resize(PAGE_SIZE, 0);
+        page
+    }
+
+    /// Decodes a page.
+    pub fn decode(mut page: &[u8]) -> Result<Self> {
+        let invalid = || Error::Internal("Invalid B+ tree page".into());
+        let take = |page: &mut &[u8], len: usize| -> Result<Vec<u8>> {
+            let bytes = page.get(..len).ok_or_else(invalid)?.to_vec();
+            page.advance(len);
+            Ok(bytes)
+        };
+        if page.len() != PAGE_SIZE {
+            return Err(invalid());
+        }
+        match page.get_u8() {
+            NODE_LEAF => {
+                let num_entries = page.get_u16() as usize;
+                let (prev, next) = (page.get_u64(), page.get_u64());
+                let mut entries = Vec::with_capacity(num_entries);
+                for _ in 0..num_entries {
+                    let key_len = take(&mut page, 2)?.as_slice().get_u16() as usize;
+                    let key = take(&mut page, key_len)?;
+                    let value_len = take(&mut page, 2)?.as_slice().get_u16() as usize;
+                    entries.push((key, take(&mut page, value_len)?));
+                }
+                Ok(Self::Leaf { entries, prev, next })
+            }
+            NODE_INTERNAL => {
+                let num_keys = page.get_u16() as usize;
+                let mut keys = Vec::with_capacity(num_keys);
+                let mut children = vec![page.get_u64()];
+                for _ in 0..num_keys {
+                    let key_len = take(&mut page, 2)?.as_slice().get_u16() as usize;
+                    keys.push(take(&mut page, key_len)?);
+                    children.push(take(&mut page, 8)?.as_slice().get_u64());
+                }
+                Ok(Self::Internal { keys, children })
+            }
+            NODE_FREE => Ok(Self::Free { next: page.get_u64() }),
+            kind => Err(Error::Internal(format!("Invalid B+ tree page kind {:x?}", kind))),
+        }
+    }
+
+    /// Splits an overflowing node in two halves of about the same encoded size, returning the
+    /// key separating them and the right half.
+    pub fn split(&mut self) -> (Vec<u8>, Node) {
+        let half = self.encoded_size() / 2;
+        match self {
+            Self::Leaf { entries, next, .. } => {
+                let mut size = LEAF_HEADER_SIZE;
+                let idx = entries.iter()
+                    .position(|(key, value)| {
+                        size += 4 + key.len() + value.len();
+                        size > half
+                    })
+                    .unwrap_or(entries.len() - 1)
+                    .clamp(1, entries.len() - 1);
+                let right = entries.split_off(idx);
+                let separator = right[0].0.clone();
+                // The caller links the leaves once the right one has a page.
+                (separator, Self::Leaf { entries: right, prev: 0, next: *next })
+            }
+            Self::Internal { keys, children } => {
+                let mut size = INTERNAL_HEADER_SIZE;
+                let idx = keys.iter()
+                    .position(|key| {
+                        size += 10 + key.len();
+                        size > half
+                    })
+                    .unwrap_or(keys.len() - 1)
+                    .clamp(1, keys.len() - 1);
+                let right_keys = keys.split_off(idx + 1);
+                let separator = keys.pop().expect("split keeps a key on the left");
+                let right_children = children.split_off(idx + 1);
+                (separator, Self::Internal { keys: right_keys, children: right_children })
+            }
+            Self::Free { .. } => unreachable!("free pages are never split"),
+        }
+    }
+}
+
+
+
+#[test]
+fn test_node_encoding() {
+    let leaf = Node::Leaf {
+        entries: vec![(b"a".to_vec(), b"1".to_vec()), (b"bb".to_vec(), vec![])],
+        prev: 3,
+        next: 7,
+    };
+    let page = leaf.encode();
+    assert_eq!(page.len(), PAGE_SIZE);
+    assert_eq!(Node::decode(&page).unwrap(), leaf);
+    assert_eq!(leaf.encoded_size(), LEAF_HEADER_SIZE + 4 + 2 + 4 + 2);
+
+    let internal = Node::Internal { keys: vec![b"m".to_vec()], children: vec![4, 5] };
+    assert_eq!(Node::decode(&internal.encode()).unwrap(), internal);
+    let free = Node::Free { next: 9 };
+    assert_eq!(Node::decode(&free.encode()).unwrap(), free);
+    assert!(Node::decode(&[0xff; PAGE_SIZE]).is_err());
+}
+
+#[test]
+fn test_node_split() {
+    let entries = (0..100_u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![0; 40]))
+        .collect::<Vec<_>>();
+    let mut leaf = Node::Leaf { entries, prev: 1, next: 2 };
+    let (separator, right) = leaf.split();
+    assert_eq!(separator, 49_u32.to_be_bytes().to_vec());
+    assert_eq!((leaf.encoded_size(), right.encoded_size()), (19 + 49 * 48, 19 + 51 * 48));
+    assert!(matches!(right, Node::Leaf { next: 2, .. }));
+
+    let mut internal = Node::Internal {
+        keys: (1..10_u8).map(|i| vec![i]).collect(),
+        children: (0..10).collect(),
+    };
+    let (separator, right) = internal.split();
+    assert_eq!(separator, vec![5]);
+    assert_eq!(internal, Node::Internal {
+        keys: (1..5_u8).map(|i| vec![i]).collect(),
+        children: (0..5).collect(),
+    });
+    assert_eq!(right, Node::Internal {
+        keys: (6..10_u8).map(|i| vec![i]).collect(),
+        children: (5..10).collect(),
+    });
+}
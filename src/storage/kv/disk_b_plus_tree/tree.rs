@@ -0,0 +1,663 @@
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::error::{Error, Result};
+use super::super::{KvScan, KvStore, Range};
+use super::buffer_pool::BufferPool;
+use super::node::{Node, PageId, MAX_ENTRY_SIZE, MIN_NODE_SIZE, PAGE_SIZE};
+
+/// Options for opening a `DiskBPlusTree`.
+#[derive(Clone, Debug)]
+pub struct DiskBPlusTreeOptions {
+    /// Number of pages cached by the buffer pool.
+    pub buffer_pool_pages: usize,
+}
+
+impl Default for DiskBPlusTreeOptions {
+    fn default() -> Self {
+        Self { buffer_pool_pages: 1024 }
+    }
+}
+
+struct Inner {
+    pool: BufferPool,
+    /// Incremented by every modification, so that iterators know when their position is stale.
+    version: u64,
+}
+
+/// An on-disk B+ tree of fixed-size pages, cached in a buffer pool. Leaves are linked both
+/// ways, so scans are lazy and read one leaf at a time. Nodes are split when they overflow their
+/// page, and merged with a sibling (or borrow from it) when they fall below a quarter of it;
+/// pages freed by merges are reused through a free list.
+///
+/// Keys and values must be at most `MAX_ENTRY_SIZE` bytes together. Modified pages reach the
+/// file when they are evicted or flushed, and dropping the tree flushes it. The tree is only
+/// consistent on disk after a `flush`, as it is not protected by a WAL: opening a file whose tree
+/// was not flushed after its last writes, e.g. after a crash, fails rather than reading a
+/// corrupted tree.
+pub struct DiskBPlusTree {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl DiskBPlusTree {
+    /// Opens or creates a tree file with the default options.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(path, DiskBPlusTreeOptions::default())
+    }
+
+    /// Opens or creates a tree file.
+    pub fn open_with_options(
+        path: impl AsRef<Path>,
+        options: DiskBPlusTreeOptions,
+    ) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(path)?;
+        Self::open_file(file, options)
+    }
+
+    /// Opens a tree in an already opened file, e.g. a temporary one.
+    pub fn open_file(file: File, options: DiskBPlusTreeOptions) -> Result<Self> {
+        let pool = BufferPool::open(file, options.buffer_pool_pages)?;
+        Ok(Self { inner: Arc::new(Mutex::new(Inner { pool, version: 0 })) })
+    }
+
+    /// Number of pages read from the file since the tree was opened.
+    pub fn page_reads(&self) -> u64 {
+        self.inner.lock().pool.page_reads()
+    }
+
+    /// Number of pages in the file, including the header and the free pages.
+    pub fn num_pages(&self) -> u64 {
+        self.inner.lock().pool.header().num_pages
+    }
+
+    /// Height of the tree, 1 when the root is a leaf.
+    pub fn height(&self) -> Result<usize> {
+        let mut inner = self.inner.lock();
+        let mut page_id = inner.pool.header().root;
+        let mut height = 1;
+        while let Node::Internal { children, .. } = &*inner.pool.read(page_id)? {
+            page_id = children[0];
+            height += 1;
+        }
+        Ok(height)
+    }
+}
+
+impl Drop for DiskBPlusTree {
+    /// Writes the modified pages back. On failure, the file stays marked as unflushed, and
+    /// opening it fails.
+    fn drop(&mut self) {
+        let _ = self.inner.lock().pool.flush();
+    }
+}
+
+impl Inner {
+    /// Finds the leaf that may hold a key.
+    fn find_leaf(&mut self, key: &[u8]) -> Result<PageId> {
+        let mut page_id = self.pool.header().root;
+        loop {
+            match &*self.pool.read(page_id)? {
+                Node::Internal { keys, children } => {
+                    page_id = children[keys.partition_point(|k| k.as_slice() <= key)];
+                }
+                Node::Leaf { .. } => return Ok(page_id),
+                Node::Free { .. } => return Err(free_page_error(page_id)),
+            }
+        }
+    }
+
+    /// Finds the leftmost or rightmost leaf.
+    fn find_edge_leaf(&mut self, rightmost: bool) -> Result<PageId> {
+        let mut page_id = self.pool.header().root;
+        loop {
+            match &*self.pool.read(page_id)? {
+                Node::Internal { children, .. } => {
+                    page_id = match rightmost {
+                        true => children[children.len() - 1],
+                        false => children[0],
+                    };
+                }
+                Node::Leaf { .. } => return Ok(page_id),
+                Node::Free { .. } => return Err(free_page_error(page_id)),
+            }
+        }
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let leaf = self.find_leaf(key)?;
+        match &*self.pool.read(leaf)? {
+            Node::Leaf { entries, .. } => Ok(entries
+                .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+                .ok()
+                .map(|idx| entries[idx].1.clone())),
+            _ => Err(Error::Internal(format!("B+ tree page {} is not a leaf", leaf))),
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        if key.len() + value.len() > MAX_ENTRY_SIZE {
+            return Err(Error::Value(format!(
+                "Key and value of {} bytes exceed the B+ tree limit of {} bytes",
+                key.len() + value.len(), MAX_ENTRY_SIZE
+            )));
+        }
+        self.version += 1;
+        let root = self.pool.header().root;
+        if let Some((separator, right)) = self.insert(root, key, value)? {
+            let new_root = self.pool.allocate(Node::Internal {
+                keys: vec![separator],
+                children: vec![root, right],
+            })?;
+            self.pool.set_root(new_root);
+        }
+        Ok(())
+    }
+
+    /// Inserts an entry into a subtree, returning the separator and page of the new right
+    /// sibling if the subtree root was split.
+    fn insert(&mut self, page_id: PageId, key: &[u8], value: Vec<u8>)
+        -> Result<Option<(Vec<u8>, PageId)>>
+    {
+        let mut node = (*self.pool.read(page_id)?).clone();
+        match &mut node {
+            Node::Leaf { entries, .. } => {
+                match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                    Ok(idx) => entries[idx].1 = value,
+                    Err(idx) => entries.insert(idx, (key.to_vec(), value)),
+                }
+            }
+            Node::Internal { keys, children } => {
+                let idx = keys.partition_point(|k| k.as_slice() <= key);
+                match self.insert(children[idx], key, value)? {
+                    Some((separator, right)) => {
+                        keys.insert(idx, separator);
+                        children.insert(idx + 1, right);
+                    }
+                    None => return Ok(None),
+                }
+            }
+            Node::Free { .. } => return Err(free_page_error(page_id)),
+        }
+        if node.encoded_size() <= PAGE_SIZE {
+            self.pool.write(page_id, node)?;
+            return Ok(None);
+        }
+        let (separator, right) = node.split();
+        let right_id = self.pool.allocate(right)?;
+        self.link_leaves(page_id, &mut node, right_id)?;
+        self.pool.write(page_id, node)?;
+        Ok(Some((separator, right_id)))
+    }
+
+    /// Links a leaf split off a left leaf between it and its next leaf. Does nothing for
+    /// internal nodes.
+    fn link_leaves(&mut self, left_id: PageId, left: &mut Node, right_id: PageId) -> Result<()> {
+        let Node::Leaf { next, .. } = left else { return Ok(()) };
+        let old_next = std::mem::replace(next, right_id);
+        if old_next != 0 {
+            self.set_prev(old_next, right_id)?;
+        }
+        let mut right = (*self.pool.read(right_id)?).clone();
+        if let Node::Leaf { prev, .. } = &mut right {
+            *prev = left_id;
+        }
+        self.pool.write(right_id, right)
+    }
+
+    fn set_prev(&mut self, page_id: PageId, new_prev: PageId) -> Result<()> {
+        let mut node = (*self.pool.read(page_id)?).clone();
+        if let Node::Leaf { prev, .. } = &mut node {
+            *prev = new_prev;
+        }
+        self.pool.write(page_id, node)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.version += 1;
+        let root = self.pool.header().root;
+        self.remove(root, key)?;
+        // Collapse a root left with a single child.
+        if let Node::Internal { keys, children } = &*self.pool.read(root)? {
+            if keys.is_empty() {
+                self.pool.set_root(children[0]);
+                self.pool.free(root)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a key from a subtree, returning whether its root fell below `MIN_NODE_SIZE`.
+    fn remove(&mut self, page_id: PageId, key: &[u8]) -> Result<bool> {
+        let mut node = (*self.pool.read(page_id)?).clone();
+        match &mut node {
+            Node::Leaf { entries, .. } => {
+                match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                    Ok(idx) => { entries.remove(idx); }
+                    Err(_) => return Ok(false),
+                }
+            }
+            Node::Internal { keys, children } => {
+                let idx = keys.partition_point(|k| k.as_slice() <= key);
+                if !self.remove(children[idx], key)? {
+                    return Ok(false);
+                }
+                self.rebalance(keys, children, idx)?;
+            }
+            Node::Free { .. } => return Err(free_page_error(page_id)),
+        }
+        let underflow = node.encoded_size() < MIN_NODE_SIZE;
+        self.pool.write(page_id, node)?;
+        Ok(underflow)
+    }
+
+    /// Fixes the underflowing child `idx` of an internal node, by merging it with a sibling if
+    /// both fit in a page, or else by evening out their sizes.
+    fn rebalance(&mut self, keys: &mut Vec<Vec<u8>>, children: &mut Vec<PageId>, idx: usize)
+        -> Result<()>
+    {
+        if children.len() < 2 {
+            return Ok(());
+        }
+        // Pair the child with its left sibling, or its right one for the first child.
+        let left_idx = idx.saturating_sub(1).min(children.len() - 2);
+        let (left_id, right_id) = (children[left_idx], children[left_idx + 1]);
+        let mut left = (*self.pool.read(left_id)?).clone();
+        let right = (*self.pool.read(right_id)?).clone();
+        let separator = keys[left_idx].clone();
+        let right_next = match (&mut left, right) {
+            (
+                Node::Leaf { entries, next, .. },
+                Node::Leaf { entries: right_entries, next: right_next, .. },
+            ) => {
+                entries.extend(right_entries);
+                *next = right_next;
+                right_next
+            }
+            (Node::Internal { keys: left_keys, children: left_children },
+             Node::Internal { keys: right_keys, children: right_children }) => {
+                left_keys.push(separator);
+                left_keys.extend(right_keys);
+                left_children.extend(right_children);
+                0
+            }
+            _ => return Err(Error::Internal("B+ tree siblings of different kinds".into())),
+        };
+
+        if left.encoded_size() <= PAGE_SIZE {
+            // Merge the right sibling into the left one, freeing its page.
+            if right_next != 0 {
+                self.set_prev(right_next, left_id)?;
+            }
+            self.pool.write(left_id, left)?;
+            self.pool.free(right_id)?;
+            keys.remove(left_idx);
+            children.remove(left_idx + 1);
+        } else {
+            // Split the combined node again, halfway by size.
+            let (separator, right) = left.split();
+            self.pool.write(right_id, right)?;
+            self.link_leaves(left_id, &mut left, right_id)?;
+            self.pool.write(left_id, left)?;
+            keys[left_idx] = separator;
+        }
+        Ok(())
+    }
+
+    /// Finds the first entry at or after the start of a range, in key order, or the last entry at
+    /// or before its end in reverse order. Returns its leaf and index.
+    fn seek(&mut self, bound: Bound<&[u8]>, reverse: bool) -> Result<Option<(PageId, usize)>> {
+        let mut page_id = match bound {
+            Bound::Included(key) | Bound::Excluded(key) => self.find_leaf(key)?,
+            Bound::Unbounded => self.find_edge_leaf(reverse)?,
+        };
+        let node = self.pool.read(page_id)?;
+        let Node::Leaf { entries, prev, next } = &*node else {
+            return Err(Error::Internal(format!("B+ tree page {} is not a leaf", page_id)));
+        };
+        let found = match (bound, reverse) {
+            (Bound::Unbounded, false) => Some(0),
+            (Bound::Unbounded, true) => entries.len().checked_sub(1),
+            (Bound::Included(key), false) => {
+                Some(entries.partition_point(|(k, _)| k.as_slice() < key))
+            }
+            (Bound::Excluded(key), false) => {
+                Some(entries.partition_point(|(k, _)| k.as_slice() <= key))
+            }
+            (Bound::Included(key), true) => entries.partition_point(|(k, _)| k.as_slice() <= key)
+                .checked_sub(1),
+            (Bound::Excluded(key), true) => entries.partition_point(|(k, _)| k.as_slice() < key)
+                .checked_sub(1),
+        };
+        let (prev, next) = (*prev, *next);
+        match found {
+            Some(idx) if idx < entries.len() => Ok(Some((page_id, idx))),
+            // The entry is in a neighbouring leaf, if any.
+            _ => {
+                page_id = if reverse { prev } else { next };
+                self.first_in_leaf(page_id, reverse)
+            }
+        }
+    }
+
+    /// Finds the first entry in key order (or the last in reverse) starting from a leaf, skipping
+    /// empty leaves.
+    fn first_in_leaf(&mut self, mut page_id: PageId, reverse: bool)
+        -> Result<Option<(PageId, usize)>>
+    {
+        while page_id != 0 {
+            let node = self.pool.read(page_id)?;
+            let Node::Leaf { entries, prev, next } = &*node else {
+                return Err(Error::Internal(format!("B+ tree page {} is not a leaf", page_id)));
+            };
+            if !entries.is_empty() {
+                return Ok(Some((page_id, if reverse { entries.len() - 1 } else { 0 })));
+            }
+            page_id = if reverse { *prev } else { *next };
+        }
+        Ok(None)
+    }
+}
+
+fn free_page_error(page_id: PageId) -> Error {
+    Error::Internal(format!("B+ tree page {} is in the free list", page_id))
+}
+
+impl Display for DiskBPlusTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bplustree")
+    }
+}
+
+impl KvStore for DiskBPlusTree {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.inner.lock().set(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.lock().get(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        self.inner.lock().delete(key)
+    }
+
+    fn scan(&self, range: Range) -> Result<KvScan> {
+        Ok(Box::new(DiskBPlusTreeIter::new(self.inner.clone(), range)))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.lock().pool.flush()
+    }
+}
+
+/// The position of an iterator end: the leaf and index of the next entry to return, valid as
+/// long as the tree is at the same version.
+#[derive(Clone, Copy)]
+struct Cursor {
+    page_id: PageId,
+    idx: usize,
+    version: u64,
+}
+
+/// A lazy iterator over a range of a `DiskBPlusTree`, locking the tree for each entry only.
+/// When the tree was modified in the meantime, the iterator seeks again from the root past the
+/// last returned key, so it sees a consistent order though not a snapshot.
+pub struct DiskBPlusTreeIter {
+    inner: Arc<Mutex<Inner>>,
+    /// The remaining range, narrowed past each returned key.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    front: Option<Cursor>,
+    back: Option<Cursor>,
+    done: bool,
+}
+
+impl DiskBPlusTreeIter {
+    fn new(inner: Arc<Mutex<Inner>>, range: Range) -> Self {
+        Self {
+            inner,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            front: None,
+            back: None,
+            done: false,
+        }
+    }
+
+    fn try_next_inner(&mut self, reverse: bool) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut inner = self.inner.lock();
+        let version = inner.version;
+        let cursor = if reverse { self.back } else { self.front };
+        let position = match cursor {
+            Some(cursor) if cursor.version == version => Some((cursor.page_id, cursor.idx)),
+            _ => {
+                let bound = if reverse { &self.end } else { &self.start };
+                inner.seek(bound.as_ref().map(|key| key.as_slice()), reverse)?
+            }
+        };
+        let Some((page_id, idx)) = position else {
+            self.done = true;
+            return Ok(None);
+        };
+        let node = inner.pool.read(page_id)?;
+        let Node::Leaf { entries, prev, next } = &*node else {
+            return Err(Error::Internal(format!("B+ tree page {} is not a leaf", page_id)));
+        };
+        let (key, value) = entries[idx].clone();
+        let bounds: (Bound<&Vec<u8>>, Bound<&Vec<u8>>) = (self.start.as_ref(), self.end.as_ref());
+        if !RangeBounds::<Vec<u8>>::contains(&bounds, &key) {
+            self.done = true;
+            return Ok(None);
+        }
+
+        // Move past the entry, to the neighbouring leaf if it was the last of its leaf.
+        let next_position = match reverse {
+            false if idx + 1 < entries.len() => Some((page_id, idx + 1)),
+            true if idx > 0 => Some((page_id, idx - 1)),
+            false => inner.first_in_leaf(*next, false)?,
+            true => inner.first_in_leaf(*prev, true)?,
+        };
+        let next_cursor = next_position.map(|(page_id, idx)| Cursor { page_id, idx, version });
+        match reverse {
+            false => {
+                self.front = next_cursor;
+                self.start = Bound::Excluded(key.clone());
+            }
+            true => {
+                self.back = next_cursor;
+                self.end = Bound::Excluded(key.clone());
+            }
+        }
+        if next_cursor.is_none() {
+            // Nothing is left on this side, hence nothing is left on the other one either.
+            self.done = true;
+        }
+        Ok(Some((key, value)))
+    }
+}
+
+impl Iterator for DiskBPlusTreeIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next_inner(false).transpose()
+    }
+}
+
+impl DoubleEndedIterator for DiskBPlusTreeIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_inner(true).transpose()
+    }
+}
+
+
+
+#[cfg(test)]
+impl super::super::TestSuite<DiskBPlusTree> for DiskBPlusTree {
+    fn setup() -> Result<Self> {
+        // A small buffer pool, so that pages are evicted and read back.
+        let options = DiskBPlusTreeOptions { buffer_pool_pages: 16 };
+        DiskBPlusTree::open_file(tempfile::tempfile()?, options)
+    }
+}
+
+#[test]
+fn tests() -> Result<()> {
+    use super::super::TestSuite;
+    DiskBPlusTree::test()
+}
+
+#[test]
+fn test_disk_b_plus_tree_split_merge() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("tree");
+    let options = DiskBPlusTreeOptions { buffer_pool_pages: 16 };
+    let tree = DiskBPlusTree::open_with_options(&path, options.clone())?;
+    // Long keys make for a small fan-out, hence a tall tree.
+    let key = |i: u32| format!("{:0100}", i).into_bytes();
+    for i in 0..5000 {
+        tree.set(&key(i), vec![i as u8; 100])?;
+    }
+    assert!(tree.height()? >= 3);
+    let num_pages = tree.num_pages();
+
+    // Deleting most keys merges nodes, and their pages are reused by new writes.
+    for i in 0..4900 {
+        tree.delete(&key(i))?;
+    }
+    assert!(tree.height()? <= 2);
+    for i in 10_000..14_000 {
+        tree.set(&key(i), vec![0; 100])?;
+    }
+    assert_eq!(tree.num_pages(), num_pages);
+    assert!(tree.set(&key(0), vec![0; MAX_ENTRY_SIZE]).is_err());
+    tree.flush()?;
+    drop(tree);
+
+    let tree = DiskBPlusTree::open_with_options(&path, options)?;
+    assert_eq!(tree.get(&key(4950))?, Some(vec![4950_u32 as u8; 100]));
+    assert_eq!(tree.get(&key(10))?, None);
+    let scan = tree.scan(Range::from(key(4990)..key(10_005)))?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(scan.len(), 15);
+    assert_eq!(tree.scan(Range::from(..))?.count(), 4100);
+    assert_eq!(tree.scan(Range::from(..))?.rev().count(), 4100);
+    Ok(())
+}
+
+#[test]
+fn test_disk_b_plus_tree_reopen_without_flush() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("tree");
+    let options = DiskBPlusTreeOptions { buffer_pool_pages: 16 };
+    let key = |i: u32| format!("{:0100}", i).into_bytes();
+    let tree = DiskBPlusTree::open_with_options(&path, options.clone())?;
+    for i in 0..2000 {
+        tree.set(&key(i), vec![i as u8; 100])?;
+    }
+    assert!(tree.height()? >= 3);
+    drop(tree);
+
+    // Dropping the tree flushed the pages that were not evicted, along with the header.
+    let tree = DiskBPlusTree::open_with_options(&path, options.clone())?;
+    assert_eq!(tree.scan(Range::from(..))?.count(), 2000);
+    assert_eq!(tree.get(&key(1999))?, Some(vec![1999_u32 as u8; 100]));
+
+    // A crash after pages were evicted leaves a file that cannot be opened.
+    for i in 2000..4000 {
+        tree.set(&key(i), vec![i as u8; 100])?;
+    }
+    std::mem::forget(tree);
+    assert!(DiskBPlusTree::open_with_options(&path, options).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_disk_b_plus_tree_lazy_scan() -> Result<()> {
+    let tree = DiskBPlusTree::open_file(tempfile::tempfile()?, DiskBPlusTreeOptions::default())?;
+    let key = |i: u32| i.to_be_bytes().to_vec();
+    for i in 0..1000 {
+        tree.set(&key(i), vec![1; 50])?;
+    }
+
+    // Scans see writes made past their position while they iterate.
+    let mut scan = tree.scan(Range::from(..))?;
+    assert_eq!(scan.next().transpose()?.map(|(k, _)| k), Some(key(0)));
+    tree.delete(&key(1))?;
+    tree.set(&key(5000), vec![])?;
+    assert_eq!(scan.next().transpose()?.map(|(k, _)| k), Some(key(2)));
+    assert_eq!(scan.next_back().transpose()?.map(|(k, _)| k), Some(key(5000)));
+    assert_eq!(scan.next_back().transpose()?.map(|(k, _)| k), Some(key(999)));
+    assert_eq!(scan.count(), 996);
+    Ok(())
+}
+
+#[test]
+fn test_disk_b_plus_tree_random() -> Result<()> {
+    use std::collections::BTreeMap;
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+    let options = DiskBPlusTreeOptions { buffer_pool_pages: 32 };
+    let tree = DiskBPlusTree::open_file(tempfile::tempfile()?, options)?;
+    let mut model = BTreeMap::new();
+    for _ in 0..20_000 {
+        let key = format!("{:0width$}", rng.gen_range(0..2000), width = rng.gen_range(4..200));
+        match rng.gen_bool(0.6) {
+            true => {
+                let value = vec![rng.gen::<u8>(); rng.gen_range(0..500)];
+                tree.set(key.as_bytes(), value.clone())?;
+                model.insert(key.into_bytes(), value);
+            }
+            false => {
+                tree.delete(key.as_bytes())?;
+                model.remove(key.as_bytes());
+            }
+        }
+    }
+    let expected = model.into_iter().collect::<Vec<_>>();
+    assert_eq!(tree.scan(Range::from(..))?.collect::<Result<Vec<_>>>()?, expected);
+    let mut reversed = tree.scan(Range::from(..))?.rev().collect::<Result<Vec<_>>>()?;
+    reversed.reverse();
+    assert_eq!(reversed, expected);
+    Ok(())
+}
+
+/// Runs trees in temporary files, with a small buffer pool.
+#[cfg(test)]
+#[derive(Default)]
+struct DiskBPlusTreeEngine {
+    file: Option<File>,
+}
+
+#[cfg(test)]
+impl super::super::TestEngine for DiskBPlusTreeEngine {
+    type Store = DiskBPlusTree;
+
+    fn create(&mut self) -> Result<DiskBPlusTree> {
+        let file = self.file.insert(tempfile::tempfile()?);
+        DiskBPlusTree::open_file(file.try_clone()?, DiskBPlusTreeOptions { buffer_pool_pages: 16 })
+    }
+
+    fn reopen(&mut self, store: DiskBPlusTree) -> Result<DiskBPlusTree> {
+        drop(store);
+        let file = self.file.as_ref().expect("a tree was created").try_clone()?;
+        DiskBPlusTree::open_file(file, DiskBPlusTreeOptions { buffer_pool_pages: 16 })
+    }
+}
+
+#[test]
+fn test_disk_b_plus_tree_model() -> Result<()> {
+    for seed in 0..10 {
+        let test = super::super::ModelTest { seed, ..Default::default() };
+        test.run(&mut DiskBPlusTreeEngine::default())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,462 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::{KvScan, KvStore, Range};
+use crate::error::Result;
+
+/// Formats the keys of traces, e.g. `MVCC::format_key` to decode MVCC keys.
+pub type KeyFormatter = fn(&[u8]) -> String;
+
+/// Formats a raw key, escaping its non-printable bytes.
+pub fn format_raw_key(key: &[u8]) -> String {
+    format!("\"{}\"", key.escape_ascii())
+}
+
+/// A `KvStore` operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KvOp {
+    Set,
+    Get,
+    Delete,
+    Scan,
+    ScanPrefix,
+    Flush,
+}
+
+impl KvOp {
+    const ALL: [KvOp; 6] =
+        [KvOp::Set, KvOp::Get, KvOp::Delete, KvOp::Scan, KvOp::ScanPrefix, KvOp::Flush];
+}
+
+impl Display for KvOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Set => "set",
+            Self::Get => "get",
+            Self::Delete => "delete",
+            Self::Scan => "scan",
+            Self::ScanPrefix => "scan_prefix",
+            Self::Flush => "flush",
+        })
+    }
+}
+
+/// A call recorded by an `InstrumentedKvStore`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KvTrace {
+    pub op: KvOp,
+    /// The formatted key, or range or prefix of a scan.
+    pub key: String,
+    /// Latency of the call. For a scan, from its creation until it is dropped.
+    pub latency: Duration,
+    /// Number of entries read or written.
+    pub entries: u64,
+    /// Size of the values read or written, plus the keys returned by a scan.
+    pub bytes: u64,
+    /// The error returned by the call, if any.
+    pub error: Option<String>,
+}
+
+impl Display for KvTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} in {:?}: {} entries, {} bytes", self.op, self.key, self.latency,
+            self.entries, self.bytes)?;
+        if let Some(error) = &self.error {
+            write!(f, ", error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters of the calls of an operation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KvOpStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub entries: u64,
+    pub bytes: u64,
+    pub latency: Duration,
+}
+
+#[derive(Default)]
+struct OpCounters {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    entries: AtomicU64,
+    bytes: AtomicU64,
+    latency_nanos: AtomicU64,
+}
+
+/// Options of an `InstrumentedKvStore`.
+#[derive(Clone, Debug)]
+pub struct InstrumentedKvStoreOptions {
+    /// Level at which traces are logged, if any. They are only formatted if the level is
+    /// enabled.
+    pub log_level: Option<log::Level>,
+    /// Number of the latest traces kept in memory, 0 to keep none.
+    pub trace_capacity: usize,
+    /// Formats the keys of traces.
+    pub key_formatter: KeyFormatter,
+}
+
+impl Default for InstrumentedKvStoreOptions {
+    fn default() -> Self {
+        Self {
+            log_level: Some(log::Level::Trace),
+            trace_capacity: 0,
+            key_formatter: format_raw_key,
+        }
+    }
+}
+
+struct Recorder {
+    options: InstrumentedKvStoreOptions,
+    counters: [OpCounters; KvOp::ALL.len()],
+    traces: Mutex<VecDeque<KvTrace>>,
+}
+
+impl Recorder {
+    /// Records a call. The key is only formatted if the trace is logged or kept.
+    fn record(
+        &self,
+        op: KvOp,
+        key: impl FnOnce(KeyFormatter) -> String,
+        started: Instant,
+        entries: u64,
+        bytes: u64,
+        error: Option<String>,
+    ) {
+        let latency = started.elapsed();
+        let counters = &self.counters[op as usize];
+        counters.calls.fetch_add(1, Ordering::Relaxed);
+        counters.errors.fetch_add(error.is_some() as u64, Ordering::Relaxed);
+        counters.entries.fetch_add(entries, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        counters.latency_nanos.fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+
+        let log_level = self.options.log_level.filter(|level| log::log_enabled!(*level));
+        if log_level.is_none() && self.options.trace_capacity == 0 {
+            return;
+        }
+        let trace = KvTrace {
+            op, key: key(self.options.key_formatter), latency, entries, bytes, error,
+        };
+        if let Some(level) = log_level {
+            log::log!(level, "{}", trace);
+        }
+        if self.options.trace_capacity > 0 {
+            let mut traces = self.traces.lock();
+            if traces.len() == self.options.trace_capacity {
+                traces.pop_front();
+            }
+            traces.push_back(trace);
+        }
+    }
+}
+
+/// A `KvStore` wrapper recording every call to the inner store: counters per operation, and
+/// optionally traces logged to the `log` crate or kept in memory, with keys formatted by a
+/// `KeyFormatter`. Stores that are not wrapped pay nothing.
+///
+/// Clones share the inner store and the records, so a clone can be inspected while another is
+/// used, e.g. by `MVCC`.
+#[derive(Clone)]
+pub struct InstrumentedKvStore {
+    inner: Arc<dyn KvStore>,
+    recorder: Arc<Recorder>,
+}
+
+impl InstrumentedKvStore {
+    /// Wraps a store, logging traces at the trace level.
+    pub fn new(inner: Box<dyn KvStore>) -> Self {
+        Self::with_options(inner, InstrumentedKvStoreOptions::default())
+    }
+
+    /// Wraps a store with the given options.
+    pub fn with_options(inner: Box<dyn KvStore>, options: InstrumentedKvStoreOptions) -> Self {
+        let recorder = Recorder {
+            traces: Mutex::new(VecDeque::with_capacity(options.trace_capacity)),
+            options,
+            counters: Default::default(),
+        };
+        Self { inner: inner.into(), recorder: Arc::new(recorder) }
+    }
+
+    /// Gets the counters of an operation.
+    pub fn stats(&self, op: KvOp) -> KvOpStats {
+        let counters = &self.recorder.counters[op as usize];
+        KvOpStats {
+            calls: counters.calls.load(Ordering::Relaxed),
+            errors: counters.errors.load(Ordering::Relaxed),
+            entries: counters.entries.load(Ordering::Relaxed),
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            latency: Duration::from_nanos(counters.latency_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Gets the traces kept in memory, oldest first.
+    pub fn traces(&self) -> Vec<KvTrace> {
+        self.recorder.traces.lock().iter().cloned().collect()
+    }
+
+    /// Drops the traces kept in memory.
+    pub fn clear_traces(&self) {
+        self.recorder.traces.lock().clear();
+    }
+}
+
+impl InstrumentedKvStore {
+    /// Wraps a scan to be recorded when it is dropped, or records its error.
+    fn instrument_scan(
+        &self,
+        target: ScanTarget,
+        started: Instant,
+        result: Result<KvScan>,
+    ) -> Result<KvScan> {
+        match result {
+            Ok(scan) => Ok(Box::new(InstrumentedScan {
+                scan,
+                target,
+                recorder: self.recorder.clone(),
+                started,
+                entries: 0,
+                bytes: 0,
+                error: None,
+            })),
+            Err(err) => {
+                let key = |format| target.format(format);
+                self.recorder.record(target.op(), key, started, 0, 0, Some(err.to_string()));
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Display for InstrumentedKvStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "instrumented-{}", self.inner)
+    }
+}
+
+impl KvStore for InstrumentedKvStore {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let started = Instant::now();
+        let bytes = value.len() as u64;
+        let result = self.inner.set(key, value);
+        let error = result.as_ref().err().map(ToString::to_string);
+        self.recorder.record(KvOp::Set, |format| format(key), started, 1, bytes, error);
+        result
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let started = Instant::now();
+        let result = self.inner.get(key);
+        let (entries, bytes, error) = match &result {
+            Ok(value) => (value.is_some() as u64, value.as_ref().map_or(0, Vec::len) as u64, None),
+            Err(err) => (0, 0, Some(err.to_string())),
+        };
+        self.recorder.record(KvOp::Get, |format| format(key), started, entries, bytes, error);
+        result
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let started = Instant::now();
+        let result = self.inner.delete(key);
+        let error = result.as_ref().err().map(ToString::to_string);
+        self.recorder.record(KvOp::Delete, |format| format(key), started, 1, 0, error);
+        result
+    }
+
+    fn scan(&self, range: Range) -> Result<KvScan> {
+        let started = Instant::now();
+        let result = self.inner.scan(range.clone());
+        self.instrument_scan(ScanTarget::Range(range), started, result)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvScan> {
+        let started = Instant::now();
+        let result = self.inner.scan_prefix(prefix);
+        self.instrument_scan(ScanTarget::Prefix(prefix.to_vec()), started, result)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let started = Instant::now();
+        let result = self.inner.flush();
+        let error = result.as_ref().err().map(ToString::to_string);
+        self.recorder.record(KvOp::Flush, |_| String::new(), started, 0, 0, error);
+        result
+    }
+
+    // Estimates are passed through, unrecorded.
+    fn approximate_size(&self, range: Range) -> Result<u64> {
+        self.inner.approximate_size(range)
+    }
+
+    fn approximate_count(&self, range: Range) -> Result<u64> {
+        self.inner.approximate_count(range)
+    }
+
+    fn split_points(&self, range: Range, n: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.split_points(range, n)
+    }
+}
+
+fn format_range(range: &Range, format: KeyFormatter) -> String {
+    let start = match range.start_bound() {
+        Bound::Included(key) => format!("[{}", format(key)),
+        Bound::Excluded(key) => format!("({}", format(key)),
+        Bound::Unbounded => "(".into(),
+    };
+    let end = match range.end_bound() {
+        Bound::Included(key) => format!("{}]", format(key)),
+        Bound::Excluded(key) => format!("{})", format(key)),
+        Bound::Unbounded => ")".into(),
+    };
+    format!("{}..{}", start, end)
+}
+
+/// What a scan reads.
+enum ScanTarget {
+    Range(Range),
+    Prefix(Vec<u8>),
+}
+
+impl ScanTarget {
+    fn op(&self) -> KvOp {
+        match self {
+            Self::Range(_) => KvOp::Scan,
+            Self::Prefix(_) => KvOp::ScanPrefix,
+        }
+    }
+
+    fn format(&self, format: KeyFormatter) -> String {
+        match self {
+            Self::Range(range) => format_range(range, format),
+            Self::Prefix(prefix) => format(prefix),
+        }
+    }
+}
+
+/// A scan of an `InstrumentedKvStore`, recorded when it is dropped.
+struct InstrumentedScan {
+    scan: KvScan,
+    target: ScanTarget,
+    recorder: Arc<Recorder>,
+    started: Instant,
+    entries: u64,
+    bytes: u64,
+    error: Option<String>,
+}
+
+impl InstrumentedScan {
+    fn count(&mut self, item: Option<Result<(Vec<u8>, Vec<u8>)>>)
+        -> Option<Result<(Vec<u8>, Vec<u8>)>>
+    {
+        match &item {
+            Some(Ok((key, value))) => {
+                self.entries += 1;
+                self.bytes += (key.len() + value.len()) as u64;
+            }
+            Some(Err(err)) => self.error = Some(err.to_string()),
+            None => {}
+        }
+        item
+    }
+}
+
+impl Iterator for InstrumentedScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.scan.next();
+        self.count(item)
+    }
+}
+
+impl DoubleEndedIterator for InstrumentedScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let item = self.scan.next_back();
+        self.count(item)
+    }
+}
+
+impl Drop for InstrumentedScan {
+    fn drop(&mut self) {
+        let target = &self.target;
+        self.recorder.record(
+            target.op(),
+            |format| target.format(format),
+            self.started,
+            self.entries,
+            self.bytes,
+            self.error.take(),
+        );
+    }
+}
+
+#[cfg(test)]
+use super::StdBPlusTree;
+
+#[cfg(test)]
+impl super::TestSuite<InstrumentedKvStore> for InstrumentedKvStore {
+    fn setup() -> Result<Self> {
+        Ok(InstrumentedKvStore::new(Box::new(StdBPlusTree::new())))
+    }
+}
+
+
+
+#[test]
+fn tests() -> Result<()> {
+    use super::TestSuite;
+    InstrumentedKvStore::test()
+}
+
+#[test]
+fn test_instrumented_store() -> Result<()> {
+    let options = InstrumentedKvStoreOptions {
+        log_level: None,
+        trace_capacity: 3,
+        ..Default::default()
+    };
+    let store = InstrumentedKvStore::with_options(Box::new(StdBPlusTree::new()), options);
+    store.set(b"a", b"12".to_vec())?;
+    store.set(b"b\x00", b"345".to_vec())?;
+    assert_eq!(store.get(b"a")?, Some(b"12".to_vec()));
+    assert_eq!(store.get(b"c")?, None);
+    store.delete(b"c")?;
+    let scan = store.scan(Range::from(b"a".to_vec()..))?;
+    assert_eq!(store.stats(KvOp::Scan).calls, 0);
+    assert_eq!(scan.rev().count(), 2);
+    store.flush()?;
+
+    assert_eq!(store.stats(KvOp::Set), KvOpStats {
+        calls: 2, errors: 0, entries: 2, bytes: 5, latency: store.stats(KvOp::Set).latency
+    });
+    assert_eq!((store.stats(KvOp::Get).calls, store.stats(KvOp::Get).entries), (2, 1));
+    assert_eq!(store.stats(KvOp::Scan).bytes, 3 + 5);
+
+    // Only the latest traces are kept.
+    let traces = store.traces();
+    assert_eq!(traces.iter().map(|trace| trace.op).collect::<Vec<_>>(),
+        vec![KvOp::Delete, KvOp::Scan, KvOp::Flush]);
+    assert_eq!(traces[1].key, "[\"a\"..)");
+    assert_eq!((traces[1].entries, traces[1].error.as_ref()), (2, None));
+    assert!(traces[0].to_string().starts_with("delete \"c\" in "));
+    store.clear_traces();
+    assert!(store.traces().is_empty());
+
+    // Prefix scans are forwarded, and recorded apart from range scans.
+    assert_eq!(store.scan_prefix(b"b")?.count(), 1);
+    assert_eq!(store.stats(KvOp::Scan).calls, 1);
+    assert_eq!((store.stats(KvOp::ScanPrefix).calls, store.stats(KvOp::ScanPrefix).bytes), (1, 5));
+    assert!(store.traces()[0].to_string().starts_with("scan_prefix \"b\" in "));
+    assert_eq!(format_raw_key(b"b\x00"), "\"b\\x00\"");
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use super::blob::BlobFile;
+use super::encryption::KeyProvider;
+use super::env::StdEnv;
+use super::lsm_storage::{LsmStorage, DEFAULT_COLUMN_FAMILY};
+use super::manifest::{Manifest, ManifestRecord};
+use super::sstable::{FileObject, SsTable};
+
+/// An SSTable belonging to a backup.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct BackupFile {
+    /// The level of the SSTable.
+    level: usize,
+    /// The SSTable ID in the backed-up storage.
+    sstable_id: usize,
+    /// The file name under the `shared` directory.
+    shared_name: String,
+}
+
+/// The files of a column family in a backup.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct BackupFamily {
+    name: String,
+    /// The SSTables, by level.
+    sstables: Vec<BackupFile>,
+    /// The blob files, as their ID and file name under the `shared` directory.
+    blob_files: Vec<(usize, String)>,
+}
+
+/// The files of a backup, the default column family first.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct BackupMeta {
+    families: Vec<BackupFamily>,
+}
+
+/// Incremental backups of an `LsmStorage`. SSTables are immutable, so they are copied into a
+/// `shared` directory once and reused by every later backup that still contains them. Every
+/// column family is backed up, and the storage must be on the real filesystem (`StdEnv`).
+///
+/// Directory layout, where the files of the column families other than the default one are in a
+/// directory named after the family:
+///
+/// ```text
+///     <dir>/shared/<sstable_id>_<created_at>_<size>.sst
+///     <dir>/shared/<blob_file_id>_<created_at>_<size>.blob
+///     <dir>/shared/<column_family>/<sstable_id>_<created_at>_<size>.sst
+///     <dir>/meta/<backup_id>
+/// ```
+pub struct BackupEngine {
+    dir: PathBuf,
+}
+
+impl BackupEngine {
+    /// Opens a backup directory, creating it if needed.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(dir.join("shared"))?;
+        std::fs::create_dir_all(dir.join("meta"))?;
+        Ok(Self { dir })
+    }
+
+    /// Backs up the storage, copying only the SSTables and blob files not already in the backup
+    /// set. Returns the ID of the new backup.
+    pub fn create_backup(&self, storage: &LsmStorage) -> Result<u64> {
+        let backup_id = self.list_backups()?.last().map_or(1, |id| id + 1);
+
+        // A checkpoint gives a consistent set of hard-linked SSTables to copy from.
+        let checkpoint_dir = self.dir.join(format!("tmp_{}", backup_id));
+        if checkpoint_dir.exists() {
+            std::fs::remove_dir_all(&checkpoint_dir)?;
+        }
+        storage.checkpoint(&checkpoint_dir)?;
+        let key_provider = storage.key_provider().map(|key_provider| key_provider.as_ref());
+        let backup_meta = self.copy_checkpoint(&checkpoint_dir, key_provider);
+        std::fs::remove_dir_all(&checkpoint_dir)?;
+
+        // The meta is written aside, so that a crash cannot leave a truncated one.
+        let meta = bincode::serialize(&backup_meta?)?;
+        let meta_path = self.dir.join("meta").join(backup_id.to_string());
+        let tmp_path = meta_path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(&meta)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &meta_path)?;
+        Ok(backup_id)
+    }
+
+    fn copy_checkpoint(
+        &self,
+        checkpoint_dir: &Path,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<BackupMeta> {
+        let mut families = vec![
+            self.copy_family(DEFAULT_COLUMN_FAMILY, checkpoint_dir, key_provider)?
+        ];
+        let mut names = vec![];
+        let families_dir = LsmStorage::path_of_column_families(checkpoint_dir);
+        if families_dir.exists() {
+            for entry in std::fs::read_dir(&families_dir)? {
+                names.push(entry?.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort_unstable();
+        for name in names {
+            let family_dir = LsmStorage::path_of_column_family(checkpoint_dir, &name);
+            families.push(self.copy_family(&name, &family_dir, key_provider)?);
+        }
+        Ok(BackupMeta { families })
+    }
+
+    fn copy_family(
+        &self,
+        name: &str,
+        checkpoint_dir: &Path,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<BackupFamily> {
+        let shared_prefix = match name {
+            DEFAULT_COLUMN_FAMILY => String::new(),
+            name => format!("{}/", name),
+        };
+        let (_, records) = Manifest::recover(&StdEnv, checkpoint_dir.join("MANIFEST"))?;
+        let placements = match records.as_slice() {
+            [ManifestRecord::Snapshot(placements)] => placements.clone(),
+            _ => return Err(Error::Internal("Unexpected checkpoint manifest".into())),
+        };
+        let mut files = vec![];
+        for (level, sstable_id) in placements {
+            let path = LsmStorage::path_of_sst(checkpoint_dir, sstable_id);
+            let file = FileObject::open(&StdEnv, &path)?;
+            let size = file.size();
+            let sstable = SsTable::open_with_keys(sstable_id, None, file, key_provider)?;
+            let shared_name = format!(
+                "{}{:05}_{}_{}.sst",
+                shared_prefix, sstable_id, sstable.properties().created_at, size,
+            );
+            self.copy_to_shared(&path, &shared_name)?;
+            files.push(BackupFile { level, sstable_id, shared_name });
+        }
+        let mut blob_files = vec![];
+        for file_name in std::fs::read_dir(checkpoint_dir)? {
+            let file_name = file_name?.file_name().to_string_lossy().into_owned();
+            let blob_file_id = match file_name.strip_suffix(".blob").map(str::parse::<usize>) {
+                Some(Ok(blob_file_id)) => blob_file_id,
+                _ => continue,
+            };
+            let path = LsmStorage::path_of_blob(checkpoint_dir, blob_file_id);
+            let blob_file = BlobFile::open(&StdEnv, &path, key_provider)?;
+            let shared_name = format!(
+                "{}{:05}_{}_{}.blob",
+                shared_prefix, blob_file_id, blob_file.created_at(), blob_file.size(),
+            );
+            self.copy_to_shared(&path, &shared_name)?;
+            blob_files.push((blob_file_id, shared_name));
+        }
+        Ok(BackupFamily { name: name.to_string(), sstables: files, blob_files })
+    }
+
+    /// Copies a file into the `shared` directory, unless a previous backup already did.
+    fn copy_to_shared(&self, path: &Path, shared_name: &str) -> Result<()> {
+        let shared_path = self.dir.join("shared").join(shared_name);
+        if !shared_path.exists() {
+            if let Some(parent) = shared_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let tmp_path = self.dir.join("shared").join(format!("{}.tmp", shared_name));
+            std::fs::copy(path, &tmp_path)?;
+            std::fs::rename(&tmp_path, &shared_path)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the IDs of all backups, in ascending order.
+    pub fn list_backups(&self) -> Result<Vec<u64>> {
+        let mut ids = vec![];
+        for entry in std::fs::read_dir(self.dir.join("meta"))? {
+            if let Ok(id) = entry?.file_name().to_string_lossy().parse::<u64>() {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Restores a backup into `dir`, which can then be opened with `LsmStorage::open`, or with
+    /// `LsmStorage::open_with_column_families` to read the other column families.
+    pub fn restore_backup(&self, backup_id: u64, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        let meta = std::fs::read(self.dir.join("meta").join(backup_id.to_string()))
+            .map_err(|_| Error::Value(format!("Backup {} not found", backup_id)))?;
+        let backup_meta: BackupMeta = bincode::deserialize(&meta)?;
+        for family in backup_meta.families {
+            let family_dir = match family.name.as_str() {
+                DEFAULT_COLUMN_FAMILY => dir.to_path_buf(),
+                name => LsmStorage::path_of_column_family(dir, name),
+            };
+            std::fs::create_dir_all(&family_dir)?;
+            let mut placements = vec![];
+            for file in family.sstables {
+                std::fs::copy(
+                    self.dir.join("shared").join(&file.shared_name),
+                    LsmStorage::path_of_sst(&family_dir, file.sstable_id),
+                )?;
+                placements.push((file.level, file.sstable_id));
+            }
+            for (blob_file_id, shared_name) in family.blob_files {
+                std::fs::copy(
+                    self.dir.join("shared").join(&shared_name),
+                    LsmStorage::path_of_blob(&family_dir, blob_file_id),
+                )?;
+            }
+            Manifest::create(&StdEnv, family_dir.join("MANIFEST"))?
+                .add_record(&ManifestRecord::Snapshot(placements))?;
+        }
+        Ok(())
+    }
+}
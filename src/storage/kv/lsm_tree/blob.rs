@@ -0,0 +1,252 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut};
+
+use crate::error::{Error, Result};
+use super::encryption::{BlockCipher, KeyProvider};
+use super::env::Env;
+use super::sstable::FileObject;
+
+/// Tag of a value stored inline in the memtable or SsTable.
+const VALUE_INLINE: u8 = 0x00;
+/// Tag of a value stored in a blob file, with only a `BlobPointer` kept in the SsTable.
+const VALUE_BLOB: u8 = 0x01;
+
+/// The location of a value in a blob file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobPointer {
+    /// The blob file ID, which is the ID of the SsTable it was first written with.
+    pub file_id: usize,
+    /// Offset of the value in the blob file.
+    pub offset: u64,
+    /// Length of the value.
+    pub len: u32,
+}
+
+/// A value as stored in the LSM tree. Every non-tombstone value carries a one-byte tag, so that
+/// inline values and blob pointers can be told apart regardless of the storage options.
+///
+/// Data alignment:
+///
+/// ```text
+///     | VALUE_INLINE (1B) | value |
+///     | VALUE_BLOB (1B) | file_id (8B) | offset (8B) | len (4B) |
+/// ```
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoredValue<'a> {
+    Inline(&'a [u8]),
+    Blob(BlobPointer),
+}
+
+impl<'a> StoredValue<'a> {
+    /// Encodes a user value to be stored inline.
+    pub fn encode_inline(value: &[u8]) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(value.len() + 1);
+        buffer.put_u8(VALUE_INLINE);
+        buffer.put_slice(value);
+        buffer
+    }
+
+    /// Encodes a blob pointer.
+    pub fn encode_blob(pointer: &BlobPointer) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(21);
+        buffer.put_u8(VALUE_BLOB);
+        buffer.put_u64(pointer.file_id as u64);
+        buffer.put_u64(pointer.offset);
+        buffer.put_u32(pointer.len);
+        buffer
+    }
+
+    /// Decodes a stored (non-tombstone) value.
+    pub fn decode(mut stored: &'a [u8]) -> Result<Self> {
+        if stored.is_empty() {
+            return Err(Error::Internal("Cannot decode a tombstone".into()));
+        }
+        match stored.get_u8() {
+            VALUE_INLINE => Ok(Self::Inline(stored)),
+            VALUE_BLOB if stored.len() == 20 => Ok(Self::Blob(BlobPointer {
+                file_id: stored.get_u64() as usize,
+                offset: stored.get_u64(),
+                len: stored.get_u32(),
+            })),
+            tag => Err(Error::Internal(format!("Invalid stored value with tag {:x?}", tag))),
+        }
+    }
+}
+
+/// Magic number ending blob files.
+const BLOB_MAGIC: &[u8; 8] = b"FEATHBLB";
+/// Length of the trailer of blob files.
+const BLOB_TRAILER_LEN: u64 = 21;
+
+/// An immutable, append-only file of large values, referenced by `BlobPointer`s. A blob file is
+/// written along with an SsTable and shares its ID, but outlives it: compactions carry the
+/// pointers over unchanged, and only blob garbage collection rewrites the values. Encrypted blob
+/// files hold each value as a block at its offset.
+///
+/// Data alignment:
+///
+/// ```text
+///     | values | created_at (8B) | key_id (4B) | encrypted (1B) | BLOB_MAGIC (8B) |
+/// ```
+pub struct BlobFile {
+    file: FileObject,
+    cipher: Option<BlockCipher>,
+    /// Seconds since the Unix epoch when the blob file was written.
+    created_at: u64,
+}
+
+impl BlobFile {
+    /// Open a blob file from the disk, with the provider of its key if it is encrypted.
+    pub fn open(
+        env: &dyn Env,
+        path: &Path,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<Self> {
+        let file = FileObject::open(env, path)?;
+        let size = file.size();
+        if size < BLOB_TRAILER_LEN || file.read(size - 8, 8)? != BLOB_MAGIC {
+            return Err(Error::Internal(format!("{} is not a blob file", path.display())));
+        }
+        let trailer = file.read(size - BLOB_TRAILER_LEN, BLOB_TRAILER_LEN - 8)?;
+        let mut trailer = &trailer[..];
+        let created_at = trailer.get_u64();
+        let key_id = trailer.get_u32();
+        let cipher = match trailer.get_u8() {
+            0 => None,
+            _ => {
+                let key_provider = key_provider.ok_or_else(|| Error::Value(format!(
+                    "Blob file {} is encrypted with key {}, but no key provider is set",
+                    path.display(), key_id
+                )))?;
+                Some(BlockCipher::open(key_provider, key_id)?)
+            }
+        };
+        Ok(Self { file, cipher, created_at })
+    }
+
+    /// Read the value a pointer refers to.
+    pub fn read(&self, pointer: &BlobPointer) -> Result<Vec<u8>> {
+        if pointer.offset + pointer.len as u64 > self.data_size() {
+            return Err(Error::Internal(format!(
+                "Blob pointer {:?} out of the bounds of the blob file", pointer
+            )));
+        }
+        let value = self.file.read(pointer.offset, pointer.len as u64)?;
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(pointer.offset, &value),
+            None => Ok(value),
+        }
+    }
+
+    /// Get the size of the blob file.
+    pub fn size(&self) -> u64 {
+        self.file.size()
+    }
+
+    /// Get the size of the values in the blob file, without the trailer.
+    pub fn data_size(&self) -> u64 {
+        self.file.size() - BLOB_TRAILER_LEN
+    }
+
+    /// Get the ID of the key the blob file is encrypted with, if it is.
+    pub fn key_id(&self) -> Option<u32> {
+        self.cipher.as_ref().map(|cipher| cipher.key_id())
+    }
+
+    /// Get the time the blob file was written, in seconds since the Unix epoch.
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+}
+
+/// Builds a blob file by appending values.
+pub struct BlobFileBuilder {
+    file_id: usize,
+    data: Vec<u8>,
+    cipher: Option<BlockCipher>,
+}
+
+impl BlobFileBuilder {
+    /// Create a builder for the blob file with the given ID.
+    pub fn new(file_id: usize) -> Self {
+        Self { file_id, data: Vec::new(), cipher: None }
+    }
+
+    /// Encrypts the values with the cipher.
+    pub fn with_cipher(mut self, cipher: Option<BlockCipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Appends a value, returning a pointer to it.
+    pub fn add(&mut self, value: &[u8]) -> BlobPointer {
+        let offset = self.data.len() as u64;
+        match &self.cipher {
+            Some(cipher) => self.data.extend(cipher.encrypt(offset, value)),
+            None => self.data.extend_from_slice(value),
+        }
+        BlobPointer { file_id: self.file_id, offset, len: (self.data.len() as u64 - offset) as u32 }
+    }
+
+    /// Check if no value has been added.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Writes the blob file to the given path.
+    pub fn build(mut self, env: &dyn Env, path: impl AsRef<Path>) -> Result<BlobFile> {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        self.data.put_u64(created_at);
+        self.data.put_u32(self.cipher.as_ref().map_or(0, |cipher| cipher.key_id()));
+        self.data.put_u8(self.cipher.is_some() as u8);
+        self.data.put_slice(BLOB_MAGIC);
+        let file = FileObject::create(env, path.as_ref(), self.data)?;
+        Ok(BlobFile { file, cipher: self.cipher, created_at })
+    }
+}
+
+
+#[cfg(test)]
+use tempfile::tempdir;
+
+#[cfg(test)]
+use super::env::StdEnv;
+
+#[test]
+fn test_stored_value_encoding() {
+    let inline = StoredValue::encode_inline(b"233");
+    assert_eq!(StoredValue::decode(&inline).unwrap(), StoredValue::Inline(b"233"));
+    let inline = StoredValue::encode_inline(b"");
+    assert_eq!(StoredValue::decode(&inline).unwrap(), StoredValue::Inline(b""));
+    let pointer = BlobPointer { file_id: 3, offset: 233, len: 2333 };
+    let blob = StoredValue::encode_blob(&pointer);
+    assert_eq!(StoredValue::decode(&blob).unwrap(), StoredValue::Blob(pointer));
+    assert!(StoredValue::decode(&[]).is_err());
+    assert!(StoredValue::decode(&[0x02, 0x00]).is_err());
+    assert!(StoredValue::decode(&blob[..10]).is_err());
+}
+
+#[test]
+fn test_blob_file() {
+    let dir = tempdir().unwrap();
+    let mut builder = BlobFileBuilder::new(1);
+    assert!(builder.is_empty());
+    let first = builder.add(b"value_1");
+    let second = builder.add(&[0x42; 10000]);
+    let blob_file = builder.build(&StdEnv, dir.path().join("00001.blob")).unwrap();
+    assert_eq!(blob_file.read(&first).unwrap(), b"value_1");
+    assert_eq!(blob_file.read(&second).unwrap(), vec![0x42; 10000]);
+    assert!(blob_file.read(&BlobPointer { file_id: 1, offset: 10000, len: 10 }).is_err());
+
+    // Blob files are self-describing, and can be opened without their SsTable.
+    let blob_file = BlobFile::open(&StdEnv, &dir.path().join("00001.blob"), None).unwrap();
+    assert_eq!(blob_file.data_size(), 10007);
+    assert_eq!(blob_file.key_id(), None);
+    assert_eq!(blob_file.read(&second).unwrap(), vec![0x42; 10000]);
+    std::fs::write(dir.path().join("00002.blob"), b"value_1").unwrap();
+    assert!(BlobFile::open(&StdEnv, &dir.path().join("00002.blob"), None).is_err());
+}
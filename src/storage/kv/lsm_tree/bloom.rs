@@ -0,0 +1,93 @@
+use bytes::{Buf, BufMut, Bytes};
+
+/// A Bloom filter over 32-bit key hashes, using double hashing to derive the probe positions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bloom {
+    /// The filter bits.
+    filter: Bytes,
+    /// Number of probes per key.
+    k: u8,
+}
+
+/// Data alignment:
+///
+/// ```text
+///     | filter bits | k (1B) |
+/// ```
+impl Bloom {
+    /// Hashes a key with FNV-1a. The hash is persisted in filters, so it must stay stable.
+    pub fn hash(key: &[u8]) -> u32 {
+        key.iter().fold(0x811c9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193))
+    }
+
+    /// Builds a filter over key hashes, with `bits_per_key` bits of filter per key.
+    pub fn build_from_key_hashes(hashes: &[u32], bits_per_key: usize) -> Self {
+        // k = ln(2) * bits per key minimizes the false positive rate.
+        let k = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+        let num_bits = (hashes.len() * bits_per_key).max(64);
+        let mut filter = vec![0u8; num_bits.div_ceil(8)];
+        let num_bits = filter.len() * 8;
+        for hash in hashes {
+            let mut hash = *hash;
+            let delta = hash.rotate_left(15);
+            for _ in 0..k {
+                let bit = hash as usize % num_bits;
+                filter[bit / 8] |= 1 << (bit % 8);
+                hash = hash.wrapping_add(delta);
+            }
+        }
+        Self { filter: filter.into(), k }
+    }
+
+    /// Checks if a key hash may have been added to the filter.
+    pub fn may_contain(&self, mut hash: u32) -> bool {
+        let num_bits = self.filter.len() * 8;
+        if num_bits == 0 {
+            return true;
+        }
+        let delta = hash.rotate_left(15);
+        for _ in 0..self.k {
+            let bit = hash as usize % num_bits;
+            if self.filter[bit / 8] & (1 << (bit % 8)) == 0 {
+                return false;
+            }
+            hash = hash.wrapping_add(delta);
+        }
+        true
+    }
+
+    /// Encode the filter to a buffer.
+    pub fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.put_slice(&self.filter);
+        buffer.put_u8(self.k);
+    }
+
+    /// Decode a filter from a buffer holding exactly the encoded filter.
+    pub fn decode(mut buffer: &[u8]) -> Self {
+        if buffer.is_empty() {
+            return Self { filter: Bytes::new(), k: 0 };
+        }
+        let filter = buffer.copy_to_bytes(buffer.len() - 1);
+        let k = buffer.get_u8();
+        Self { filter, k }
+    }
+}
+
+
+
+#[test]
+fn test_bloom() {
+    let keys = (0..1000).map(|idx| format!("key_{:05}", idx)).collect::<Vec<_>>();
+    let hashes = keys.iter().map(|key| Bloom::hash(key.as_bytes())).collect::<Vec<_>>();
+    let bloom = Bloom::build_from_key_hashes(&hashes, 10);
+    let mut buffer = vec![];
+    bloom.encode(&mut buffer);
+    let bloom = Bloom::decode(&buffer);
+    for hash in hashes {
+        assert!(bloom.may_contain(hash));
+    }
+    let false_positives = (1000..11000)
+        .filter(|idx| bloom.may_contain(Bloom::hash(format!("key_{:05}", idx).as_bytes())))
+        .count();
+    assert!(false_positives < 300, "{} false positives", false_positives);
+}
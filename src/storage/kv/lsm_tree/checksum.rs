@@ -0,0 +1,15 @@
+/// Computes the CRC-32 (IEEE 802.3) checksum of the data, which detects corrupted SSTable blocks,
+/// WAL batches and records of the `storage::log` files.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    assert_ne!(crc32(b"block"), crc32(b"blocl"));
+}
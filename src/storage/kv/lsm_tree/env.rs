@@ -0,0 +1,425 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use rand::RngCore;
+
+use crate::error::{Error, Result};
+
+/// A file opened for reading at arbitrary offsets.
+pub trait RandomAccessFile: Send + Sync {
+    /// Reads exactly `len` bytes at `offset`.
+    fn read_at(&self, offset: u64, len: u64) -> Result<Vec<u8>>;
+
+    /// Gets the size of the file.
+    fn size(&self) -> u64;
+}
+
+/// A file opened for appending.
+pub trait WritableFile: Send + Sync {
+    /// Appends data to the file. The data is only durable once synced.
+    fn append(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Syncs the appended data to the disk.
+    fn sync(&mut self) -> Result<()>;
+}
+
+/// The filesystem used by the LSM tree for its SsTables, blob files, WAL and manifests, so that
+/// tests can inject faults into it.
+pub trait Env: Debug + Send + Sync {
+    /// Opens an existing file for reading.
+    fn open_random_access(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>>;
+
+    /// Creates a file for appending, truncating it if it exists.
+    fn create_writable(&self, path: &Path) -> Result<Box<dyn WritableFile>>;
+
+    /// Opens an existing file for appending.
+    fn open_appendable(&self, path: &Path) -> Result<Box<dyn WritableFile>>;
+
+    /// Reads a whole file.
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let file = self.open_random_access(path)?;
+        file.read_at(0, file.size())
+    }
+
+    /// Checks if a file or directory exists.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Creates a directory and all its missing parents.
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// Lists the names of the entries of a directory.
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>>;
+
+    /// Deletes a file.
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    /// Renames a file, replacing `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Hard-links a file to a new path, or copies it if it cannot be linked.
+    fn link_or_copy(&self, from: &Path, to: &Path) -> Result<()>;
+}
+
+/// The real filesystem.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdEnv;
+
+struct StdRandomAccessFile(File, u64);
+
+impl RandomAccessFile for StdRandomAccessFile {
+    fn read_at(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        use std::os::unix::fs::FileExt;
+        let mut data = vec![0; len as usize];
+        self.0.read_exact_at(&mut data[..], offset)?;
+        Ok(data)
+    }
+
+    fn size(&self) -> u64 {
+        self.1
+    }
+}
+
+struct StdWritableFile(File);
+
+impl WritableFile for StdWritableFile {
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        Ok(self.0.write_all(data)?)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(self.0.sync_all()?)
+    }
+}
+
+impl Env for StdEnv {
+    fn open_random_access(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>> {
+        let file = File::options().read(true).write(false).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Box::new(StdRandomAccessFile(file, size)))
+    }
+
+    fn create_writable(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new().create(true).truncate(true).write(true).open(path)?;
+        Ok(Box::new(StdWritableFile(file)))
+    }
+
+    fn open_appendable(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        Ok(Box::new(StdWritableFile(OpenOptions::new().append(true).open(path)?)))
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        Ok(std::fs::create_dir_all(path)?)
+    }
+
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(path)? {
+            names.push(entry?.file_name().to_string_lossy().to_string());
+        }
+        Ok(names)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        Ok(std::fs::remove_file(path)?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        Ok(std::fs::rename(from, to)?)
+    }
+
+    fn link_or_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        if std::fs::hard_link(from, to).is_err() {
+            std::fs::copy(from, to)?;
+        }
+        Ok(())
+    }
+}
+
+/// A file of the in-memory filesystem.
+#[derive(Default)]
+struct MemFile {
+    data: Vec<u8>,
+    /// Length of the data synced to the "disk", which survives a crash.
+    synced_len: usize,
+}
+
+#[derive(Default)]
+struct MemFs {
+    files: HashMap<PathBuf, Arc<Mutex<MemFile>>>,
+    dirs: BTreeSet<PathBuf>,
+    /// Incremented by every crash, so that files opened before it can no longer be written.
+    epoch: u64,
+    fail_writes: bool,
+    /// A file whose syncs fail, leaving its appended data for a crash to tear.
+    fail_syncs: Option<PathBuf>,
+}
+
+/// An in-memory filesystem that can simulate crashes, which drop the data not synced yet, and
+/// inject write failures and corrupted bytes. Deleted and renamed files stay so after a crash.
+#[derive(Clone, Default)]
+pub struct FaultInjectionEnv {
+    fs: Arc<Mutex<MemFs>>,
+}
+
+impl Debug for FaultInjectionEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FaultInjectionEnv")
+    }
+}
+
+impl FaultInjectionEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Simulates a crash, dropping the data of every file that was not synced.
+    pub fn crash(&self) {
+        let mut fs = self.fs.lock();
+        fs.epoch += 1;
+        for file in fs.files.values() {
+            let mut file = file.lock();
+            let synced_len = file.synced_len;
+            file.data.truncate(synced_len);
+        }
+    }
+
+    /// Simulates a crash that tears writes: every file keeps a random part of its data that was
+    /// not synced.
+    pub fn crash_torn(&self, rng: &mut dyn RngCore) {
+        let mut fs = self.fs.lock();
+        fs.epoch += 1;
+        for file in fs.files.values() {
+            let mut file = file.lock();
+            let unsynced_len = file.data.len() - file.synced_len;
+            let kept_len = file.synced_len + (rng.next_u64() as usize % (unsynced_len + 1));
+            file.data.truncate(kept_len);
+            file.synced_len = kept_len;
+        }
+    }
+
+    /// Makes every write, sync and file creation fail until reset.
+    pub fn set_fail_writes(&self, fail_writes: bool) {
+        self.fs.lock().fail_writes = fail_writes;
+    }
+
+    /// Makes every sync of the file at the given path fail until reset, while appends still
+    /// succeed, so that a crash can tear an append to that file alone.
+    pub fn set_fail_syncs(&self, path: Option<&Path>) {
+        self.fs.lock().fail_syncs = path.map(Path::to_path_buf);
+    }
+
+    /// Corrupts the byte of a file at `offset` by flipping all its bits.
+    pub fn corrupt(&self, path: &Path, offset: usize) -> Result<()> {
+        let file = self.file(path)?;
+        let mut file = file.lock();
+        match file.data.get_mut(offset) {
+            Some(byte) => *byte = !*byte,
+            None => return Err(Error::Value(format!("Offset {} out of bounds", offset))),
+        }
+        Ok(())
+    }
+
+    fn file(&self, path: &Path) -> Result<Arc<Mutex<MemFile>>> {
+        self.fs.lock().files.get(path).cloned().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, path.display().to_string()).into()
+        })
+    }
+
+    fn check_writable(fs: &MemFs, epoch: u64) -> Result<()> {
+        if fs.fail_writes {
+            return Err(Error::Internal("Injected write failure".into()));
+        }
+        if fs.epoch != epoch {
+            return Err(Error::Internal("File was opened before a crash".into()));
+        }
+        Ok(())
+    }
+
+    fn check_parent(fs: &MemFs, path: &Path) -> Result<()> {
+        match path.parent() {
+            Some(parent) if !fs.dirs.contains(parent) => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound, parent.display().to_string()
+            ).into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+struct MemRandomAccessFile(Arc<Mutex<MemFile>>);
+
+impl RandomAccessFile for MemRandomAccessFile {
+    fn read_at(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let file = self.0.lock();
+        file.data
+            .get(offset as usize..(offset + len) as usize)
+            .map(|data| data.to_vec())
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+    }
+
+    fn size(&self) -> u64 {
+        self.0.lock().data.len() as u64
+    }
+}
+
+struct MemWritableFile {
+    fs: Arc<Mutex<MemFs>>,
+    path: PathBuf,
+    file: Arc<Mutex<MemFile>>,
+    epoch: u64,
+}
+
+impl WritableFile for MemWritableFile {
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        let fs = self.fs.lock();
+        FaultInjectionEnv::check_writable(&fs, self.epoch)?;
+        self.file.lock().data.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let fs = self.fs.lock();
+        FaultInjectionEnv::check_writable(&fs, self.epoch)?;
+        if fs.fail_syncs.as_ref() == Some(&self.path) {
+            return Err(Error::Internal("Injected sync failure".into()));
+        }
+        let mut file = self.file.lock();
+        file.synced_len = file.data.len();
+        Ok(())
+    }
+}
+
+impl Env for FaultInjectionEnv {
+    fn open_random_access(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(MemRandomAccessFile(self.file(path)?)))
+    }
+
+    fn create_writable(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let mut fs = self.fs.lock();
+        Self::check_writable(&fs, fs.epoch)?;
+        Self::check_parent(&fs, path)?;
+        let file = Arc::new(Mutex::new(MemFile::default()));
+        fs.files.insert(path.to_path_buf(), file.clone());
+        let path = path.to_path_buf();
+        Ok(Box::new(MemWritableFile { fs: self.fs.clone(), path, file, epoch: fs.epoch }))
+    }
+
+    fn open_appendable(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = self.file(path)?;
+        let epoch = self.fs.lock().epoch;
+        let path = path.to_path_buf();
+        Ok(Box::new(MemWritableFile { fs: self.fs.clone(), path, file, epoch }))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let fs = self.fs.lock();
+        fs.files.contains_key(path) || fs.dirs.contains(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let mut fs = self.fs.lock();
+        for ancestor in path.ancestors() {
+            fs.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>> {
+        let fs = self.fs.lock();
+        if !fs.dirs.contains(path) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound, path.display().to_string()
+            ).into());
+        }
+        Ok(fs.files.keys()
+            .chain(fs.dirs.iter())
+            .filter(|entry| entry.parent() == Some(path))
+            .filter_map(|entry| entry.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let mut fs = self.fs.lock();
+        Self::check_writable(&fs, fs.epoch)?;
+        match fs.files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound, path.display().to_string()
+            ).into()),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut fs = self.fs.lock();
+        Self::check_writable(&fs, fs.epoch)?;
+        Self::check_parent(&fs, to)?;
+        let file = fs.files.remove(from).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::NotFound, from.display().to_string()
+        ))?;
+        fs.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn link_or_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut fs = self.fs.lock();
+        Self::check_writable(&fs, fs.epoch)?;
+        Self::check_parent(&fs, to)?;
+        let file = fs.files.get(from).cloned().ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::NotFound, from.display().to_string()
+        ))?;
+        fs.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+}
+
+
+
+#[test]
+fn test_fault_injection_env() {
+    let env = FaultInjectionEnv::new();
+    let dir = Path::new("/db");
+    assert!(env.create_writable(&dir.join("1")).is_err());
+    env.create_dir_all(dir).unwrap();
+    let mut file = env.create_writable(&dir.join("1")).unwrap();
+    file.append(b"synced").unwrap();
+    file.sync().unwrap();
+    file.append(b" unsynced").unwrap();
+    assert_eq!(env.read_file(&dir.join("1")).unwrap(), b"synced unsynced");
+    assert_eq!(env.list_dir(dir).unwrap(), vec!["1".to_string()]);
+
+    // A crash drops unsynced data, and files opened before it cannot be written anymore.
+    env.crash();
+    assert_eq!(env.read_file(&dir.join("1")).unwrap(), b"synced");
+    assert!(file.append(b"lost").is_err());
+
+    let mut file = env.open_appendable(&dir.join("1")).unwrap();
+    env.set_fail_writes(true);
+    assert!(file.append(b"failed").is_err());
+    env.set_fail_writes(false);
+    file.append(b"!").unwrap();
+    env.set_fail_syncs(Some(&dir.join("1")));
+    assert!(file.sync().is_err());
+    env.set_fail_syncs(None);
+    env.corrupt(&dir.join("1"), 0).unwrap();
+    assert_eq!(env.read_file(&dir.join("1")).unwrap(), [&[!b's'][..], b"ynced!"].concat());
+
+    env.link_or_copy(&dir.join("1"), &dir.join("2")).unwrap();
+    env.remove_file(&dir.join("1")).unwrap();
+    assert!(!env.exists(&dir.join("1")));
+    assert_eq!(env.read_file(&dir.join("2")).unwrap().len(), 7);
+}
@@ -1,19 +1,84 @@
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Display;
 use std::path::{Path, PathBuf};
+use std::ops::{Bound, RangeBounds};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use parking_lot::{RwLock, Mutex};
 
-use crate::error::Result;
-use super::super::{KvStore, Range, KvScan};
+use crate::error::{Error, Result};
+use super::super::{split_by_size, KvStore, Range, KvScan};
+use super::blob::{BlobFile, BlobFileBuilder, StoredValue};
 use super::block::Block;
+use super::encryption::{BlockCipher, KeyProvider};
+use super::env::{Env, StdEnv};
 use super::iterators::{MergeIter, TwoMergeIter};
 use super::lsm_iterator::LsmIter;
+use super::manifest::{Manifest, ManifestRecord, ManifestState};
 use super::memtable::MemTable;
-use super::sstable::{SsTable, SsTableBuilder, SsTableIter};
+use super::prefix::PrefixExtractor;
+use super::rate_limiter::{RateLimitedEnv, RateLimiter};
+use super::sstable::{FileObject, SsTable, SsTableBuilder, SsTableIter};
+use super::wal::{Wal, WalBatches, WalEntry};
 
 pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 
+/// Number of levels below L0.
+const NUM_LEVELS: usize = 6;
+
+/// Options for opening an `LsmStorage`.
+#[derive(Clone, Debug)]
+pub struct LsmStorageOptions {
+    /// Target size of SsTable data blocks, in bytes.
+    pub block_size: usize,
+    /// Values at least this large are moved to a blob file when their memtable is flushed, and
+    /// the SsTable only keeps a pointer to them. None keeps all values inline.
+    pub blob_threshold: Option<usize>,
+    /// Extracts the key prefixes added to the prefix filter of each new SsTable, which let
+    /// `scan_prefix` skip SsTables without the prefix. None builds no prefix filters.
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// Syncs the WAL after every write, so that acknowledged writes survive a machine crash.
+    /// Otherwise, writes survive a process crash but may be lost with the page cache.
+    pub sync_writes: bool,
+    /// The filesystem holding the storage. The one of the default column family is used for all
+    /// column families.
+    pub env: Arc<dyn Env>,
+    /// Limits the bytes written per second by flushes, compactions and blob garbage collection.
+    /// An auto-tuned limiter follows the total size of L0 SsTables, the pending compaction debt.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Target size of SsTables written by compactions, in bytes.
+    pub target_file_size: usize,
+    /// Number of L0 SsTables that makes a flush compact them into L1. None, the default, never
+    /// compacts L0 automatically, leaving it to `compact_range`.
+    pub l0_compaction_trigger: Option<usize>,
+    /// Maximum number of threads a compaction is split into, each merging a disjoint key range.
+    /// Compactions get one subcompaction per `target_file_size` bytes of input, up to this limit.
+    pub max_subcompactions: usize,
+    /// Encrypts new SsTables, blob files and WAL segments with the current key of the provider.
+    /// Existing files stay readable while the provider has their keys. Compactions rewrite
+    /// SsTables, and blob garbage collection blob files, with the current key. The one of the
+    /// default column family is used for all column families.
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
+}
+
+impl Default for LsmStorageOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            blob_threshold: None,
+            prefix_extractor: None,
+            sync_writes: false,
+            env: Arc::new(StdEnv),
+            rate_limiter: None,
+            target_file_size: 2 << 20,
+            l0_compaction_trigger: None,
+            max_subcompactions: 4,
+            key_provider: None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct LsmStorageInner {
     /// The current memtable.
@@ -23,8 +88,9 @@ pub struct LsmStorageInner {
     /// L0 SsTables, from earliest to latest.
     l0_sstables: Vec<Arc<SsTable>>,
     /// L1 - L6 SsTables, sorted by key range.
-    #[allow(dead_code)]
     levels: Vec<Vec<Arc<SsTable>>>,
+    /// Blob files, by ID.
+    blob_files: HashMap<usize, Arc<BlobFile>>,
     /// The next SSTable ID.
     next_sst_id: usize,
 }
@@ -35,163 +101,622 @@ impl LsmStorageInner {
             memtable: Arc::new(MemTable::create()),
             imm_memtables: vec![],
             l0_sstables: vec![],
-            levels: vec![],
+            levels: vec![vec![]; NUM_LEVELS],
+            blob_files: HashMap::new(),
             next_sst_id: 1,
         }
     }
-}
 
-/// The storage interface of the LSM tree.
-pub struct LsmStorage {
-    inner: Arc<RwLock<Arc<LsmStorageInner>>>,
-    flush_lock: Mutex<()>,
-    path: PathBuf,
-    block_cache: Arc<BlockCache>,
-}
-
-impl LsmStorage {
-    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self {
-            inner: Arc::new(RwLock::new(Arc::new(LsmStorageInner::create()))),
-            flush_lock: Mutex::new(()),
-            path: path.as_ref().to_path_buf(),
-            block_cache: Arc::new(BlockCache::new(1 << 20)), // 4GB block cache
-        })
+    /// All SsTables, from the newest data to the oldest: L0 from latest to earliest, then L1 - L6.
+    fn sstables(&self) -> impl Iterator<Item = &Arc<SsTable>> {
+        self.l0_sstables.iter().rev().chain(self.levels.iter().flatten())
     }
-}
 
-impl KvStore for LsmStorage {
-    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        assert!(!key.is_empty(), "key cannot be empty");
-        assert!(!value.is_empty(), "value cannot be empty");
-
-        let session = self.inner.read();
-        session.memtable.set(key, value);
+    /// Adds an SsTable to a level. L0 tables are appended as the latest; other levels are kept
+    /// sorted by key range.
+    fn add_sstable(&mut self, level: usize, sstable: Arc<SsTable>) {
+        if level == 0 {
+            self.l0_sstables.push(sstable);
+            return;
+        }
+        let tables = &mut self.levels[level - 1];
+        let idx = tables.partition_point(
+            |table| table.properties().first_key < sstable.properties().first_key
+        );
+        tables.insert(idx, sstable);
+    }
 
-        Ok(())
+    /// Replaces an SsTable in place with a rewritten one covering a subset of its key range, or
+    /// removes it if there is no replacement.
+    fn replace_sstable(&mut self, sstable_id: usize, replacement: Option<Arc<SsTable>>) {
+        for tables in std::iter::once(&mut self.l0_sstables).chain(self.levels.iter_mut()) {
+            if let Some(idx) = tables.iter().position(|table| table.id() == sstable_id) {
+                match replacement {
+                    Some(sstable) => tables[idx] = sstable,
+                    None => { tables.remove(idx); },
+                }
+                return;
+            }
+        }
     }
 
-    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let snapshot = {
-            let session = self.inner.read();
-            Arc::clone(&session)
-        };
+    /// Turns a stored value into the user value, reading it from its blob file if needed.
+    fn resolve_value(&self, stored: &[u8]) -> Result<Vec<u8>> {
+        match StoredValue::decode(stored)? {
+            StoredValue::Inline(value) => Ok(value.to_vec()),
+            StoredValue::Blob(pointer) => self.blob_files
+                .get(&pointer.file_id)
+                .ok_or_else(|| Error::Internal(format!("Blob file {} not found", pointer.file_id)))?
+                .read(&pointer),
+        }
+    }
 
+    /// Searches the memtables and the SsTables for the stored value of a key. Returns `Some` of
+    /// an empty value for a tombstone.
+    fn get_stored(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         // Search in the current memtable.
-        if let Some(value) = snapshot.memtable.get(key) {
-            match value.is_empty() {
-                true => return Ok(None),
-                false => return Ok(Some(value)),
-            }
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(Some(value));
         }
 
         // Search in immutable memtables.
-        for memtable in snapshot.imm_memtables.iter().rev() {
+        for memtable in self.imm_memtables.iter().rev() {
             if let Some(value) = memtable.get(key) {
-                match value.is_empty() {
-                    true => return Ok(None),
-                    false => return Ok(Some(value)),
-                }
+                return Ok(Some(value));
             }
         }
 
         // Search in SsTables.
         let mut sstable_iters = vec![];
-        sstable_iters.reserve(snapshot.l0_sstables.len());
-        for sstable in snapshot.l0_sstables.iter().rev() {
+        for sstable in self.sstables() {
+            if !sstable.may_contain_key(key) {
+                continue;
+            }
             sstable_iters.push(Box::new(
                 SsTableIter::create_and_seek_to_key(sstable.clone(), key, true)?
             ));
         }
         let mut merge_iter = MergeIter::create(sstable_iters)?;
         match merge_iter.next().transpose()? {
-            None => Ok(None),
-            Some((result_key, value)) => {
-                match key == result_key {
-                    true => match value.is_empty() {
-                        true => return Ok(None),
-                        false => return Ok(Some(value)),
-                    },
-                    false => Ok(None),
+            Some((result_key, value)) if key == result_key => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Checks whether a key has a version (or tombstone) in the memtables or in any of the given
+    /// SsTables.
+    fn is_shadowed<'a>(
+        &self,
+        key: &[u8],
+        mut sstables: impl Iterator<Item = &'a Arc<SsTable>>,
+    ) -> Result<bool> {
+        if std::iter::once(&self.memtable)
+            .chain(self.imm_memtables.iter())
+            .any(|memtable| memtable.get(key).is_some())
+        {
+            return Ok(true);
+        }
+        sstables.try_fold(false, |shadowed, sstable| {
+            if shadowed || !sstable.may_contain_key(key) {
+                return Ok(shadowed);
+            }
+            let mut iter = SsTableIter::create_and_seek_to_key(sstable.clone(), key, true)?;
+            Ok(matches!(iter.next().transpose()?, Some((found, _)) if found == key))
+        })
+    }
+}
+
+/// Name of the column family every `LsmStorage` has, kept in the storage directory itself.
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/// A column family: an independent keyspace of an `LsmStorage`, with its own memtables, SsTables
+/// and options. All column families of a storage share its write-ahead log.
+#[derive(Clone)]
+pub struct ColumnFamily {
+    name: String,
+    inner: Arc<RwLock<Arc<LsmStorageInner>>>,
+    flush_lock: Arc<Mutex<()>>,
+    path: PathBuf,
+    block_cache: Arc<BlockCache>,
+    /// The manifest, or None if the column family was opened read-only.
+    manifest: Option<Arc<Manifest>>,
+    options: LsmStorageOptions,
+    /// The WAL, or None if the column family was opened read-only.
+    wal: Option<Arc<Wal>>,
+}
+
+impl ColumnFamily {
+    /// Opens a column family in the given directory, restoring the SsTables recorded in its
+    /// manifest, or read-only without a WAL. Returns the column family along with the first WAL
+    /// segment whose writes may not be in its SsTables yet.
+    fn open(
+        name: &str,
+        path: PathBuf,
+        options: LsmStorageOptions,
+        wal: Option<Arc<Wal>>,
+    ) -> Result<(Self, u64)> {
+        let env = options.env.as_ref();
+        let manifest_path = path.join("MANIFEST");
+        let (manifest, records) = match (&wal, env.exists(&manifest_path)) {
+            (Some(_), false) => {
+                env.create_dir_all(&path)?;
+                (Some(Arc::new(Manifest::create(env, &manifest_path)?)), vec![])
+            }
+            (Some(_), true) => {
+                let (manifest, records) = Manifest::recover(env, &manifest_path)?;
+                (Some(Arc::new(manifest)), records)
+            }
+            (None, false) => (None, vec![]),
+            (None, true) => (None, Manifest::read_records(env, &manifest_path)?),
+        };
+        let block_cache = Arc::new(BlockCache::new(1 << 20)); // 4GB block cache
+        let (inner, wal_segment) =
+            Self::load_sstables(&options, &path, &block_cache, records, None)?;
+
+        let column_family = Self {
+            name: name.to_string(),
+            inner: Arc::new(RwLock::new(Arc::new(inner))),
+            flush_lock: Arc::new(Mutex::new(())),
+            path,
+            block_cache,
+            manifest,
+            options,
+            wal,
+        };
+        Ok((column_family, wal_segment))
+    }
+
+    /// Restores the SsTables recorded by manifest records into a state with empty memtables,
+    /// reusing the already opened SsTables of `previous`. Returns the state along with the first
+    /// WAL segment whose writes may not be in the SsTables.
+    fn load_sstables(
+        options: &LsmStorageOptions,
+        path: &Path,
+        block_cache: &Arc<BlockCache>,
+        records: Vec<ManifestRecord>,
+        previous: Option<&LsmStorageInner>,
+    ) -> Result<(LsmStorageInner, u64)> {
+        let env = options.env.as_ref();
+        let key_provider = options.key_provider.as_deref();
+        let mut inner = LsmStorageInner::create();
+
+        // Replay the records on SsTable IDs first, as later records may delete files added by
+        // earlier ones.
+        let state = ManifestState::replay(records)?;
+        inner.next_sst_id = state.next_sst_id;
+
+        let opened = previous.map_or_else(HashMap::new, |previous| {
+            previous.sstables().map(|sstable| (sstable.id(), sstable.clone())).collect()
+        });
+        for (level, sstable_id) in state.placements {
+            let sstable = match opened.get(&sstable_id) {
+                Some(sstable) => sstable.clone(),
+                None => Arc::new(SsTable::open_with_keys(
+                    sstable_id,
+                    Some(block_cache.clone()),
+                    FileObject::open(env, &LsmStorage::path_of_sst(path, sstable_id))?,
+                    key_provider,
+                )?),
+            };
+            inner.add_sstable(level, sstable);
+        }
+
+        // Blob files outlive the SsTables they were written with, so all of them are loaded, and
+        // their IDs are taken. The ones with an ID the manifest has not recorded may be left by
+        // an interrupted write, or still being written by the primary of a secondary instance,
+        // and are skipped if they cannot be opened.
+        for file_name in env.list_dir(path)? {
+            let blob_file_id = match file_name.strip_suffix(".blob").map(str::parse::<usize>) {
+                Some(Ok(blob_file_id)) => blob_file_id,
+                _ => continue,
+            };
+            let opened = previous.and_then(|previous| previous.blob_files.get(&blob_file_id));
+            if let Some(blob_file) = opened {
+                inner.blob_files.insert(blob_file_id, blob_file.clone());
+                continue;
+            }
+            let blob_path = LsmStorage::path_of_blob(path, blob_file_id);
+            match BlobFile::open(env, &blob_path, key_provider) {
+                Ok(blob_file) => {
+                    inner.blob_files.insert(blob_file_id, Arc::new(blob_file));
+                    inner.next_sst_id = inner.next_sst_id.max(blob_file_id + 1);
                 }
-            },
+                Err(_) if blob_file_id >= state.next_sst_id => continue,
+                Err(err) => return Err(err),
+            }
         }
+        Ok((inner, state.wal_segment))
     }
 
-    fn delete(&self, key: &[u8]) -> Result<()> {
-        assert!(!key.is_empty(), "key cannot be empty");
-        
-        let session = self.inner.read();
-        session.memtable.set(key, vec![]);
+    /// Gets the WAL and the manifest, failing with `Error::ReadOnly` if the column family was
+    /// opened read-only.
+    fn writable(&self) -> Result<(&Arc<Wal>, &Arc<Manifest>)> {
+        match (&self.wal, &self.manifest) {
+            (Some(wal), Some(manifest)) => Ok((wal, manifest)),
+            _ => Err(Error::ReadOnly),
+        }
+    }
 
-        Ok(())
+    /// The current memtable.
+    fn memtable(&self) -> Arc<MemTable> {
+        self.inner.read().memtable.clone()
     }
 
-    fn scan(&self, range: Range) -> Result<KvScan> {
-        let snapshot = {
-            let session = self.inner.read();
-            Arc::clone(&session)
+    /// The filesystem holding the column family.
+    fn env(&self) -> &dyn Env {
+        self.options.env.as_ref()
+    }
+
+    /// The provider of the encryption keys, if the column family is encrypted.
+    fn key_provider(&self) -> Option<&dyn KeyProvider> {
+        self.options.key_provider.as_deref()
+    }
+
+    /// The filesystem for background writes, going through the rate limiter if any.
+    fn background_env(&self) -> Arc<dyn Env> {
+        match &self.options.rate_limiter {
+            Some(limiter) => Arc::new(RateLimitedEnv::new(self.options.env.clone(), limiter.clone())),
+            None => self.options.env.clone(),
+        }
+    }
+
+    /// Name of the column family.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Ingests standalone SSTable files, e.g. written by `SstFileWriter`. The files are linked (or
+    /// copied) into the storage directory, and each is placed at the lowest level that none of the
+    /// levels above overlap, so that it shadows all older data in its key range. Memtables that
+    /// overlap the files are flushed first. The ingestion is recorded atomically in the manifest.
+    pub fn ingest_external_files<P: AsRef<Path>>(&self, paths: &[P]) -> Result<()> {
+        let (_, manifest) = self.writable()?;
+        let _flush_guard = self.flush_lock.lock();
+
+        // Validate the files before touching the storage.
+        let mut externals = Vec::with_capacity(paths.len());
+        for path in paths {
+            let file = FileObject::open(self.env(), path.as_ref())?;
+            let sstable = SsTable::open_with_keys(0, None, file, self.key_provider())
+                .map_err(|e| Error::Value(format!(
+                    "Invalid external SSTable {}: {}", path.as_ref().display(), e
+                )))?;
+            if sstable.properties().num_entries == 0 {
+                return Err(Error::Value(format!(
+                    "External SSTable {} is empty", path.as_ref().display()
+                )));
+            }
+            externals.push((path.as_ref().to_path_buf(), sstable));
+        }
+        externals.sort_by(|(_, a), (_, b)| a.properties().first_key.cmp(&b.properties().first_key));
+        for pair in externals.windows(2) {
+            if pair[0].1.properties().last_key >= pair[1].1.properties().first_key {
+                return Err(Error::Value(format!(
+                    "External SSTables {} and {} overlap",
+                    pair[0].0.display(), pair[1].0.display()
+                )));
+            }
+        }
+
+        // Ingested data must shadow the memtables, so flush them if they overlap.
+        let overlaps_memtables = {
+            let snapshot = self.inner.read();
+            externals.iter().any(|(_, sstable)| {
+                let range = Range::from(
+                    sstable.properties().first_key.to_vec()..=sstable.properties().last_key.to_vec()
+                );
+                std::iter::once(&snapshot.memtable)
+                    .chain(snapshot.imm_memtables.iter())
+                    .any(|memtable| memtable.scan(range.clone()).next().is_some())
+            })
         };
+        if overlaps_memtables {
+            self.flush_memtable()?;
+        }
 
-        let mut memtable_iters = vec![];
-        memtable_iters.reserve(snapshot.imm_memtables.len() + 1);
-        memtable_iters.push(Box::new(snapshot.memtable.scan(range.clone())));
-        for memtable in snapshot.imm_memtables.iter().rev() {
-            memtable_iters.push(Box::new(memtable.scan(range.clone())));
+        let snapshot = Arc::clone(&self.inner.read());
+        let mut placements = Vec::with_capacity(externals.len());
+        let mut sstables = Vec::with_capacity(externals.len());
+        for (offset, (path, external)) in externals.into_iter().enumerate() {
+            let sstable_id = snapshot.next_sst_id + offset;
+            let level = Self::pick_ingestion_level(&snapshot, &external);
+            let sst_path = LsmStorage::path_of_sst(&self.path, sstable_id);
+            self.env().link_or_copy(&path, &sst_path)?;
+            sstables.push(Arc::new(SsTable::open_with_keys(
+                sstable_id,
+                Some(self.block_cache.clone()),
+                FileObject::open(self.env(), &sst_path)?,
+                self.key_provider(),
+            )?));
+            placements.push((level, sstable_id));
         }
-        let memtable_merge_iter = MergeIter::create(memtable_iters)?;
+        manifest.add_record(&ManifestRecord::Ingest(placements.clone()))?;
 
-        let mut sstable_iters = vec![];
-        sstable_iters.reserve(snapshot.l0_sstables.len());
-        for sstable in snapshot.l0_sstables.iter().rev() {
-            sstable_iters.push(Box::new(SsTableIter::create(sstable.clone(), range.clone())?));
+        // Add the ingested tables to the levels.
+        {
+            let mut session = self.inner.write();
+            let mut snapshot = session.as_ref().clone();
+            for ((level, _), sstable) in placements.into_iter().zip(sstables) {
+                snapshot.add_sstable(level, sstable);
+            }
+            snapshot.next_sst_id += paths.len();
+            *session = Arc::new(snapshot);
         }
-        let sstable_merge_iter = MergeIter::create(sstable_iters)?;
 
-        let two_merge_iter = TwoMergeIter::create(
-            memtable_merge_iter, sstable_merge_iter
-        )?;
+        Ok(())
+    }
 
-        Ok(Box::new(LsmIter::create(two_merge_iter)))
+    /// Hard-links (or copies) the SsTables and blob files of the column family into the empty
+    /// directory `dir`, along with a manifest describing them. Must be called with `flush_lock`
+    /// held, which keeps the set of SsTables unchanged, after flushing the memtable.
+    fn checkpoint_to(&self, dir: &Path) -> Result<()> {
+        let snapshot = Arc::clone(&self.inner.read());
+
+        let mut placements = vec![];
+        for sstable in snapshot.l0_sstables.iter() {
+            placements.push((0, sstable.id()));
+        }
+        for (idx, tables) in snapshot.levels.iter().enumerate() {
+            for sstable in tables.iter() {
+                placements.push((idx + 1, sstable.id()));
+            }
+        }
+        let mut files = vec![];
+        for (_, sstable_id) in placements.iter() {
+            files.push((
+                LsmStorage::path_of_sst(&self.path, *sstable_id), LsmStorage::path_of_sst(dir, *sstable_id)
+            ));
+        }
+        for blob_file_id in snapshot.blob_files.keys() {
+            files.push((
+                LsmStorage::path_of_blob(&self.path, *blob_file_id),
+                LsmStorage::path_of_blob(dir, *blob_file_id),
+            ));
+        }
+        for (src, dst) in files {
+            self.env().link_or_copy(&src, &dst)?;
+        }
+        Manifest::create(self.env(), dir.join("MANIFEST"))?
+            .add_record(&ManifestRecord::Snapshot(placements))
     }
 
-    fn flush(&self) -> Result<()> {
+    /// Reclaims space from dead values in blob files. A blob value is dead once a newer version
+    /// or a tombstone of its key exists, or its entry was dropped by a compaction. Every blob
+    /// file whose dead bytes make up at least `garbage_ratio` of its size is collected, as is every
+    /// blob file encrypted with a key other than the current one. The SsTables pointing into
+    /// collected blob files are rewritten: live values are copied to a new blob file, entries
+    /// pointing to dead values are dropped, and the new SsTable takes the place of the old one.
+    /// The collected blob files are then deleted. Returns the number of blob files collected.
+    pub fn gc_blob_files(&self, garbage_ratio: f64) -> Result<usize> {
+        let (_, manifest) = self.writable()?;
         let _flush_guard = self.flush_lock.lock();
+        let snapshot = Arc::clone(&self.inner.read());
+
+        // Find the live bytes of each blob file, and the SsTables pointing into it. Values
+        // shadowed by newer tables are dead.
+        let sstables = snapshot.sstables().cloned().collect::<Vec<_>>();
+        let mut live_bytes = snapshot.blob_files.keys()
+            .map(|blob_file_id| (*blob_file_id, 0))
+            .collect::<HashMap<_, _>>();
+        let mut referencing = HashMap::<usize, BTreeSet<usize>>::new();
+        for (idx, sstable) in sstables.iter().enumerate() {
+            for entry in SsTableIter::new(sstable.clone())? {
+                let (key, value) = entry?;
+                if value.is_empty() {
+                    continue;
+                }
+                if let StoredValue::Blob(pointer) = StoredValue::decode(&value)? {
+                    referencing.entry(pointer.file_id).or_default().insert(idx);
+                    if !snapshot.is_shadowed(&key, sstables[..idx].iter())? {
+                        *live_bytes.entry(pointer.file_id).or_default() += pointer.len as u64;
+                    }
+                }
+            }
+        }
+        let current_key_id = self.key_provider().map(|key_provider| key_provider.current_key_id());
+        let collected = snapshot.blob_files.iter()
+            .filter(|(blob_file_id, blob_file)| {
+                let size = blob_file.data_size();
+                let dead_bytes = size.saturating_sub(live_bytes[*blob_file_id]);
+                dead_bytes as f64 >= garbage_ratio * size as f64
+                    || blob_file.key_id() != current_key_id
+            })
+            .map(|(blob_file_id, _)| *blob_file_id)
+            .collect::<BTreeSet<_>>();
+        if collected.is_empty() {
+            return Ok(0);
+        }
 
-        let memtable_to_flush;
-        let sstable_id;
+        let env = self.background_env();
+        let mut next_sst_id = snapshot.next_sst_id;
+        let mut rewrites = vec![];
+        let rewritten = collected.iter()
+            .filter_map(|blob_file_id| referencing.get(blob_file_id))
+            .flatten()
+            .copied()
+            .collect::<BTreeSet<_>>();
+        for idx in rewritten {
+            let sstable = &sstables[idx];
+            let new_sstable_id = next_sst_id;
+            next_sst_id += 1;
+            let (mut sstable_builder, mut blob_builder) = self.new_builders(new_sstable_id)?;
+            let mut num_entries = 0;
+            for entry in SsTableIter::new(sstable.clone())? {
+                let (key, value) = entry?;
+                if !value.is_empty() {
+                    if let StoredValue::Blob(pointer) = StoredValue::decode(&value)? {
+                        if collected.contains(&pointer.file_id) {
+                            if snapshot.is_shadowed(&key, sstables[..idx].iter())? {
+                                continue;
+                            }
+                            let blob_file = &snapshot.blob_files[&pointer.file_id];
+                            let pointer = blob_builder.add(&blob_file.read(&pointer)?);
+                            sstable_builder.add(&key, &StoredValue::encode_blob(&pointer));
+                            num_entries += 1;
+                            continue;
+                        }
+                    }
+                }
+                sstable_builder.add(&key, &value);
+                num_entries += 1;
+            }
+            let new_blob_file = match blob_builder.is_empty() {
+                true => None,
+                false => Some(Arc::new(
+                    blob_builder.build(env.as_ref(), LsmStorage::path_of_blob(&self.path, new_sstable_id))?
+                )),
+            };
+            let new_sstable = match num_entries {
+                0 => None,
+                _ => Some(Arc::new(sstable_builder.build(
+                    env.as_ref(),
+                    new_sstable_id,
+                    Some(self.block_cache.clone()),
+                    LsmStorage::path_of_sst(&self.path, new_sstable_id),
+                )?)),
+            };
+            rewrites.push((sstable.id(), new_sstable, new_blob_file));
+        }
 
-        // Move mutable memtable to immutable memtables.
+        // Blob files no SsTable points into anymore need no rewrite, and are just deleted.
+        if !rewrites.is_empty() {
+            manifest.add_record(&ManifestRecord::Rewrite(
+                rewrites.iter()
+                    .map(|(sstable_id, new_sstable, _)| {
+                        (*sstable_id, new_sstable.as_ref().map(|t| t.id()))
+                    })
+                    .collect()
+            ))?;
+        }
         {
             let mut session = self.inner.write();
-
-            // Swap the current memtable with a new one.
             let mut snapshot = session.as_ref().clone();
-            let memtable = std::mem::replace(
-                &mut snapshot.memtable,
-                Arc::new(MemTable::create())
-            );
-            memtable_to_flush = memtable.clone();
-            sstable_id = snapshot.next_sst_id;
+            for blob_file_id in collected.iter() {
+                snapshot.blob_files.remove(blob_file_id);
+            }
+            for (sstable_id, new_sstable, new_blob_file) in rewrites.iter() {
+                if let (Some(new_sstable), Some(new_blob_file)) = (new_sstable, new_blob_file) {
+                    snapshot.blob_files.insert(new_sstable.id(), new_blob_file.clone());
+                }
+                snapshot.replace_sstable(*sstable_id, new_sstable.clone());
+            }
+            snapshot.next_sst_id = next_sst_id;
+            *session = Arc::new(snapshot);
+        }
 
-            // Add the memtable to the immutable memtables.
-            snapshot.imm_memtables.push(memtable);
+        // Readers still holding an older snapshot keep the deleted files open.
+        for (sstable_id, _, _) in rewrites.iter() {
+            self.env().remove_file(&LsmStorage::path_of_sst(&self.path, *sstable_id))?;
+        }
+        for blob_file_id in collected.iter() {
+            self.env().remove_file(&LsmStorage::path_of_blob(&self.path, *blob_file_id))?;
+        }
+        Ok(collected.len())
+    }
 
-            // Update the snapshot.
-            *session = Arc::new(snapshot);
+    /// Picks the lowest level that neither it nor the levels above overlap with the table.
+    fn pick_ingestion_level(snapshot: &LsmStorageInner, sstable: &SsTable) -> usize {
+        let range = Range::from(
+            sstable.properties().first_key.to_vec()..=sstable.properties().last_key.to_vec()
+        );
+        if snapshot.l0_sstables.iter().any(|table| table.overlaps_range(&range)) {
+            return 0;
+        }
+        for (idx, tables) in snapshot.levels.iter().enumerate() {
+            if tables.iter().any(|table| table.overlaps_range(&range)) {
+                return idx;
+            }
         }
+        NUM_LEVELS
+    }
+
+    /// Flushes the current memtable to a new L0 SsTable. Must be called with `flush_lock` held.
+    fn flush_memtable(&self) -> Result<()> {
+        let (wal, _) = self.writable()?;
+        if self.inner.read().memtable.is_empty() {
+            return Ok(());
+        }
+
+        // Move mutable memtable to immutable memtables, starting a new WAL segment for the
+        // writes to the new memtable.
+        let (wal_segment, frozen) = wal.rotate(&[&self.name], || self.freeze_memtable())?;
+        match frozen {
+            Some((memtable, sstable_id)) => {
+                self.flush_frozen_memtable(wal_segment, memtable, sstable_id)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the current memtable to the immutable memtables unless it is empty, returning it
+    /// along with the ID of its SsTable. Must be called with `flush_lock` held, from the `swap`
+    /// of a WAL rotation.
+    fn freeze_memtable(&self) -> Option<(Arc<MemTable>, usize)> {
+        let mut session = self.inner.write();
+        if session.memtable.is_empty() {
+            return None;
+        }
+
+        // Swap the current memtable with a new one.
+        let mut snapshot = session.as_ref().clone();
+        let memtable = std::mem::replace(&mut snapshot.memtable, Arc::new(MemTable::create()));
+        let sstable_id = snapshot.next_sst_id;
+
+        // Add the memtable to the immutable memtables.
+        snapshot.imm_memtables.push(memtable.clone());
+
+        // Update the snapshot.
+        *session = Arc::new(snapshot);
+        Some((memtable, sstable_id))
+    }
+
+    /// Writes a memtable frozen by `freeze_memtable` to a new L0 SsTable, recording that the
+    /// writes before `wal_segment` are flushed. Must be called with `flush_lock` held.
+    fn flush_frozen_memtable(
+        &self,
+        wal_segment: u64,
+        memtable_to_flush: Arc<MemTable>,
+        sstable_id: usize,
+    ) -> Result<()> {
+        let (wal, manifest) = self.writable()?;
 
         // At this point, the old memtable should be disabled for write, and all write threads
         // should be operating on the new memtable. We can safely flush the old memtable to
         // disk.
 
-        let mut sstable_builder = SsTableBuilder::new(4096);
-        memtable_to_flush.flush(&mut sstable_builder)?;
+        let (mut sstable_builder, mut blob_builder) = self.new_builders(sstable_id)?;
+        match self.options.blob_threshold {
+            None => memtable_to_flush.flush(&mut sstable_builder)?,
+            Some(blob_threshold) => {
+                for entry in memtable_to_flush.scan(Range::from(..)) {
+                    let (key, value) = entry?;
+                    match value.is_empty() {
+                        true => sstable_builder.add(&key, &value),
+                        false => match StoredValue::decode(&value)? {
+                            StoredValue::Inline(value) if value.len() >= blob_threshold => {
+                                let pointer = blob_builder.add(value);
+                                sstable_builder.add(&key, &StoredValue::encode_blob(&pointer));
+                            },
+                            _ => sstable_builder.add(&key, &value),
+                        },
+                    }
+                }
+            }
+        }
+        let env = self.background_env();
+        let blob_file = match blob_builder.is_empty() {
+            true => None,
+            false => Some(Arc::new(
+                blob_builder.build(env.as_ref(), LsmStorage::path_of_blob(&self.path, sstable_id))?
+            )),
+        };
         let sstable = Arc::new(sstable_builder.build(
-            sstable_id, 
+            env.as_ref(),
+            sstable_id,
             Some(self.block_cache.clone()), 
-            self.path.join(format!("{:05}.sst", sstable_id)),
+            LsmStorage::path_of_sst(&self.path, sstable_id),
         )?);
+        manifest.add_record(&ManifestRecord::Flush(sstable_id, wal_segment))?;
 
         // Add the flushed L0 table to the list.
         {
@@ -201,18 +726,827 @@ impl KvStore for LsmStorage {
             snapshot.imm_memtables.pop();
             // Add L0 table
             snapshot.l0_sstables.push(sstable);
+            if let Some(blob_file) = blob_file {
+                snapshot.blob_files.insert(sstable_id, blob_file);
+            }
             // Update SST ID
             snapshot.next_sst_id += 1;
             // Update the snapshot.
             *session = Arc::new(snapshot);
         }
+        if let Some(limiter) = &self.options.rate_limiter {
+            limiter.tune(self.compaction_debt());
+        }
+
+        wal.flushed(&self.name)
+    }
+
+    /// Compacts all SsTables overlapping `range` into the bottommost level, dropping tombstones,
+    /// after flushing the memtable. The range is widened until no SsTable partly overlaps it, so
+    /// that the output does not overlap the SsTables left in the bottommost level. Blocks until
+    /// done, holding `flush_lock`, so no flush, ingestion or garbage collection runs meanwhile.
+    pub fn compact_range(&self, range: Range) -> Result<()> {
+        self.writable()?;
+        let _flush_guard = self.flush_lock.lock();
+        self.flush_memtable()?;
+        let snapshot = Arc::clone(&self.inner.read());
+
+        let mut inputs = snapshot.sstables()
+            .filter(|sstable| sstable.overlaps_range(&range))
+            .cloned()
+            .collect::<Vec<_>>();
+        loop {
+            let first_key = inputs.iter()
+                .map(|sstable| sstable.properties().first_key.to_vec())
+                .min();
+            let last_key = inputs.iter()
+                .map(|sstable| sstable.properties().last_key.to_vec())
+                .max();
+            let span = match (first_key, last_key) {
+                (Some(first_key), Some(last_key)) => Range::from(first_key..=last_key),
+                _ => return Ok(()),
+            };
+            let widened = snapshot.sstables()
+                .filter(|sstable| sstable.overlaps_range(&span))
+                .cloned()
+                .collect::<Vec<_>>();
+            if widened.len() == inputs.len() {
+                break;
+            }
+            inputs = widened;
+        }
+        self.compact(&snapshot, inputs, NUM_LEVELS)
+    }
+
+    /// Compacts a key range like `compact_range`, on a blocking thread of the Tokio runtime.
+    pub async fn compact_range_async(&self, range: Range) -> Result<()> {
+        let family = self.clone();
+        tokio::task::spawn_blocking(move || family.compact_range(range)).await?
+    }
+
+    /// Compacts all L0 SsTables into L1 once there are `l0_compaction_trigger` of them. Must be
+    /// called with `flush_lock` held.
+    fn maybe_compact_l0(&self) -> Result<()> {
+        let snapshot = Arc::clone(&self.inner.read());
+        match self.options.l0_compaction_trigger {
+            Some(trigger) if snapshot.l0_sstables.len() >= trigger.max(1) => {}
+            _ => return Ok(()),
+        }
+        let first_key = snapshot.l0_sstables.iter()
+            .map(|sstable| sstable.properties().first_key.to_vec())
+            .min()
+            .expect("L0 is not empty");
+        let last_key = snapshot.l0_sstables.iter()
+            .map(|sstable| sstable.properties().last_key.to_vec())
+            .max()
+            .expect("L0 is not empty");
+        let range = Range::from(first_key..=last_key);
+        let mut inputs = snapshot.l0_sstables.iter().rev().cloned().collect::<Vec<_>>();
+        inputs.extend(snapshot.levels[0].iter().filter(|sstable| sstable.overlaps_range(&range)).cloned());
+        self.compact(&snapshot, inputs, 1)
+    }
+
+    /// Merges SsTables into new SsTables at `output_level`, with parallel subcompactions over
+    /// disjoint key ranges, and commits the result atomically with a single manifest record.
+    /// Inputs are ordered from the newest data to the oldest. Tombstones are dropped from the key
+    /// ranges that no level below the output overlaps. Blob pointers are carried over unchanged,
+    /// leaving the values in their blob files for `gc_blob_files` to reclaim. Must be called with
+    /// `flush_lock` held.
+    fn compact(
+        &self,
+        snapshot: &LsmStorageInner,
+        inputs: Vec<Arc<SsTable>>,
+        output_level: usize,
+    ) -> Result<()> {
+        let (_, manifest) = self.writable()?;
+        let next_sst_id = AtomicUsize::new(snapshot.next_sst_id);
+        let outputs = std::thread::scope(|scope| {
+            let handles = self.subcompaction_ranges(&inputs)
+                .into_iter()
+                .map(|range| {
+                    let (inputs, next_sst_id) = (&inputs, &next_sst_id);
+                    scope.spawn(move || {
+                        self.run_subcompaction(snapshot, inputs, output_level, range, next_sst_id)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter()
+                .map(|handle| handle.join().expect("subcompaction panicked"))
+                .collect::<Result<Vec<_>>>()
+        })?.into_iter().flatten().collect::<Vec<_>>();
+
+        manifest.add_record(&ManifestRecord::Compact(
+            inputs.iter().map(|sstable| sstable.id()).collect(),
+            outputs.iter().map(|sstable| (output_level, sstable.id())).collect(),
+        ))?;
+        {
+            let mut session = self.inner.write();
+            let mut snapshot = session.as_ref().clone();
+            for sstable in inputs.iter() {
+                snapshot.replace_sstable(sstable.id(), None);
+            }
+            for sstable in outputs {
+                snapshot.add_sstable(output_level, sstable);
+            }
+            snapshot.next_sst_id = next_sst_id.into_inner();
+            *session = Arc::new(snapshot);
+        }
+
+        // Readers still holding an older snapshot keep the deleted files open.
+        for sstable in inputs.iter() {
+            self.env().remove_file(&LsmStorage::path_of_sst(&self.path, sstable.id()))?;
+        }
+        Ok(())
+    }
+
+    /// Splits the key space of a compaction at first keys of its inputs, into one range per
+    /// `target_file_size` bytes of input, up to `max_subcompactions` ranges.
+    fn subcompaction_ranges(&self, inputs: &[Arc<SsTable>]) -> Vec<Range> {
+        let input_size = inputs.iter().map(|sstable| sstable.table_size()).sum::<u64>();
+        let mut first_keys = inputs.iter()
+            .map(|sstable| sstable.properties().first_key.to_vec())
+            .collect::<Vec<_>>();
+        first_keys.sort();
+        first_keys.dedup();
+        // Splitting at the smallest key would leave the first range empty.
+        let candidates = first_keys.get(1..).unwrap_or_default();
+        let count = self.options.max_subcompactions
+            .min(input_size.div_ceil(self.options.target_file_size.max(1) as u64) as usize)
+            .min(candidates.len() + 1)
+            .max(1);
+
+        let mut start = Bound::Unbounded;
+        let mut ranges = Vec::with_capacity(count);
+        for idx in 1..count {
+            let boundary = candidates[idx * candidates.len() / count].clone();
+            ranges.push(Range::from((start, Bound::Excluded(boundary.clone()))));
+            start = Bound::Included(boundary);
+        }
+        ranges.push(Range::from((start, Bound::Unbounded)));
+        ranges
+    }
+
+    /// Merges the inputs of a compaction within a key range, writing SsTables of about
+    /// `target_file_size` bytes.
+    fn run_subcompaction(
+        &self,
+        snapshot: &LsmStorageInner,
+        inputs: &[Arc<SsTable>],
+        output_level: usize,
+        range: Range,
+        next_sst_id: &AtomicUsize,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let mut iters = vec![];
+        for sstable in inputs.iter().filter(|sstable| sstable.overlaps_range(&range)) {
+            iters.push(Box::new(SsTableIter::create(sstable.clone(), range.clone())?));
+        }
+        let bottommost = snapshot.levels[output_level..]
+            .iter()
+            .flatten()
+            .all(|sstable| !sstable.overlaps_range(&range));
+
+        let env = self.background_env();
+        let mut outputs = vec![];
+        let mut output: Option<(usize, SsTableBuilder)> = None;
+        for entry in MergeIter::create(iters)? {
+            let (key, value) = entry?;
+            if value.is_empty() && bottommost {
+                continue;
+            }
+            if output.is_none() {
+                let sstable_id = next_sst_id.fetch_add(1, Ordering::SeqCst);
+                let (sstable_builder, _) = self.new_builders(sstable_id)?;
+                output = Some((sstable_id, sstable_builder));
+            }
+            let (_, sstable_builder) = output.as_mut().expect("output is being built");
+            sstable_builder.add(&key, &value);
+            if sstable_builder.estimated_size() >= self.options.target_file_size {
+                let output = output.take().expect("output is being built");
+                outputs.push(self.build_compaction_output(env.as_ref(), output)?);
+            }
+        }
+        if let Some(output) = output {
+            outputs.push(self.build_compaction_output(env.as_ref(), output)?);
+        }
+        Ok(outputs)
+    }
+
+    fn build_compaction_output(
+        &self,
+        env: &dyn Env,
+        (sstable_id, sstable_builder): (usize, SsTableBuilder),
+    ) -> Result<Arc<SsTable>> {
+        Ok(Arc::new(sstable_builder.build(
+            env,
+            sstable_id,
+            Some(self.block_cache.clone()),
+            LsmStorage::path_of_sst(&self.path, sstable_id),
+        )?))
+    }
+
+    /// Bytes waiting to be compacted: the total size of the L0 SsTables.
+    fn compaction_debt(&self) -> u64 {
+        self.inner.read().l0_sstables.iter().map(|sstable| sstable.table_size()).sum()
+    }
+
+    /// Creates the builders of a new SsTable and its blob file, encrypted with the current key
+    /// if encryption is enabled.
+    fn new_builders(&self, sstable_id: usize) -> Result<(SsTableBuilder, BlobFileBuilder)> {
+        let mut builder = SsTableBuilder::new(self.options.block_size);
+        if let Some(prefix_extractor) = &self.options.prefix_extractor {
+            builder = builder.with_prefix_extractor(prefix_extractor.clone());
+        }
+        let cipher = self.key_provider().map(BlockCipher::new).transpose()?;
+        if let Some(cipher) = &cipher {
+            builder = builder.with_cipher(cipher.clone());
+        }
+        Ok((builder, BlobFileBuilder::new(sstable_id).with_cipher(cipher)))
+    }
+
+    /// Scans a key range, skipping the SsTables for which `may_contain` is false.
+    fn scan_sstables(
+        &self,
+        range: Range,
+        may_contain: impl Fn(&SsTable) -> bool,
+    ) -> Result<KvScan> {
+        let snapshot = {
+            let session = self.inner.read();
+            Arc::clone(&session)
+        };
+
+        let mut memtable_iters = vec![];
+        memtable_iters.reserve(snapshot.imm_memtables.len() + 1);
+        memtable_iters.push(Box::new(snapshot.memtable.scan(range.clone())));
+        for memtable in snapshot.imm_memtables.iter().rev() {
+            memtable_iters.push(Box::new(memtable.scan(range.clone())));
+        }
+        let memtable_merge_iter = MergeIter::create(memtable_iters)?;
+
+        let mut sstable_iters = vec![];
+        for sstable in snapshot.sstables() {
+            if !sstable.overlaps_range(&range) || !may_contain(sstable) {
+                continue;
+            }
+            sstable_iters.push(Box::new(SsTableIter::create(sstable.clone(), range.clone())?));
+        }
+        let sstable_merge_iter = MergeIter::create(sstable_iters)?;
+
+        let two_merge_iter = TwoMergeIter::create(
+            memtable_merge_iter, sstable_merge_iter
+        )?;
+
+        Ok(Box::new(LsmIter::create(two_merge_iter).map(move |entry| {
+            entry.and_then(|(key, value)| Ok((key, snapshot.resolve_value(&value)?)))
+        })))
+    }
+
+    /// Samples the data in a range to estimate it, as the first key, size and number of entries
+    /// of chunks of data: the live entries of the memtables, and the data blocks of the SsTables.
+    /// Blob files are spread over the blocks in proportion to their size, as blob values cannot
+    /// be located without reading the blocks. Blocks partly in the range count in full, and keys
+    /// overwritten or deleted in newer SsTables are still counted.
+    fn range_samples(&self, range: &Range) -> Vec<(Vec<u8>, u64, f64)> {
+        let snapshot = Arc::clone(&self.inner.read());
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let mut samples = vec![];
+        for memtable in std::iter::once(&snapshot.memtable).chain(&snapshot.imm_memtables) {
+            samples.extend(memtable.live_entries(range).into_iter()
+                .map(|(key, size)| (key, size, 1.0)));
+        }
+        let blob_size = snapshot.blob_files.values().map(|blob| blob.data_size()).sum::<u64>();
+        let data_size = snapshot.sstables().map(|sstable| sstable.data_size()).sum::<u64>();
+        let blob_ratio = blob_size as f64 / data_size.max(1) as f64;
+        for sstable in snapshot.sstables() {
+            let properties = sstable.properties();
+            let live_entries = properties.num_entries - properties.num_tombstones;
+            for (first_key, block_size) in sstable.blocks_in_range(range) {
+                let share = block_size as f64 / sstable.data_size().max(1) as f64;
+                // The first block may start before the range.
+                let key = match start {
+                    Some(start) if first_key < start[..] => start.clone(),
+                    _ => first_key.to_vec(),
+                };
+                let size = block_size + (block_size as f64 * blob_ratio) as u64;
+                samples.push((key, size, live_entries as f64 * share));
+            }
+        }
+        samples
+    }
+
+    /// Logs a write to the WAL and applies it to the memtable.
+    fn write(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let (wal, _) = self.writable()?;
+        let entry = WalEntry { family: self.name.clone(), key: key.to_vec(), value };
+        wal.log(vec![entry], |entries| {
+            let session = self.inner.read();
+            for entry in entries {
+                session.memtable.set(&entry.key, entry.value);
+            }
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn l0_sstables_for_test(&self) -> Vec<Arc<SsTable>> {
+        self.inner.read().l0_sstables.clone()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn levels_for_test(&self) -> Vec<Vec<Arc<SsTable>>> {
+        self.inner.read().levels.clone()
+    }
+}
+
+impl KvStore for ColumnFamily {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        assert!(!key.is_empty(), "key cannot be empty");
+        assert!(!value.is_empty(), "value cannot be empty");
+
+        self.write(key, StoredValue::encode_inline(&value))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let snapshot = {
+            let session = self.inner.read();
+            Arc::clone(&session)
+        };
+
+        match snapshot.get_stored(key)? {
+            Some(value) if !value.is_empty() => Ok(Some(snapshot.resolve_value(&value)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        assert!(!key.is_empty(), "key cannot be empty");
+
+        self.write(key, vec![])
+    }
+
+    fn scan(&self, range: Range) -> Result<KvScan> {
+        self.scan_sstables(range, |_| true)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvScan> {
+        let range = Range::prefix(prefix);
+        match &self.options.prefix_extractor {
+            Some(extractor) => match extractor.prefix(prefix) {
+                Some(extracted) => self.scan_sstables(range, |sstable| {
+                    sstable.may_contain_prefix(extractor.as_ref(), extracted)
+                }),
+                None => self.scan(range),
+            },
+            None => self.scan(range),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        let _flush_guard = self.flush_lock.lock();
+        self.flush_memtable()?;
+        self.maybe_compact_l0()
+    }
+
+    /// Estimates the size of a range from the SsTable block metas and the memtables, without
+    /// reading any block. See `range_samples`.
+    fn approximate_size(&self, range: Range) -> Result<u64> {
+        Ok(self.range_samples(&range).iter().map(|(_, size, _)| size).sum())
+    }
+
+    /// Estimates the number of keys in a range from the SsTable properties and block metas and
+    /// the memtables, without reading any block. See `range_samples`.
+    fn approximate_count(&self, range: Range) -> Result<u64> {
+        let count = self.range_samples(&range).iter().map(|(_, _, count)| count).sum::<f64>();
+        Ok(count.round() as u64)
+    }
+
+    /// Splits a range at keys of the memtables and first keys of the SsTable blocks. See
+    /// `range_samples`.
+    fn split_points(&self, range: Range, n: usize) -> Result<Vec<Vec<u8>>> {
+        let samples = self.range_samples(&range).into_iter()
+            .map(|(key, size, _)| (key, size))
+            .collect();
+        Ok(split_by_size(samples, n))
+    }
+}
+
+impl Display for ColumnFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LsmStorage({})", self.name)
+    }
+}
+
+/// Writes to one or more column families, applied atomically by `LsmStorage::write`.
+#[derive(Default)]
+pub struct WriteBatch {
+    entries: Vec<WalEntry>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a value for a key of a column family.
+    pub fn set(&mut self, family: &str, key: &[u8], value: Vec<u8>) {
+        assert!(!key.is_empty(), "key cannot be empty");
+        assert!(!value.is_empty(), "value cannot be empty");
+
+        self.entries.push(WalEntry {
+            family: family.to_string(),
+            key: key.to_vec(),
+            value: StoredValue::encode_inline(&value),
+        });
+    }
+
+    /// Deletes a key of a column family.
+    pub fn delete(&mut self, family: &str, key: &[u8]) {
+        assert!(!key.is_empty(), "key cannot be empty");
+
+        self.entries.push(WalEntry { family: family.to_string(), key: key.to_vec(), value: vec![] });
+    }
+}
+
+/// The storage interface of the LSM tree. A storage holds one or more column families, and serves
+/// the `KvStore` interface of the default one.
+///
+/// Directory layout:
+///
+/// ```text
+///     <path>/MANIFEST, <path>/<sstable_id>.sst, ...   the default column family
+///     <path>/<segment_id>.wal                         the WAL shared by all column families
+///     <path>/column_families/<name>/MANIFEST, ...     other column families
+/// ```
+pub struct LsmStorage {
+    path: PathBuf,
+    mode: OpenMode,
+    default: ColumnFamily,
+    column_families: HashMap<String, ColumnFamily>,
+    /// The WAL, or None unless the storage was opened as the primary.
+    wal: Option<Arc<Wal>>,
+}
+
+/// How an `LsmStorage` is opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OpenMode {
+    /// The single writer of the storage directory.
+    Primary,
+    /// Read-only, with the data as of opening.
+    ReadOnly,
+    /// Read-only, following a live primary with `catch_up`.
+    Secondary,
+}
+
+impl LsmStorage {
+    /// Opens the LSM tree in the given directory with the default options.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(path, LsmStorageOptions::default())
+    }
+
+    /// Opens the LSM tree in the given directory with only the default column family.
+    pub fn open_with_options(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
+        Self::open_with_column_families(path, options, &[])
+    }
+
+    /// Opens the LSM tree in the given directory with the default column family and the given
+    /// ones, each with its own options. The SsTables recorded in the manifests are restored, and
+    /// writes not yet flushed to SsTables are replayed from the WAL. Every column family with
+    /// writes in the WAL must be opened.
+    pub fn open_with_column_families(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        column_families: &[(&str, LsmStorageOptions)],
+    ) -> Result<Self> {
+        Self::open_with_mode(path, options, column_families, OpenMode::Primary)
+    }
+
+    /// Opens the LSM tree in the given directory read-only with the default options, without
+    /// writing anything to it. Mutations fail with `Error::ReadOnly`.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_read_only_with_column_families(path, LsmStorageOptions::default(), &[])
+    }
+
+    /// Opens the LSM tree in the given directory read-only, with the given column families. Other
+    /// column families are ignored.
+    pub fn open_read_only_with_column_families(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        column_families: &[(&str, LsmStorageOptions)],
+    ) -> Result<Self> {
+        Self::open_with_mode(path, options, column_families, OpenMode::ReadOnly)
+    }
+
+    /// Opens the LSM tree in the given directory as a secondary instance of a live primary. Like
+    /// a read-only instance, it never writes to the directory, and it can follow the writes of
+    /// the primary with `catch_up`.
+    pub fn open_as_secondary(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        column_families: &[(&str, LsmStorageOptions)],
+    ) -> Result<Self> {
+        Self::open_with_mode(path, options, column_families, OpenMode::Secondary)
+    }
+
+    fn open_with_mode(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        column_families: &[(&str, LsmStorageOptions)],
+        mode: OpenMode,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let env = options.env.clone();
+        let key_provider = options.key_provider.clone();
+        let (wal, batches) = match mode {
+            OpenMode::Primary => {
+                env.create_dir_all(&path)?;
+                let (wal, batches) = Wal::open(
+                    env.clone(), &path, options.sync_writes, options.key_provider.clone()
+                )?;
+                (Some(Arc::new(wal)), batches)
+            }
+            OpenMode::ReadOnly | OpenMode::Secondary => {
+                if !env.exists(&path) {
+                    return Err(Error::Value(format!("Storage {} not found", path.display())));
+                }
+                (None, Wal::read(env.as_ref(), &path, options.key_provider.as_deref())?)
+            }
+        };
+
+        let mut wal_segments = HashMap::new();
+        let (default, wal_segment) =
+            ColumnFamily::open(DEFAULT_COLUMN_FAMILY, path.clone(), options, wal.clone())?;
+        wal_segments.insert(DEFAULT_COLUMN_FAMILY.to_string(), (default.memtable(), wal_segment));
+        let mut families = HashMap::new();
+        for (name, options) in column_families {
+            if name.is_empty()
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(Error::Value(format!("Invalid column family name {:?}", name)));
+            }
+            if *name == DEFAULT_COLUMN_FAMILY || families.contains_key(*name) {
+                return Err(Error::Value(format!("Duplicate column family {}", name)));
+            }
+            let options = LsmStorageOptions {
+                env: env.clone(),
+                key_provider: key_provider.clone(),
+                ..options.clone()
+            };
+            let (family, wal_segment) = ColumnFamily::open(
+                name, Self::path_of_column_family(&path, name), options, wal.clone()
+            )?;
+            wal_segments.insert(name.to_string(), (family.memtable(), wal_segment));
+            families.insert(name.to_string(), family);
+        }
+        let storage = Self { path, mode, default, column_families: families, wal };
+        storage.replay_wal(batches, &wal_segments)?;
+        if let Some(wal) = &storage.wal {
+            wal.skip_to(wal_segments.values().map(|(_, segment)| *segment).max().unwrap_or(0))?;
+        }
+
+        Ok(storage)
+    }
+
+    /// Replays the writes each column family has not flushed yet into its memtable. Memtables
+    /// are given by column family, along with the first WAL segment to replay.
+    fn replay_wal(
+        &self,
+        batches: WalBatches,
+        memtables: &HashMap<String, (Arc<MemTable>, u64)>,
+    ) -> Result<()> {
+        for (segment_id, batch) in batches {
+            for entry in batch {
+                let (memtable, first_segment_id) = match memtables.get(&entry.family) {
+                    Some(memtable) => memtable,
+                    None if self.mode == OpenMode::Primary => return Err(Error::Value(format!(
+                        "Column family {} has writes in the WAL but is not opened", entry.family
+                    ))),
+                    None => continue,
+                };
+                if segment_id >= *first_segment_id {
+                    if let Some(wal) = &self.wal {
+                        wal.note_unflushed(&entry.family, segment_id);
+                    }
+                    memtable.set(&entry.key, entry.value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Catches up with the primary writing to the storage directory, by re-reading the manifests
+    /// and the WAL. Only secondary instances can catch up. Fails if the primary deletes files in
+    /// the meantime, in which case it can be retried.
+    pub fn catch_up(&self) -> Result<()> {
+        if self.mode != OpenMode::Secondary {
+            return Err(Error::Value("Only secondary instances can catch up".into()));
+        }
 
+        // Read the WAL before the manifests: the segments the primary deletes in between only
+        // hold writes that the manifests cover by then.
+        let env = self.env().as_ref();
+        let batches = Wal::read(env, &self.path, self.key_provider().map(Arc::as_ref))?;
+        let mut states = HashMap::new();
+        for family in self.families() {
+            let manifest_path = family.path.join("MANIFEST");
+            let records = match env.exists(&manifest_path) {
+                true => Manifest::read_records(env, &manifest_path)?,
+                false => vec![],
+            };
+            let previous = Arc::clone(&family.inner.read());
+            states.insert(family.name.clone(), ColumnFamily::load_sstables(
+                &family.options,
+                &family.path, &family.block_cache, records, Some(&previous)
+            )?);
+        }
+        let memtables = states.iter()
+            .map(|(name, (inner, wal_segment))| (name.clone(), (inner.memtable.clone(), *wal_segment)))
+            .collect();
+        self.replay_wal(batches, &memtables)?;
+        for family in self.families() {
+            if let Some((inner, _)) = states.remove(&family.name) {
+                *family.inner.write() = Arc::new(inner);
+            }
+        }
         Ok(())
     }
+
+    pub(super) fn path_of_sst(path: &Path, id: usize) -> PathBuf {
+        path.join(format!("{:05}.sst", id))
+    }
+
+    pub(super) fn path_of_blob(path: &Path, id: usize) -> PathBuf {
+        path.join(format!("{:05}.blob", id))
+    }
+
+    pub(super) fn path_of_column_family(path: &Path, name: &str) -> PathBuf {
+        Self::path_of_column_families(path).join(name)
+    }
+
+    /// The directory holding the directories of the column families other than the default one.
+    pub(super) fn path_of_column_families(path: &Path) -> PathBuf {
+        path.join("column_families")
+    }
+
+    /// The filesystem holding the storage.
+    pub fn env(&self) -> &Arc<dyn Env> {
+        &self.default.options.env
+    }
+
+    /// The provider of the encryption keys, if the storage is encrypted.
+    pub fn key_provider(&self) -> Option<&Arc<dyn KeyProvider>> {
+        self.default.options.key_provider.as_ref()
+    }
+
+    /// All column families, the default one first.
+    fn families(&self) -> impl Iterator<Item = &ColumnFamily> {
+        std::iter::once(&self.default).chain(self.column_families.values())
+    }
+
+    fn family(&self, name: &str) -> Option<&ColumnFamily> {
+        match name {
+            DEFAULT_COLUMN_FAMILY => Some(&self.default),
+            _ => self.column_families.get(name),
+        }
+    }
+
+    /// Gets a `KvStore` handle to a column family.
+    pub fn column_family(&self, name: &str) -> Result<ColumnFamily> {
+        self.family(name)
+            .cloned()
+            .ok_or_else(|| Error::Value(format!("Column family {} not found", name)))
+    }
+
+    /// Applies a batch of writes to one or more column families. The batch is logged to the WAL
+    /// as a single record, so after a crash either all or none of its writes are recovered.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        let wal = self.wal.as_ref().ok_or(Error::ReadOnly)?;
+        for entry in batch.entries.iter() {
+            self.column_family(&entry.family)?;
+        }
+        wal.log(batch.entries, |entries| {
+            for entry in entries {
+                let family = self.family(&entry.family).expect("column family checked above");
+                family.inner.read().memtable.set(&entry.key, entry.value);
+            }
+        })
+    }
+
+    /// Ingests standalone SSTable files into the default column family. See
+    /// `ColumnFamily::ingest_external_files`.
+    pub fn ingest_external_files<P: AsRef<Path>>(&self, paths: &[P]) -> Result<()> {
+        self.default.ingest_external_files(paths)
+    }
+
+    /// Reclaims space from dead values in the blob files of the default column family. See
+    /// `ColumnFamily::gc_blob_files`.
+    pub fn gc_blob_files(&self, garbage_ratio: f64) -> Result<usize> {
+        self.default.gc_blob_files(garbage_ratio)
+    }
+
+    /// Compacts a key range of the default column family into the bottommost level. See
+    /// `ColumnFamily::compact_range`.
+    pub fn compact_range(&self, range: Range) -> Result<()> {
+        self.default.compact_range(range)
+    }
+
+    /// Compacts a key range of the default column family into the bottommost level, without
+    /// blocking the async runtime. See `ColumnFamily::compact_range`.
+    pub async fn compact_range_async(&self, range: Range) -> Result<()> {
+        self.default.compact_range_async(range).await
+    }
+
+    /// Creates an openable copy of the storage in `dir`, which must not exist or be empty. The
+    /// memtables of all column families are flushed, then their SsTables hard-linked (or copied)
+    /// into `dir` along with manifests describing them, so the copy needs no WAL. Writers are only
+    /// blocked while the memtables are swapped, so the copy holds either all or none of the writes
+    /// of each `WriteBatch`.
+    pub fn checkpoint(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let wal = self.wal.as_ref().ok_or(Error::ReadOnly)?;
+        let dir = dir.as_ref();
+        let env = self.env();
+        if env.exists(dir) && !env.list_dir(dir)?.is_empty() {
+            return Err(Error::Value(format!("Checkpoint directory {} is not empty", dir.display())));
+        }
+        env.create_dir_all(dir)?;
+
+        // Swapping every memtable under a single WAL rotation cuts all column families at the
+        // same write. The flush locks keep their SsTables unchanged until they are linked.
+        let families = self.families().collect::<Vec<_>>();
+        let _flush_guards = families.iter()
+            .map(|family| family.flush_lock.lock())
+            .collect::<Vec<_>>();
+        let names = families.iter().map(|family| family.name.as_str()).collect::<Vec<_>>();
+        let (wal_segment, frozen) = wal.rotate(&names, || {
+            families.iter().map(|family| family.freeze_memtable()).collect::<Vec<_>>()
+        })?;
+        for (family, frozen) in families.iter().zip(frozen) {
+            if let Some((memtable, sstable_id)) = frozen {
+                family.flush_frozen_memtable(wal_segment, memtable, sstable_id)?;
+            }
+        }
+        for family in families {
+            let family_dir = match family.name.as_str() {
+                DEFAULT_COLUMN_FAMILY => dir.to_path_buf(),
+                name => Self::path_of_column_family(dir, name),
+            };
+            env.create_dir_all(&family_dir)?;
+            family.checkpoint_to(&family_dir)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn l0_sstables_for_test(&self) -> Vec<Arc<SsTable>> {
+        self.default.l0_sstables_for_test()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn levels_for_test(&self) -> Vec<Vec<Arc<SsTable>>> {
+        self.default.levels_for_test()
+    }
+}
+
+impl KvStore for LsmStorage {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.default.set(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.default.get(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        self.default.delete(key)
+    }
+
+    fn scan(&self, range: Range) -> Result<KvScan> {
+        self.default.scan(range)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvScan> {
+        self.default.scan_prefix(prefix)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.default.flush()
+    }
+
+    fn approximate_size(&self, range: Range) -> Result<u64> {
+        self.default.approximate_size(range)
+    }
+
+    fn approximate_count(&self, range: Range) -> Result<u64> {
+        self.default.approximate_count(range)
+    }
+
+    fn split_points(&self, range: Range, n: usize) -> Result<Vec<Vec<u8>>> {
+        self.default.split_points(range, n)
+    }
 }
 
 impl Display for LsmStorage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "LsmStorage")
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,204 @@
+use std::path::Path;
+
+use bytes::{Buf, BufMut};
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+use super::env::{Env, WritableFile};
+
+/// A change to the set of SSTables of an LSM tree.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ManifestRecord {
+    /// A memtable flushed to the L0 SSTable with the given ID, along with the first WAL segment
+    /// holding writes that are not in SSTables.
+    Flush(usize, u64),
+    /// External SSTables ingested at once, as (level, SSTable ID) pairs.
+    Ingest(Vec<(usize, usize)>),
+    /// The complete set of SSTables as (level, SSTable ID) pairs, with L0 tables from earliest to
+    /// latest. Replaces everything recorded before it.
+    Snapshot(Vec<(usize, usize)>),
+    /// SSTables rewritten in place, as (old SSTable ID, new SSTable ID) pairs. A missing new ID
+    /// means the old SSTable was dropped.
+    Rewrite(Vec<(usize, Option<usize>)>),
+    /// SSTables compacted at once: the IDs of the input SSTables, replaced by the outputs as
+    /// (level, SSTable ID) pairs.
+    Compact(Vec<usize>, Vec<(usize, usize)>),
+}
+
+/// The SSTable layout described by a sequence of manifest records.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestState {
+    /// The SSTables as (level, SSTable ID) pairs, with L0 tables from earliest to latest.
+    pub placements: Vec<(usize, usize)>,
+    /// The first WAL segment holding writes that are not in the SSTables.
+    pub wal_segment: u64,
+    /// The next SSTable ID, above every ID recorded, including the ones of dropped SSTables.
+    pub next_sst_id: usize,
+}
+
+impl ManifestState {
+    /// Replays manifest records, from the first to the last. Fails if a record refers to an
+    /// SSTable that is not in the layout.
+    pub fn replay(records: Vec<ManifestRecord>) -> Result<Self> {
+        let mut state = Self { placements: vec![], wal_segment: 0, next_sst_id: 1 };
+        for record in records {
+            match record {
+                ManifestRecord::Flush(sstable_id, flushed_wal_segment) => {
+                    state.placements.push((0, sstable_id));
+                    state.wal_segment = flushed_wal_segment;
+                }
+                ManifestRecord::Ingest(ingested) => state.placements.extend(ingested),
+                ManifestRecord::Snapshot(snapshot) => state.placements = snapshot,
+                ManifestRecord::Compact(compacted, outputs) => {
+                    for sstable_id in compacted {
+                        state.next_sst_id = state.next_sst_id.max(sstable_id + 1);
+                        let idx = state.placements.iter()
+                            .position(|(_, id)| *id == sstable_id)
+                            .ok_or_else(|| Error::Internal(format!(
+                                "Compacted SsTable {} not found in manifest", sstable_id
+                            )))?;
+                        state.placements.remove(idx);
+                    }
+                    state.placements.extend(outputs);
+                }
+                ManifestRecord::Rewrite(rewrites) => {
+                    for (sstable_id, new_sstable_id) in rewrites {
+                        state.next_sst_id = state.next_sst_id.max(sstable_id + 1);
+                        let idx = state.placements.iter().position(|(_, id)| *id == sstable_id);
+                        match (idx, new_sstable_id) {
+                            (Some(idx), Some(new_sstable_id)) => {
+                                state.placements[idx].1 = new_sstable_id;
+                            }
+                            (Some(idx), None) => { state.placements.remove(idx); },
+                            (None, _) => return Err(Error::Internal(format!(
+                                "Rewritten SsTable {} not found in manifest", sstable_id
+                            ))),
+                        }
+                    }
+                }
+            }
+            for (_, sstable_id) in state.placements.iter() {
+                state.next_sst_id = state.next_sst_id.max(sstable_id + 1);
+            }
+        }
+        Ok(state)
+    }
+}
+
+/// An append-only log of `ManifestRecord`s, replayed on open to restore the SSTable layout.
+pub struct Manifest {
+    file: Mutex<Box<dyn WritableFile>>,
+}
+
+/// Data alignment:
+///
+/// ```text
+///     |                  record_1                  |
+///     | record_len (4B) | record (bincode-encoded) | ... |
+/// ```
+impl Manifest {
+    /// Create a new, empty manifest at the given path.
+    pub fn create(env: &dyn Env, path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = env.create_writable(path.as_ref())?;
+        file.sync()?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    /// Open an existing manifest and read all its records. A torn record at the end of the file,
+    /// left by a crash in the middle of `add_record`, is dropped before appending new records.
+    pub fn recover(env: &dyn Env, path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
+        let path = path.as_ref();
+        let data = env.read_file(path)?;
+        let (records, valid_len) = Self::decode_records(&data)?;
+        if valid_len < data.len() {
+            // The records are rewritten aside, so that a crash leaves either manifest in place.
+            let tmp_path = path.with_extension("tmp");
+            let mut file = env.create_writable(&tmp_path)?;
+            file.append(&data[..valid_len])?;
+            file.sync()?;
+            env.rename(&tmp_path, path)?;
+        }
+        let file = env.open_appendable(path)?;
+        Ok((Self { file: Mutex::new(file) }, records))
+    }
+
+    /// Read the records of a manifest without opening it for writing, e.g. while another process
+    /// appends to it.
+    pub fn read_records(env: &dyn Env, path: impl AsRef<Path>) -> Result<Vec<ManifestRecord>> {
+        Ok(Self::decode_records(&env.read_file(path.as_ref())?)?.0)
+    }
+
+    /// Decodes the records of a manifest, along with the length of the data up to the end of the
+    /// last complete record.
+    fn decode_records(data: &[u8]) -> Result<(Vec<ManifestRecord>, usize)> {
+        let mut records = Vec::new();
+        let mut buffer = data;
+        while buffer.remaining() >= 4 {
+            let record_len = (&buffer[..4]).get_u32() as usize;
+            if buffer.remaining() < record_len + 4 {
+                break;
+            }
+            buffer.advance(4);
+            records.push(bincode::deserialize(&buffer[..record_len])?);
+            buffer.advance(record_len);
+        }
+        Ok((records, data.len() - buffer.len()))
+    }
+
+    /// Append a record to the manifest and sync it to the disk.
+    pub fn add_record(&self, record: &ManifestRecord) -> Result<()> {
+        let encoded = bincode::serialize(record)?;
+        let mut buffer = Vec::with_capacity(encoded.len() + 4);
+        buffer.put_u32(encoded.len() as u32);
+        buffer.extend(encoded);
+        let mut file = self.file.lock();
+        file.append(&buffer)?;
+        file.sync()?;
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+use tempfile::tempdir;
+
+#[cfg(test)]
+use super::env::StdEnv;
+
+#[test]
+fn test_manifest_recover() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("MANIFEST");
+    let manifest = Manifest::create(&StdEnv, &path).unwrap();
+    manifest.add_record(&ManifestRecord::Flush(1, 1)).unwrap();
+    manifest.add_record(&ManifestRecord::Ingest(vec![(0, 2), (6, 3)])).unwrap();
+    drop(manifest);
+
+    let (manifest, records) = Manifest::recover(&StdEnv, &path).unwrap();
+    assert_eq!(
+        records,
+        vec![ManifestRecord::Flush(1, 1), ManifestRecord::Ingest(vec![(0, 2), (6, 3)])]
+    );
+    manifest.add_record(&ManifestRecord::Rewrite(vec![(1, Some(4)), (2, None)])).unwrap();
+    drop(manifest);
+
+    // A torn record at the end of the manifest is dropped.
+    let mut data = std::fs::read(&path).unwrap();
+    data.extend([0, 0, 0, 16, 1]);
+    std::fs::write(&path, data).unwrap();
+    let (manifest, records) = Manifest::recover(&StdEnv, &path).unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[2], ManifestRecord::Rewrite(vec![(1, Some(4)), (2, None)]));
+
+    // Records appended after recovering from a torn one are read back.
+    manifest.add_record(&ManifestRecord::Flush(5, 2)).unwrap();
+    manifest.add_record(&ManifestRecord::Flush(6, 3)).unwrap();
+    drop(manifest);
+    let (_, records) = Manifest::recover(&StdEnv, &path).unwrap();
+    assert_eq!(records.len(), 5);
+    assert_eq!(records[4], ManifestRecord::Flush(6, 3));
+    assert!(!dir.path().join("MANIFEST.tmp").exists());
+}
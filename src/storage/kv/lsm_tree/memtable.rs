@@ -30,6 +30,19 @@ impl MemTable {
         self.map.insert(key.to_vec(), value);
     }
 
+    /// Check if the mem-table has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Get the key and size of the entries in a range, skipping tombstones.
+    pub fn live_entries(&self, range: &Range) -> Vec<(Vec<u8>, u64)> {
+        self.map.range(range.clone())
+            .filter(|entry| !entry.value().is_empty())
+            .map(|entry| (entry.key().clone(), (entry.key().len() + entry.value().len()) as u64))
+            .collect()
+    }
+
     /// Get an iterator over a range of keys.
     pub fn scan(&self, bound: Range) -> MemTableIter {
         MemTableIter::create(self.map.clone(), bound)
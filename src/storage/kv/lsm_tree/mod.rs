@@ -1,7 +1,19 @@
+pub mod backup;
+pub mod blob;
 pub mod block;
+pub mod bloom;
+pub mod checksum;
+pub mod encryption;
+pub mod env;
 pub mod sstable;
 pub mod lsm_storage;
+pub mod manifest;
 pub mod lsm_iterator;
 pub mod iterators;
 pub mod memtable;
-pub mod tests;
\ No newline at end of file
+pub mod prefix;
+pub mod repair;
+pub mod rate_limiter;
+pub mod sst_file_writer;
+pub mod tests;
+pub mod wal;
\ No newline at end of file
@@ -0,0 +1,30 @@
+use std::fmt::Debug;
+
+/// Extracts the prefix of a key that is added to the prefix filter of each SsTable, e.g. a tenant
+/// ID at the start of every key.
+///
+/// For a prefix scan to use the filters, the extracted prefix of a key must only depend on the
+/// bytes it starts with: if `prefix(p)` is `Some(x)`, every key starting with `p` must have the
+/// prefix `x` as well.
+pub trait PrefixExtractor: Debug + Send + Sync {
+    /// Name of the extractor, stored with each filter. Filters built by an extractor with a
+    /// different name are ignored, so the name must change whenever the extraction does.
+    fn name(&self) -> String;
+
+    /// Extracts the prefix of a key, or None if the key is out of the domain of the extractor.
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]>;
+}
+
+/// Extracts the first `len` bytes of keys. Shorter keys have no prefix.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedPrefix(pub usize);
+
+impl PrefixExtractor for FixedPrefix {
+    fn name(&self) -> String {
+        format!("fixed:{}", self.0)
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        key.get(..self.0)
+    }
+}
@@ -0,0 +1,256 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::error::Result;
+use super::env::{Env, RandomAccessFile, WritableFile};
+
+/// How long a full bucket lasts at the current rate, which bounds the bursts.
+const REFILL_PERIOD: Duration = Duration::from_millis(100);
+
+/// Largest write requested from the limiter at once, so that large files are paced smoothly.
+const MAX_REQUEST_BYTES: usize = 64 << 10;
+
+/// Adjusts the rate to the pending compaction debt: the minimum rate without debt, growing
+/// linearly to the maximum rate at `max_debt` bytes, so that compaction catches up before reads
+/// suffer from the debt.
+#[derive(Clone, Copy, Debug)]
+pub struct AutoTune {
+    pub min_rate: u64,
+    pub max_rate: u64,
+    pub max_debt: u64,
+}
+
+/// Statistics of a `RateLimiter`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimiterStats {
+    /// Bytes requested through the limiter.
+    pub total_bytes: u64,
+    /// Time writers spent waiting for the limiter.
+    pub throttled: Duration,
+}
+
+struct Bucket {
+    /// The rate in bytes per second.
+    rate: u64,
+    /// Bytes that can be written without waiting, negative when writers are already waiting
+    /// for future refills.
+    available: f64,
+    last_refill: Instant,
+    stats: RateLimiterStats,
+}
+
+/// A token bucket limiting the bytes written per second by flushes and compactions, so that
+/// background I/O leaves disk bandwidth to foreground reads. It can be shared by several
+/// storages, and its rate changed at any time.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    auto_tune: Option<AutoTune>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                available: Self::capacity(rate),
+                last_refill: Instant::now(),
+                stats: RateLimiterStats::default(),
+            }),
+            auto_tune: None,
+        }
+    }
+
+    /// Creates a limiter whose rate is tuned to the pending compaction debt, starting at the
+    /// minimum rate.
+    pub fn new_auto_tuned(auto_tune: AutoTune) -> Self {
+        Self { auto_tune: Some(auto_tune), ..Self::new(auto_tune.min_rate) }
+    }
+
+    fn capacity(rate: u64) -> f64 {
+        rate as f64 * REFILL_PERIOD.as_secs_f64()
+    }
+
+    /// Gets the current rate in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().rate
+    }
+
+    /// Changes the rate in bytes per second, taking effect for the next requests.
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock();
+        Self::refill(&mut bucket);
+        bucket.rate = rate;
+        bucket.available = bucket.available.min(Self::capacity(rate));
+    }
+
+    /// Tunes the rate to the given compaction debt in bytes, if the limiter is auto-tuned.
+    pub fn tune(&self, debt: u64) {
+        if let Some(auto_tune) = self.auto_tune {
+            let ratio = (debt as f64 / auto_tune.max_debt.max(1) as f64).min(1.0);
+            let range = auto_tune.max_rate.saturating_sub(auto_tune.min_rate);
+            self.set_rate(auto_tune.min_rate + (range as f64 * ratio) as u64);
+        }
+    }
+
+    /// Gets the statistics of the limiter.
+    pub fn stats(&self) -> RateLimiterStats {
+        self.bucket.lock().stats
+    }
+
+    fn refill(bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.available = (bucket.available + elapsed * bucket.rate as f64)
+            .min(Self::capacity(bucket.rate));
+        bucket.last_refill = now;
+    }
+
+    /// Takes `bytes` from the bucket, blocking until they are available.
+    pub fn request(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock();
+            Self::refill(&mut bucket);
+            bucket.stats.total_bytes += bytes as u64;
+            bucket.available -= bytes as f64;
+            match bucket.available < 0.0 && bucket.rate > 0 {
+                true => {
+                    let wait = Duration::from_secs_f64(-bucket.available / bucket.rate as f64);
+                    bucket.stats.throttled += wait;
+                    wait
+                }
+                false => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RateLimiter({} B/s)", self.rate())
+    }
+}
+
+/// An `Env` whose new files are written through a `RateLimiter`.
+#[derive(Debug)]
+pub struct RateLimitedEnv {
+    env: Arc<dyn Env>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitedEnv {
+    pub fn new(env: Arc<dyn Env>, limiter: Arc<RateLimiter>) -> Self {
+        Self { env, limiter }
+    }
+}
+
+struct RateLimitedFile {
+    file: Box<dyn WritableFile>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl WritableFile for RateLimitedFile {
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(MAX_REQUEST_BYTES) {
+            self.limiter.request(chunk.len());
+            self.file.append(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync()
+    }
+}
+
+impl Env for RateLimitedEnv {
+    fn open_random_access(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>> {
+        self.env.open_random_access(path)
+    }
+
+    fn create_writable(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = self.env.create_writable(path)?;
+        Ok(Box::new(RateLimitedFile { file, limiter: self.limiter.clone() }))
+    }
+
+    fn open_appendable(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = self.env.open_appendable(path)?;
+        Ok(Box::new(RateLimitedFile { file, limiter: self.limiter.clone() }))
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        self.env.read_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.env.exists(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        self.env.create_dir_all(path)
+    }
+
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>> {
+        self.env.list_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.env.remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.env.rename(from, to)
+    }
+
+    fn link_or_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.env.link_or_copy(from, to)
+    }
+}
+
+
+
+#[test]
+fn test_rate_limiter() {
+    // 1 MB/s with 100 KB bursts: writing 300 KB waits about 200 ms.
+    let limiter = RateLimiter::new(1 << 20);
+    let start = Instant::now();
+    for _ in 0..3 {
+        limiter.request(100 << 10);
+    }
+    let stats = limiter.stats();
+    assert_eq!(stats.total_bytes, 300 << 10);
+    assert!(stats.throttled >= Duration::from_millis(150), "{:?}", stats.throttled);
+    assert!(start.elapsed() >= Duration::from_millis(150));
+
+    // A higher rate takes effect right away.
+    limiter.set_rate(1 << 30);
+    std::thread::sleep(REFILL_PERIOD);
+    let throttled = limiter.stats().throttled;
+    limiter.request(1 << 20);
+    assert_eq!(limiter.stats().throttled, throttled);
+}
+
+#[test]
+fn test_rate_limiter_auto_tune() {
+    let limiter = RateLimiter::new_auto_tuned(AutoTune {
+        min_rate: 1 << 20, max_rate: 11 << 20, max_debt: 100 << 20
+    });
+    assert_eq!(limiter.rate(), 1 << 20);
+    limiter.tune(50 << 20);
+    assert_eq!(limiter.rate(), 6 << 20);
+    limiter.tune(1 << 30);
+    assert_eq!(limiter.rate(), 11 << 20);
+    limiter.tune(0);
+    assert_eq!(limiter.rate(), 1 << 20);
+
+    // Limiters without auto-tuning keep their rate.
+    let limiter = RateLimiter::new(1 << 20);
+    limiter.tune(1 << 30);
+    assert_eq!(limiter.rate(), 1 << 20);
+}
@@ -0,0 +1,385 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::storage::kv::format_raw_key;
+use super::block::BlockIter;
+use super::encryption::BlockCipher;
+use super::lsm_storage::{LsmStorage, LsmStorageOptions, DEFAULT_COLUMN_FAMILY};
+use super::manifest::{Manifest, ManifestRecord, ManifestState};
+use super::sstable::{FileObject, SsTable, SsTableBuilder};
+use super::wal::{Wal, WalBatches};
+
+/// The directory, in the directory of each column family, where repairs move the files they could
+/// not use or replaced. Nothing is deleted, so that the data can still be recovered by hand. Each
+/// repair gets a numbered subdirectory, so that a later repair does not overwrite its files.
+const LOST_DIR: &str = "lost";
+
+/// A data block of an SSTable that could not be read, whose entries were lost by a repair.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LostBlock {
+    pub sstable_id: usize,
+    pub block_idx: usize,
+    /// The first key of the block. The lost entries are between it and the first key of the next
+    /// block.
+    pub first_key: Vec<u8>,
+    pub error: String,
+}
+
+/// What a repair did to a column family.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ColumnFamilyRepair {
+    pub name: String,
+    /// Whether the manifest could be read, and gave the order of the SSTables. Otherwise, they
+    /// were ordered by ID.
+    pub manifest_recovered: bool,
+    /// The SSTables of the column family, all in L0 from earliest to latest.
+    pub sstables: Vec<usize>,
+    /// The SSTables rewritten with the entries of their readable blocks.
+    pub salvaged_sstables: Vec<usize>,
+    /// The blocks of the salvaged SSTables that could not be read.
+    pub lost_blocks: Vec<LostBlock>,
+    /// The SSTables that could not be opened, had no readable block, or were missing from the
+    /// directory, along with the reason.
+    pub lost_sstables: Vec<(usize, String)>,
+    /// The SSTables of the directory that the manifest did not refer to, left behind by an
+    /// interrupted flush or compaction. Their data is in other SSTables.
+    pub unreferenced_sstables: Vec<usize>,
+    /// The number of writes replayed from the WAL into a new SSTable.
+    pub wal_entries: usize,
+}
+
+impl ColumnFamilyRepair {
+    /// Returns true if the repair lost no data of the column family.
+    pub fn is_lossless(&self) -> bool {
+        self.lost_blocks.is_empty() && self.lost_sstables.is_empty()
+    }
+}
+
+/// What `LsmStorage::repair` recovered and lost, by column family.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RepairReport {
+    pub column_families: Vec<ColumnFamilyRepair>,
+    /// The number of WAL segments replayed, then moved to the lost directory.
+    pub wal_segments: usize,
+    /// The lost directory of the repair, relative to the directory of each column family, e.g.
+    /// `lost/2` for the second repair of a storage.
+    pub lost_dir: PathBuf,
+}
+
+impl RepairReport {
+    /// Returns true if the repair lost no data.
+    pub fn is_lossless(&self) -> bool {
+        self.column_families.iter().all(ColumnFamilyRepair::is_lossless)
+    }
+}
+
+impl Display for RepairReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for family in &self.column_families {
+            writeln!(
+                f,
+                "column family {}: {} SSTables, {} writes replayed from the WAL, {}",
+                family.name,
+                family.sstables.len(),
+                family.wal_entries,
+                match family.manifest_recovered {
+                    true => "ordered by the manifest",
+                    false => "manifest lost, ordered by ID",
+                },
+            )?;
+            for block in &family.lost_blocks {
+                writeln!(
+                    f,
+                    "  lost block {} of SSTable {} from key {}: {}",
+                    block.block_idx,
+                    block.sstable_id,
+                    format_raw_key(&block.first_key),
+                    block.error,
+                )?;
+            }
+            for (sstable_id, error) in &family.lost_sstables {
+                writeln!(f, "  lost SSTable {}: {}", sstable_id, error)?;
+            }
+            for sstable_id in &family.unreferenced_sstables {
+                writeln!(f, "  dropped SSTable {}, not in the manifest", sstable_id)?;
+            }
+        }
+        writeln!(f, "replaced files moved to {}", self.lost_dir.display())?;
+        match self.is_lossless() {
+            true => write!(f, "no data lost"),
+            false => write!(f, "some data was lost"),
+        }
+    }
+}
+
+impl LsmStorage {
+    /// Repairs a storage that cannot be opened because its manifest is lost or damaged, or
+    /// serves errors because an SSTable is corrupted. See `repair_with_options`.
+    pub fn repair(path: impl AsRef<Path>) -> Result<RepairReport> {
+        Self::repair_with_options(path, LsmStorageOptions::default())
+    }
+
+    /// Repairs a closed storage, column family by column family:
+    ///
+    /// - SSTables that cannot be opened are dropped, and the ones with corrupted blocks are
+    ///   rewritten with the entries of their readable blocks.
+    /// - The writes of the WAL that are not in SSTables are written to a new SSTable.
+    /// - A fresh manifest places all SSTables in L0, which the next compactions sort out.
+    ///
+    /// SSTables are ordered from the deepest level to L0 when the manifest can still be read, and
+    /// by ID otherwise. IDs follow the order of the data unless `gc_blob_files` rewrote tables,
+    /// so overwritten values may come back after a repair without a manifest.
+    ///
+    /// Replaced and unusable files, including the WAL, are moved to a `lost/<n>` directory rather
+    /// than deleted, numbered after those of the earlier repairs. Corrupted blob files are not
+    /// detected. The options must have the key provider of
+    /// an encrypted storage.
+    pub fn repair_with_options(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+    ) -> Result<RepairReport> {
+        let path = path.as_ref();
+        let env = options.env.as_ref();
+        if !env.exists(path) {
+            return Err(Error::Value(format!("Storage {} not found", path.display())));
+        }
+
+        let mut families = vec![DEFAULT_COLUMN_FAMILY.to_string()];
+        let column_families_path = path.join("column_families");
+        if env.exists(&column_families_path) {
+            let mut names = env.list_dir(&column_families_path)?;
+            names.sort();
+            families.extend(names);
+        }
+        let batches = Wal::read(env, path, options.key_provider.as_deref())?;
+        for (_, batch) in &batches {
+            for entry in batch {
+                if !families.contains(&entry.family) {
+                    families.push(entry.family.clone());
+                }
+            }
+        }
+
+        let family_paths = families
+            .into_iter()
+            .map(|name| {
+                let family_path = match name == DEFAULT_COLUMN_FAMILY {
+                    true => path.to_path_buf(),
+                    false => Self::path_of_column_family(path, &name),
+                };
+                (name, family_path)
+            })
+            .collect::<Vec<_>>();
+
+        // The repair is numbered after the earlier ones of any column family.
+        let mut repair_number = 1;
+        for (_, family_path) in &family_paths {
+            let lost_path = family_path.join(LOST_DIR);
+            if env.exists(&lost_path) {
+                for file_name in env.list_dir(&lost_path)? {
+                    if let Ok(number) = file_name.parse::<u64>() {
+                        repair_number = repair_number.max(number + 1);
+                    }
+                }
+            }
+        }
+        let lost_dir = Path::new(LOST_DIR).join(repair_number.to_string());
+
+        let mut report = RepairReport { lost_dir: lost_dir.clone(), ..RepairReport::default() };
+        for (name, family_path) in family_paths {
+            let family =
+                Self::repair_column_family(&options, name, &family_path, &lost_dir, &batches)?;
+            report.column_families.push(family);
+        }
+
+        // The writes of the WAL are all in SSTables now, and the fresh manifests do not record
+        // which segments were flushed, so opening the storage must not replay them again.
+        let lost_path = path.join(&lost_dir);
+        for file_name in env.list_dir(path)? {
+            if file_name.strip_suffix(".wal").is_some_and(|id| id.parse::<u64>().is_ok()) {
+                env.create_dir_all(&lost_path)?;
+                env.rename(&path.join(&file_name), &lost_path.join(&file_name))?;
+                report.wal_segments += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    fn repair_column_family(
+        options: &LsmStorageOptions,
+        name: String,
+        path: &Path,
+        lost_dir: &Path,
+        batches: &WalBatches,
+    ) -> Result<ColumnFamilyRepair> {
+        let env = options.env.as_ref();
+        env.create_dir_all(path)?;
+        let mut repair = ColumnFamilyRepair { name, ..ColumnFamilyRepair::default() };
+
+        let mut on_disk = BTreeSet::new();
+        let mut max_blob_file_id = 0;
+        for file_name in env.list_dir(path)? {
+            if let Some(Ok(id)) = file_name.strip_suffix(".sst").map(|id| id.parse::<usize>()) {
+                on_disk.insert(id);
+            }
+            if let Some(Ok(id)) = file_name.strip_suffix(".blob").map(|id| id.parse::<usize>()) {
+                max_blob_file_id = max_blob_file_id.max(id);
+            }
+        }
+        let manifest_path = path.join("MANIFEST");
+        let state = match env.exists(&manifest_path) {
+            true => Manifest::read_records(env, &manifest_path)
+                .and_then(ManifestState::replay)
+                .ok(),
+            false => None,
+        };
+        repair.manifest_recovered = state.is_some();
+        let order = match &state {
+            Some(state) => {
+                // Deeper levels hold older data, and the sort keeps L0 from earliest to latest.
+                let mut placements = state.placements.clone();
+                placements.sort_by_key(|(level, _)| Reverse(*level));
+                let order = placements.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+                for id in on_disk.iter().filter(|id| !order.contains(id)) {
+                    Self::move_to_lost(options, path, lost_dir, *id)?;
+                    repair.unreferenced_sstables.push(*id);
+                }
+                order
+            }
+            None => on_disk.iter().copied().collect(),
+        };
+        // Blob files outlive the SSTables they were written with, and their IDs are taken too.
+        let mut next_sst_id = on_disk.last().map_or(1, |id| id + 1).max(max_blob_file_id + 1);
+        if let Some(state) = &state {
+            next_sst_id = next_sst_id.max(state.next_sst_id);
+        }
+
+        for id in order {
+            if !on_disk.contains(&id) {
+                repair.lost_sstables.push((id, "file not found".to_string()));
+            } else if Self::salvage_sstable(options, path, lost_dir, id, &mut repair)? {
+                repair.sstables.push(id);
+            }
+        }
+
+        // The last write of each key in the segments the manifest did not record as flushed.
+        let wal_segment = state.as_ref().map_or(0, |state| state.wal_segment);
+        let mut writes = BTreeMap::new();
+        for (_, batch) in batches.iter().filter(|(id, _)| *id >= wal_segment) {
+            for entry in batch.iter().filter(|entry| entry.family == repair.name) {
+                writes.insert(entry.key.clone(), entry.value.clone());
+                repair.wal_entries += 1;
+            }
+        }
+        if !writes.is_empty() {
+            let mut builder = Self::new_repair_builder(options);
+            if let Some(key_provider) = &options.key_provider {
+                builder = builder.with_cipher(BlockCipher::new(key_provider.as_ref())?);
+            }
+            for (key, value) in writes {
+                builder.add(&key, &value);
+            }
+            builder.build(env, next_sst_id, None, Self::path_of_sst(path, next_sst_id))?;
+            repair.sstables.push(next_sst_id);
+        }
+
+        // The fresh manifest is written aside, so that a crash leaves either manifest in place.
+        let tmp_path = path.join("MANIFEST.tmp");
+        Manifest::create(env, &tmp_path)?.add_record(&ManifestRecord::Snapshot(
+            repair.sstables.iter().map(|id| (0, *id)).collect(),
+        ))?;
+        if env.exists(&manifest_path) {
+            env.create_dir_all(&path.join(lost_dir))?;
+            env.rename(&manifest_path, &path.join(lost_dir).join("MANIFEST"))?;
+        }
+        env.rename(&tmp_path, &manifest_path)?;
+        Ok(repair)
+    }
+
+    /// Checks every block of an SSTable, and rewrites it under the same ID with the entries of its
+    /// readable blocks if some are corrupted. Returns false if nothing could be kept.
+    fn salvage_sstable(
+        options: &LsmStorageOptions,
+        path: &Path,
+        lost_dir: &Path,
+        id: usize,
+        repair: &mut ColumnFamilyRepair,
+    ) -> Result<bool> {
+        let env = options.env.as_ref();
+        let sst_path = Self::path_of_sst(path, id);
+        let sstable = match FileObject::open(env, &sst_path).and_then(|file| {
+            SsTable::open_with_keys(id, None, file, options.key_provider.as_deref())
+        }) {
+            Ok(sstable) => sstable,
+            Err(err) => {
+                Self::move_to_lost(options, path, lost_dir, id)?;
+                repair.lost_sstables.push((id, err.to_string()));
+                return Ok(false);
+            }
+        };
+        let mut entries = vec![];
+        let mut lost_blocks = vec![];
+        for block_idx in 0..sstable.num_of_blocks() {
+            let block = sstable.read_block(block_idx)
+                .and_then(|block| BlockIter::new(block).collect::<Result<Vec<_>>>());
+            match block {
+                Ok(block) => entries.extend(block),
+                Err(err) => lost_blocks.push(LostBlock {
+                    sstable_id: id,
+                    block_idx,
+                    first_key: sstable.block_metas()[block_idx].first_key.to_vec(),
+                    error: err.to_string(),
+                }),
+            }
+        }
+        if lost_blocks.is_empty() {
+            return Ok(true);
+        }
+        repair.lost_blocks.extend(lost_blocks);
+        if entries.is_empty() {
+            Self::move_to_lost(options, path, lost_dir, id)?;
+            repair.lost_sstables.push((id, "no readable block".to_string()));
+            return Ok(false);
+        }
+
+        // The rewritten table keeps its ID, which orders it among the others without a manifest.
+        let mut builder = Self::new_repair_builder(options);
+        if let Some(cipher) = sstable.cipher() {
+            builder = builder.with_cipher(cipher.clone());
+        }
+        for (key, value) in entries {
+            builder.add(&key, &value);
+        }
+        let lost_path = path.join(lost_dir);
+        env.create_dir_all(&lost_path)?;
+        env.rename(&sst_path, &Self::path_of_sst(&lost_path, id))?;
+        builder.build(env, id, None, &sst_path)?;
+        repair.salvaged_sstables.push(id);
+        Ok(true)
+    }
+
+    fn new_repair_builder(options: &LsmStorageOptions) -> SsTableBuilder {
+        let mut builder = SsTableBuilder::new(options.block_size);
+        if let Some(prefix_extractor) = &options.prefix_extractor {
+            builder = builder.with_prefix_extractor(prefix_extractor.clone());
+        }
+        builder
+    }
+
+    /// Moves an SSTable to the lost directory. Blob files stay, as other SSTables may point into
+    /// them, and `gc_blob_files` deletes the ones no SSTable points into anymore.
+    fn move_to_lost(
+        options: &LsmStorageOptions,
+        path: &Path,
+        lost_dir: &Path,
+        id: usize,
+    ) -> Result<()> {
+        let env = options.env.as_ref();
+        let lost_path = path.join(lost_dir);
+        env.create_dir_all(&lost_path)?;
+        env.rename(&Self::path_of_sst(path, id), &Self::path_of_sst(&lost_path, id))
+    }
+}
@@ -0,0 +1,110 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+use super::blob::StoredValue;
+use super::env::{Env, StdEnv};
+use super::sstable::{SsTableBuilder, SsTableProperties};
+
+/// Writes sorted key-value pairs to a standalone SSTable file, which can later be loaded into an
+/// `LsmStorage` with `ingest_external_files`.
+pub struct SstFileWriter {
+    builder: SsTableBuilder,
+    env: Arc<dyn Env>,
+    path: PathBuf,
+    last_key: Option<Vec<u8>>,
+}
+
+impl SstFileWriter {
+    /// Create a writer for the SSTable file at the given path.
+    pub fn create(path: impl AsRef<Path>) -> Self {
+        Self::create_with_env(Arc::new(StdEnv), path)
+    }
+
+    /// Create a writer for the SSTable file at the given path of an `Env`, which must be the one
+    /// of the `LsmStorage` ingesting it.
+    pub fn create_with_env(env: Arc<dyn Env>, path: impl AsRef<Path>) -> Self {
+        Self {
+            builder: SsTableBuilder::new(4096),
+            env,
+            path: path.as_ref().to_path_buf(),
+            last_key: None,
+        }
+    }
+
+    /// Adds a key-value pair. Keys must be added in strictly increasing order.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if value.is_empty() {
+            return Err(Error::Value("value cannot be empty".into()));
+        }
+        self.add(key, &StoredValue::encode_inline(value))
+    }
+
+    /// Adds a tombstone for a key. Keys must be added in strictly increasing order.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.add(key, &[])
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            return Err(Error::Value("key cannot be empty".into()));
+        }
+        if let Some(last_key) = &self.last_key {
+            if &last_key[..] >= key {
+                return Err(Error::Value(format!(
+                    "keys must be added in strictly increasing order, got {:?} after {:?}",
+                    key, last_key
+                )));
+            }
+        }
+        self.builder.add(key, value);
+        self.last_key = Some(key.to_vec());
+        Ok(())
+    }
+
+    /// Writes the SSTable file to the disk and returns its properties.
+    pub fn finish(self) -> Result<SsTableProperties> {
+        if self.last_key.is_none() {
+            return Err(Error::Value("cannot write an empty SSTable".into()));
+        }
+        let sstable = self.builder.build(self.env.as_ref(), 0, None, &self.path)?;
+        Ok(sstable.properties().clone())
+    }
+}
+
+
+
+#[cfg(test)]
+use tempfile::tempdir;
+
+#[test]
+fn test_sst_file_writer() {
+    use super::sstable::{FileObject, SsTable, SsTableIter};
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("external.sst");
+    let mut writer = SstFileWriter::create(&path);
+    writer.put(b"a", b"1").unwrap();
+    writer.delete(b"b").unwrap();
+    writer.put(b"c", b"3").unwrap();
+    assert!(writer.put(b"c", b"4").is_err());
+    assert!(writer.put(b"b", b"2").is_err());
+    let properties = writer.finish().unwrap();
+    assert_eq!(properties.num_entries, 3);
+    assert_eq!(properties.num_tombstones, 1);
+
+    let sstable = SsTable::open(0, None, FileObject::open(&StdEnv, &path).unwrap()).unwrap();
+    let entries = SsTableIter::new(Arc::new(sstable)).unwrap()
+        .collect::<Result<Vec<_>>>().unwrap();
+    assert_eq!(entries, vec![
+        (b"a".to_vec(), StoredValue::encode_inline(b"1")),
+        (b"b".to_vec(), vec![]),
+        (b"c".to_vec(), StoredValue::encode_inline(b"3")),
+    ]);
+}
+
+#[test]
+fn test_sst_file_writer_empty() {
+    let dir = tempdir().unwrap();
+    let writer = SstFileWriter::create(dir.path().join("external.sst"));
+    assert!(writer.finish().is_err());
+}
@@ -1,15 +1,34 @@
-use std::fs::File;
 use std::ops::{RangeBounds, Bound};
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bytes::{Buf, Bytes, BufMut};
 
 use crate::error::{Result, Error};
 use crate::storage::kv::Range;
 use super::block::{Block, BlockBuilder, BlockIter};
+use super::bloom::Bloom;
+use super::checksum::crc32;
+use super::encryption::{BlockCipher, KeyProvider};
+use super::env::{Env, RandomAccessFile};
 use super::iterators::StorageIter;
 use super::lsm_storage::BlockCache;
+use super::prefix::PrefixExtractor;
+
+/// Magic number ending encrypted SSTables.
+const ENCRYPTED_MAGIC: &[u8; 8] = b"FEATHENC";
+/// Length of the trailer of encrypted SSTables.
+const ENCRYPTED_TRAILER_LEN: u64 = 16;
+/// Position of the encrypted metadata, after all data blocks.
+const TAIL_POSITION: u64 = u64::MAX;
+/// Magic number ending unencrypted SSTables with checksums.
+const CHECKSUM_MAGIC: &[u8; 8] = b"FEATHSUM";
+/// Length of the trailer of unencrypted SSTables with checksums.
+const CHECKSUM_TRAILER_LEN: u64 = 12;
+/// Length of a checksum, following each data block of unencrypted SSTables.
+const CHECKSUM_LEN: usize = 4;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockMeta {
@@ -56,28 +75,109 @@ impl BlockMeta {
     }
 }
 
+/// Key range and entry statistics of an SSTable, written after the block metas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SsTableProperties {
+    /// The smallest key in the SSTable.
+    pub first_key: Bytes,
+    /// The largest key in the SSTable.
+    pub last_key: Bytes,
+    /// Number of key-value pairs, tombstones included.
+    pub num_entries: u64,
+    /// Number of tombstones, i.e. entries with an empty value.
+    pub num_tombstones: u64,
+    /// Creation time of the SSTable, in seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+/// Data alignment: 
+/// 
+/// ```text
+///     | first_key_len (2B) | first_key | last_key_len (2B) | last_key | 
+///     | num_entries (8B) | num_tombstones (8B) | created_at (8B) |
+/// ```
+impl SsTableProperties {
+    /// Encode properties to a buffer.
+    pub fn encode_properties(&self, buffer: &mut Vec<u8>) {
+        buffer.put_u16(self.first_key.len() as u16);
+        buffer.put_slice(&self.first_key);
+        buffer.put_u16(self.last_key.len() as u16);
+        buffer.put_slice(&self.last_key);
+        buffer.put_u64(self.num_entries);
+        buffer.put_u64(self.num_tombstones);
+        buffer.put_u64(self.created_at);
+    }
+
+    /// Decode properties from a buffer.
+    pub fn decode_properties(mut buffer: impl Buf) -> Self {
+        let first_key_len = buffer.get_u16() as usize;
+        let first_key = buffer.copy_to_bytes(first_key_len);
+        let last_key_len = buffer.get_u16() as usize;
+        let last_key = buffer.copy_to_bytes(last_key_len);
+        let num_entries = buffer.get_u64();
+        let num_tombstones = buffer.get_u64();
+        let created_at = buffer.get_u64();
+        Self { first_key, last_key, num_entries, num_tombstones, created_at }
+    }
+}
+
+/// Bits of Bloom filter per distinct key prefix.
+const PREFIX_FILTER_BITS_PER_KEY: usize = 10;
+
+/// A Bloom filter over the key prefixes of an SSTable, tagged with the extractor that built it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefixFilter {
+    /// Name of the prefix extractor.
+    extractor: String,
+    bloom: Bloom,
+}
+
+/// Data alignment:
+///
+/// ```text
+///     | extractor_len (2B) | extractor | bloom |
+/// ```
+impl PrefixFilter {
+    /// Encode the prefix filter to a buffer.
+    pub fn encode_prefix_filter(&self, buffer: &mut Vec<u8>) {
+        buffer.put_u16(self.extractor.len() as u16);
+        buffer.put_slice(self.extractor.as_bytes());
+        self.bloom.encode(buffer);
+    }
+
+    /// Decode a prefix filter from a buffer holding exactly the encoded filter.
+    pub fn decode_prefix_filter(mut buffer: &[u8]) -> Result<Self> {
+        if buffer.len() < 2 {
+            return Err(Error::Internal("Prefix filter is too short".into()));
+        }
+        let extractor_len = buffer.get_u16() as usize;
+        let extractor = buffer.get(..extractor_len)
+            .and_then(|extractor| String::from_utf8(extractor.to_vec()).ok())
+            .ok_or_else(|| Error::Internal("Invalid prefix extractor name".into()))?;
+        Ok(Self { extractor, bloom: Bloom::decode(&buffer[extractor_len..]) })
+    }
+}
+
 /// A file object.
-pub struct FileObject(File, u64);
+pub struct FileObject(Box<dyn RandomAccessFile>, u64);
 
 impl FileObject {
-    /// Create a new file object (day 2) and write the file to the disk (day 4).
-    pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
-        std::fs::write(path, &data)?;
-        Ok(FileObject(
-            File::options().read(true).write(false).open(path)?,
-            data.len() as u64,
-        ))
+    /// Create a new file object (day 2) and write the file to the disk (day 4), syncing it.
+    pub fn create(env: &dyn Env, path: &Path, data: Vec<u8>) -> Result<Self> {
+        let mut file = env.create_writable(path)?;
+        file.append(&data)?;
+        file.sync()?;
+        Ok(FileObject(env.open_random_access(path)?, data.len() as u64))
     }
 
     pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
-        use std::os::unix::fs::FileExt;
-        let mut data = vec![0; len as usize];
-        self.0.read_exact_at(&mut data[..], offset)?;
-        Ok(data)
+        self.0.read_at(offset, len)
     }
 
-    pub fn open(_path: &Path) -> Result<Self> {
-        unimplemented!()
+    pub fn open(env: &dyn Env, path: &Path) -> Result<Self> {
+        let file = env.open_random_access(path)?;
+        let size = file.size();
+        Ok(FileObject(file, size))
     }
 
     pub fn size(&self) -> u64 {
@@ -90,7 +190,14 @@ pub struct SsTable {
     file: FileObject,
     block_metas: Vec<BlockMeta>,
     block_meta_offset: usize,
+    properties: SsTableProperties,
+    prefix_filter: Option<PrefixFilter>,
     block_cache: Option<Arc<BlockCache>>,
+    /// Number of blocks read from the disk, for statistics.
+    block_reads: AtomicUsize,
+    /// Decrypts the blocks, if the SSTable is encrypted. Otherwise, each data block is followed
+    /// by its checksum.
+    cipher: Option<BlockCipher>,
 }
 
 impl SsTable {
@@ -99,29 +206,194 @@ impl SsTable {
         Self::open(0, None, file)
     }
 
-    /// Open SSTable from a file.
-    /// 
-    /// Data alignment: 
-    /// 
-    ///     | data block | data block | ... | data block | meta block | meta block offset (u32) |
-    /// 
+    /// Open an unencrypted SSTable from a file.
+    ///
+    /// Data alignment:
+    ///
+    /// ```text
+    ///     | data block | checksum (u32) | ... | data block | checksum (u32) |
+    ///     | meta block | properties | prefix filter (optional) |
+    ///     | meta block offset (u32) | properties offset (u32) | prefix filter offset (u32) |
+    ///     | tail checksum (u32) | magic (8B) |
+    /// ```
+    ///
+    /// The checksums are CRC-32s of the data blocks, and of everything from the meta block to the
+    /// offsets for the tail one.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
+        Self::open_with_keys(id, block_cache, file, None)
+    }
+
+    /// Open an SSTable from a file, which may be encrypted with a key of the provider.
+    ///
+    /// In encrypted SSTables, every data block is encrypted, and so is everything after them as a
+    /// single block. The offsets are the ones of the unencrypted layout:
+    ///
+    /// ```text
+    ///     | encrypted data block | ... | encrypted meta block, properties, ..., offsets |
+    ///     | meta block offset (u32) | key id (u32) | magic (8B) |
+    /// ```
+    pub fn open_with_keys(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        file: FileObject,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<Self> {
+        let invalid = || Error::Internal(format!("SSTable {} has invalid offsets", id));
         let file_len = file.size();
-        let meta_offset_raw = file.read(file_len - 4, 4)?;
-        let block_meta_offset = (&meta_offset_raw[..]).get_u32() as u64;
-        let meta_raw = file.read(block_meta_offset, file_len - 4 - block_meta_offset)?;
-        let block_metas = BlockMeta::decode_block_meta(&meta_raw[..]);
+        if file_len < 12 {
+            return Err(Error::Internal(format!("SSTable {} is too short", id)));
+        }
+        let encrypted = file_len >= ENCRYPTED_TRAILER_LEN
+            && file.read(file_len - 8, 8)? == ENCRYPTED_MAGIC;
+        let (tail_offset, tail, cipher) = match encrypted {
+            true => {
+                let trailer = file.read(file_len - ENCRYPTED_TRAILER_LEN, 8)?;
+                let mut trailer = &trailer[..];
+                let tail_offset = trailer.get_u32() as u64;
+                let key_id = trailer.get_u32();
+                let key_provider = key_provider.ok_or_else(|| Error::Value(format!(
+                    "SSTable {} is encrypted with key {}, but no key provider is set", id, key_id
+                )))?;
+                let cipher = BlockCipher::open(key_provider, key_id)?;
+                let tail_len = (file_len - ENCRYPTED_TRAILER_LEN).checked_sub(tail_offset)
+                    .ok_or_else(invalid)?;
+                let tail = cipher.decrypt(TAIL_POSITION, &file.read(tail_offset, tail_len)?)?;
+                (tail_offset, tail, Some(cipher))
+            }
+            false => {
+                if file_len < CHECKSUM_TRAILER_LEN + 12
+                    || file.read(file_len - 8, 8)? != CHECKSUM_MAGIC
+                {
+                    return Err(Error::Internal(format!(
+                        "SSTable {} has no checksum trailer", id
+                    )));
+                }
+                let tail_end = file_len - CHECKSUM_TRAILER_LEN;
+                let tail_offset = (&file.read(tail_end - 12, 4)?[..]).get_u32() as u64;
+                let tail_len = tail_end.checked_sub(tail_offset).ok_or_else(invalid)?;
+                let tail = file.read(tail_offset, tail_len)?;
+                if crc32(&tail) != (&file.read(tail_end, 4)?[..]).get_u32() {
+                    return Err(Error::Internal(format!(
+                        "SSTable {} has a checksum mismatch in its meta block", id
+                    )));
+                }
+                (tail_offset, tail, None)
+            }
+        };
+
+        // The offsets are relative to the file, and the tail starts at the meta block.
+        let footer_offset = tail.len().checked_sub(12).ok_or_else(invalid)?;
+        let mut offsets_raw = &tail[footer_offset..];
+        let mut next_offset = || (offsets_raw.get_u32() as u64).checked_sub(tail_offset)
+            .map(|offset| offset as usize)
+            .ok_or_else(invalid);
+        let (block_meta_offset, properties_offset, prefix_filter_offset) =
+            (next_offset()?, next_offset()?, next_offset()?);
+        if block_meta_offset != 0
+            || properties_offset > prefix_filter_offset
+            || prefix_filter_offset > footer_offset
+        {
+            return Err(invalid());
+        }
+        let block_metas = BlockMeta::decode_block_meta(&tail[..properties_offset]);
+        let properties = SsTableProperties::decode_properties(
+            &tail[properties_offset..prefix_filter_offset]
+        );
+        let prefix_filter = match prefix_filter_offset == footer_offset {
+            true => None,
+            false => Some(PrefixFilter::decode_prefix_filter(
+                &tail[prefix_filter_offset..footer_offset]
+            )?),
+        };
         Ok(Self {
             id,
             file,
             block_metas,
-            block_meta_offset: block_meta_offset as usize,
+            block_meta_offset: tail_offset as usize,
+            properties,
+            prefix_filter,
             block_cache,
+            block_reads: AtomicUsize::new(0),
+            cipher,
         })
     }
 
-    /// Read a block from the disk.
+    /// Get the SSTable ID.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Get the ID of the key the SSTable is encrypted with, if it is.
+    pub fn key_id(&self) -> Option<u32> {
+        self.cipher.as_ref().map(|cipher| cipher.key_id())
+    }
+
+    /// Get the cipher of the SSTable, if it is encrypted.
+    pub fn cipher(&self) -> Option<&BlockCipher> {
+        self.cipher.as_ref()
+    }
+
+    /// Get the size of the SsTable file.
+    pub fn table_size(&self) -> u64 {
+        self.file.size()
+    }
+
+    /// Get the properties of the SSTable.
+    pub fn properties(&self) -> &SsTableProperties {
+        &self.properties
+    }
+
+    /// Get the metas of the data blocks.
+    pub fn block_metas(&self) -> &[BlockMeta] {
+        &self.block_metas
+    }
+
+    /// Get the name of the extractor that built the prefix filter, if the SSTable has one.
+    pub fn prefix_extractor_name(&self) -> Option<&str> {
+        self.prefix_filter.as_ref().map(|filter| filter.extractor.as_str())
+    }
+
+    /// Check if the key range of the SSTable intersects with `range`.
+    pub fn overlaps_range(&self, range: &Range) -> bool {
+        let first_key = &self.properties.first_key[..];
+        let last_key = &self.properties.last_key[..];
+        let start_before_last = match range.start_bound() {
+            Bound::Included(v) => &v[..] <= last_key,
+            Bound::Excluded(v) => &v[..] < last_key,
+            Bound::Unbounded => true,
+        };
+        let end_after_first = match range.end_bound() {
+            Bound::Included(v) => first_key <= &v[..],
+            Bound::Excluded(v) => first_key < &v[..],
+            Bound::Unbounded => true,
+        };
+        start_before_last && end_after_first
+    }
+
+    /// Check if `key` falls into the key range of the SSTable.
+    pub fn may_contain_key(&self, key: &[u8]) -> bool {
+        &self.properties.first_key[..] <= key && key <= &self.properties.last_key[..]
+    }
+
+    /// Check if the SSTable may contain keys with the prefix extracted by `extractor`. Always true
+    /// if the SSTable has no prefix filter built by the same extractor.
+    pub fn may_contain_prefix(&self, extractor: &dyn PrefixExtractor, prefix: &[u8]) -> bool {
+        match &self.prefix_filter {
+            Some(filter) if filter.extractor == extractor.name() => {
+                filter.bloom.may_contain(Bloom::hash(prefix))
+            }
+            _ => true,
+        }
+    }
+
+    /// Get the number of blocks read from the disk so far.
+    pub fn num_block_reads(&self) -> usize {
+        self.block_reads.load(Ordering::Relaxed)
+    }
+
+    /// Read a block from the disk, verifying its checksum or authentication tag.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
+        self.block_reads.fetch_add(1, Ordering::Relaxed);
         let block_offset = self.block_metas[block_idx].offset;
         let block_end = self
             .block_metas
@@ -129,7 +401,25 @@ impl SsTable {
             .map_or(self.block_meta_offset, |meta| meta.offset);
         let block_len = block_end - block_offset;
         let block_raw = self.file.read(block_offset as u64, block_len as u64)?;
-        Ok(Arc::new(Block::decode(&block_raw)))
+        match &self.cipher {
+            Some(cipher) => {
+                Ok(Arc::new(Block::decode(&cipher.decrypt(block_idx as u64, &block_raw)?)))
+            }
+            None => {
+                let (data, mut checksum) = block_raw
+                    .split_at_checked(block_len.saturating_sub(CHECKSUM_LEN))
+                    .filter(|(data, _)| !data.is_empty())
+                    .ok_or_else(|| Error::Internal(format!(
+                        "SSTable {} block {} is too short", self.id, block_idx
+                    )))?;
+                if crc32(data) != checksum.get_u32() {
+                    return Err(Error::Internal(format!(
+                        "SSTable {} block {} has a checksum mismatch", self.id, block_idx
+                    )));
+                }
+                Ok(Arc::new(Block::decode(data)))
+            }
+        }
     }
 
     /// Read a block from disk, with block cache. (Day 4)
@@ -162,6 +452,37 @@ impl SsTable {
     pub fn num_of_blocks(&self) -> usize {
         self.block_metas.len()
     }
+
+    /// Get the total size of the data blocks.
+    pub fn data_size(&self) -> u64 {
+        self.block_meta_offset as u64
+    }
+
+    /// Get the first key and size of each data block that may hold keys in `range`, to estimate
+    /// the data in a range at block granularity.
+    pub fn blocks_in_range(&self, range: &Range) -> Vec<(Bytes, u64)> {
+        if !self.overlaps_range(range) || self.block_metas.is_empty() {
+            return vec![];
+        }
+        let first = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => self.front_find_block_idx(key).max(0),
+            Bound::Unbounded => 0,
+        };
+        let last = match range.end_bound() {
+            Bound::Included(key) => self.front_find_block_idx(key),
+            Bound::Excluded(key) => self.back_find_block_idx(key) - 1,
+            Bound::Unbounded => self.block_metas.len() as i32 - 1,
+        };
+        (first..=last)
+            .map(|idx| {
+                let idx = idx as usize;
+                let end = self.block_metas.get(idx + 1)
+                    .map_or(self.block_meta_offset, |meta| meta.offset);
+                let meta = &self.block_metas[idx];
+                (meta.first_key.clone(), (end - meta.offset) as u64)
+            })
+            .collect()
+    }
 }
 
 /// Builds an SSTable from key-value pairs.
@@ -169,8 +490,16 @@ pub struct SsTableBuilder {
     pub(super) meta: Vec<BlockMeta>,
     data: Vec<u8>,
     cur_block_first_key: Vec<u8>,
+    first_key: Vec<u8>,
+    last_key: Vec<u8>,
+    num_entries: u64,
+    num_tombstones: u64,
     block_builder: BlockBuilder,
     block_size: usize,
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// Hashes of the distinct key prefixes.
+    prefix_hashes: Vec<u32>,
+    cipher: Option<BlockCipher>,
 }
 
 impl SsTableBuilder {
@@ -180,16 +509,56 @@ impl SsTableBuilder {
             meta: Vec::new(),
             data: Vec::new(),
             cur_block_first_key: Vec::new(),
+            first_key: Vec::new(),
+            last_key: Vec::new(),
+            num_entries: 0,
+            num_tombstones: 0,
             block_builder: BlockBuilder::new(block_size),
             block_size,
+            prefix_extractor: None,
+            prefix_hashes: Vec::new(),
+            cipher: None,
         }
     }
 
+    /// Encrypts the SSTable with the cipher.
+    pub fn with_cipher(mut self, cipher: BlockCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Get the cipher the SSTable is encrypted with, if any.
+    pub fn cipher(&self) -> Option<&BlockCipher> {
+        self.cipher.as_ref()
+    }
+
+    /// Builds a prefix filter over the key prefixes extracted by `prefix_extractor`.
+    pub fn with_prefix_extractor(mut self, prefix_extractor: Arc<dyn PrefixExtractor>) -> Self {
+        self.prefix_extractor = Some(prefix_extractor);
+        self
+    }
+
     /// Adds a key-value pair to SSTable
     pub fn add(&mut self, key: &[u8], value: &[u8]) {
         if self.cur_block_first_key.is_empty() {
             self.cur_block_first_key = key.into();
         }
+        if self.first_key.is_empty() {
+            self.first_key = key.into();
+        }
+        if let Some(prefix) = self.prefix_extractor.as_ref().and_then(|e| e.prefix(key)) {
+            // Keys are added in order, so only the previous key can share the prefix.
+            let hash = Bloom::hash(prefix);
+            let last_prefix = self.prefix_extractor.as_ref().and_then(|e| e.prefix(&self.last_key));
+            if self.num_entries == 0 || last_prefix != Some(prefix) {
+                self.prefix_hashes.push(hash);
+            }
+        }
+        self.last_key = key.into();
+        self.num_entries += 1;
+        if value.is_empty() {
+            self.num_tombstones += 1;
+        }
         if !self.block_builder.add(key, value) {
             self.finalize_block();
             assert!(self.block_builder.add(key, value));
@@ -201,11 +570,18 @@ impl SsTableBuilder {
         let old_builder = 
             std::mem::replace(&mut self.block_builder, BlockBuilder::new(self.block_size));
         let encoded_block = old_builder.build().encode();
+        let block_idx = self.meta.len() as u64;
         self.meta.push(BlockMeta {
             offset: self.data.len(),
             first_key: self.cur_block_first_key.clone().into(),
         });
-        self.data.extend(encoded_block);
+        match &self.cipher {
+            Some(cipher) => self.data.extend(cipher.encrypt(block_idx, &encoded_block)),
+            None => {
+                self.data.extend(&encoded_block);
+                self.data.put_u32(crc32(&encoded_block));
+            }
+        }
     }
 
     /// Get the estimated size of the SSTable.
@@ -217,6 +593,7 @@ impl SsTableBuilder {
     /// chapter 4 block cache.
     pub fn build(
         mut self,
+        env: &dyn Env,
         id: usize,
         block_cache: Option<Arc<BlockCache>>,
         path: impl AsRef<Path>,
@@ -224,21 +601,61 @@ impl SsTableBuilder {
         self.finalize_block();
         let mut sst_data = self.data;
         let block_meta_offset = sst_data.len();
-        BlockMeta::encode_block_meta(&self.meta, &mut sst_data);
-        sst_data.put_u32(block_meta_offset as u32);
-        let file = FileObject::create(path.as_ref(), sst_data)?;
+        let mut tail = Vec::new();
+        BlockMeta::encode_block_meta(&self.meta, &mut tail);
+        let properties = SsTableProperties {
+            first_key: self.first_key.into(),
+            last_key: self.last_key.into(),
+            num_entries: self.num_entries,
+            num_tombstones: self.num_tombstones,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+        };
+        let properties_offset = block_meta_offset + tail.len();
+        properties.encode_properties(&mut tail);
+        let prefix_filter = self.prefix_extractor.map(|extractor| PrefixFilter {
+            extractor: extractor.name(),
+            bloom: Bloom::build_from_key_hashes(&self.prefix_hashes, PREFIX_FILTER_BITS_PER_KEY),
+        });
+        let prefix_filter_offset = block_meta_offset + tail.len();
+        if let Some(prefix_filter) = &prefix_filter {
+            prefix_filter.encode_prefix_filter(&mut tail);
+        }
+        tail.put_u32(block_meta_offset as u32);
+        tail.put_u32(properties_offset as u32);
+        tail.put_u32(prefix_filter_offset as u32);
+        match &self.cipher {
+            Some(cipher) => {
+                sst_data.extend(cipher.encrypt(TAIL_POSITION, &tail));
+                sst_data.put_u32(block_meta_offset as u32);
+                sst_data.put_u32(cipher.key_id());
+                sst_data.put_slice(ENCRYPTED_MAGIC);
+            }
+            None => {
+                let checksum = crc32(&tail);
+                sst_data.extend(tail);
+                sst_data.put_u32(checksum);
+                sst_data.put_slice(CHECKSUM_MAGIC);
+            }
+        }
+        let file = FileObject::create(env, path.as_ref(), sst_data)?;
         Ok(SsTable {
             id,
             file,
             block_metas: self.meta,
             block_meta_offset,
+            properties,
+            prefix_filter,
             block_cache,
+            block_reads: AtomicUsize::new(0),
+            cipher: self.cipher,
         })
     }
 
     #[cfg(test)]
     pub(crate) fn build_for_test(self, path: impl AsRef<Path>) -> Result<SsTable> {
-        self.build(0, None, path)
+        self.build(&StdEnv, 0, None, path)
     }
 }
 
@@ -483,6 +900,9 @@ impl DoubleEndedIterator for SsTableIter {
 #[cfg(test)]
 use tempfile::{tempdir, TempDir};
 
+#[cfg(test)]
+use super::env::StdEnv;
+
 #[test]
 fn test_sst_build_single_key() {
     let mut builder = SsTableBuilder::new(16);
@@ -546,6 +966,33 @@ fn test_sst_decode() {
     assert_eq!(new_sst.block_metas, meta);
 }
 
+#[test]
+fn test_sst_properties() {
+    let (_dir, sst) = generate_sst();
+    let properties = sst.properties().clone();
+    assert_eq!(properties.first_key, key_of(0));
+    assert_eq!(properties.last_key, key_of(num_of_keys() - 1));
+    assert_eq!(properties.num_entries, num_of_keys() as u64);
+    assert_eq!(properties.num_tombstones, 0);
+    let new_sst = SsTable::open_for_test(sst.file).unwrap();
+    assert_eq!(new_sst.properties(), &properties);
+}
+
+#[test]
+fn test_sst_properties_tombstones() {
+    let mut builder = SsTableBuilder::new(16);
+    builder.add(b"11", b"11");
+    builder.add(b"22", b"");
+    builder.add(b"33", b"33");
+    builder.add(b"44", b"");
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    assert_eq!(sst.properties().first_key, &b"11"[..]);
+    assert_eq!(sst.properties().last_key, &b"44"[..]);
+    assert_eq!(sst.properties().num_entries, 4);
+    assert_eq!(sst.properties().num_tombstones, 2);
+}
+
 #[cfg(test)]
 fn as_bytes(x: &[u8]) -> Bytes {
     Bytes::copy_from_slice(x)
@@ -724,4 +1171,109 @@ fn test_sst_seek_key_iter() {
         }
         iter.front_seek_to_key(b"k", true).unwrap();
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_sst_prefix_filter() {
+    use super::prefix::FixedPrefix;
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(128).with_prefix_extractor(Arc::new(FixedPrefix(2)));
+    for key in [&b"aa1"[..], b"aa2", b"ab", b"c", b"dd1"] {
+        builder.add(key, b"value");
+    }
+    let path = dir.path().join("1.sst");
+    builder.build_for_test(&path).unwrap();
+    let sstable = SsTable::open_for_test(FileObject::open(&StdEnv, &path).unwrap()).unwrap();
+    let extractor = FixedPrefix(2);
+    for prefix in [&b"aa"[..], b"ab", b"dd"] {
+        assert!(sstable.may_contain_prefix(&extractor, prefix));
+    }
+    assert!(!sstable.may_contain_prefix(&extractor, b"bb"));
+    assert!(sstable.may_contain_prefix(&FixedPrefix(1), b"b"));
+}
+
+#[test]
+fn test_sst_encryption() {
+    use super::encryption::InMemoryKeyProvider;
+    use super::prefix::FixedPrefix;
+    let provider = InMemoryKeyProvider::new(7, [7; 32]);
+    let mut builder = SsTableBuilder::new(128)
+        .with_prefix_extractor(Arc::new(FixedPrefix(4)))
+        .with_cipher(BlockCipher::new(&provider).unwrap());
+    for idx in 0..num_of_keys() {
+        builder.add(&key_of(idx), &value_of(idx));
+    }
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let sst = builder.build_for_test(&path).unwrap();
+    assert_eq!(sst.key_id(), Some(7));
+
+    // Neither the keys nor the values are stored in plaintext.
+    let data = std::fs::read(&path).unwrap();
+    assert!(!data.windows(7).any(|window| window == b"key_005"));
+    assert!(!data.windows(16).any(|window| window == value_of(1).as_slice()));
+
+    let open = || FileObject::open(&StdEnv, &path).unwrap();
+    assert!(SsTable::open(1, None, open()).is_err());
+    let sst = SsTable::open_with_keys(1, None, open(), Some(&provider)).unwrap();
+    assert_eq!(sst.key_id(), Some(7));
+    assert_eq!(sst.properties().num_entries, num_of_keys() as u64);
+    assert!(sst.may_contain_prefix(&FixedPrefix(4), b"key_"));
+    let entries = SsTableIter::create(Arc::new(sst), Range::from(..)).unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(entries.len(), num_of_keys());
+    assert_eq!(entries[3], (key_of(3), value_of(3)));
+
+    // Tampering with a block is detected.
+    let mut data = data;
+    data[20] ^= 1;
+    std::fs::write(&path, data).unwrap();
+    let sst = SsTable::open_with_keys(1, None, open(), Some(&provider)).unwrap();
+    assert!(sst.read_block(0).is_err());
+}
+
+#[test]
+fn test_sst_blocks_in_range() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut builder = SsTableBuilder::new(128);
+    for i in 0..100_u32 {
+        builder.add(&i.to_be_bytes(), &[1; 20]);
+    }
+    let sstable = builder.build_for_test(dir.path().join("1.sst"))?;
+    let key = |i: u32| i.to_be_bytes().to_vec();
+    let all = sstable.blocks_in_range(&Range::from(..));
+    assert_eq!(all.len(), sstable.num_of_blocks());
+    assert_eq!(all.iter().map(|(_, size)| size).sum::<u64>(), sstable.data_size());
+
+    let some = sstable.blocks_in_range(&Range::from(key(30)..key(40)));
+    assert!(!some.is_empty() && some.len() < all.len());
+    assert!(some[0].0 <= key(30) && some.last().unwrap().0 < key(40));
+    assert!(sstable.blocks_in_range(&Range::from(key(100)..)).is_empty());
+    assert!(sstable.blocks_in_range(&Range::from(key(40)..key(30))).is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_sst_checksums() -> Result<()> {
+    let (dir, sst) = generate_sst();
+    let data = std::fs::read(dir.path().join("1.sst"))?;
+    let corrupt = |offset: usize| {
+        let mut data = data.clone();
+        data[offset] ^= 1;
+        SsTable::open_for_test(FileObject::create(&StdEnv, &dir.path().join("2.sst"), data)?)
+    };
+
+    // A corrupted block fails to be read, and so do the scans reading it.
+    let corrupted = corrupt(sst.block_metas()[1].offset + 1)?;
+    assert!(corrupted.read_block(0).is_ok());
+    assert!(corrupted.read_block(1).is_err());
+    assert!(SsTableIter::new(Arc::new(corrupted))?.collect::<Result<Vec<_>>>().is_err());
+    // So does a corrupted meta block.
+    assert!(corrupt(sst.data_size() as usize + 1).is_err());
+
+    // An SSTable without its checksum trailer is corrupted.
+    let truncated = data[..data.len() - CHECKSUM_TRAILER_LEN as usize].to_vec();
+    let truncated = FileObject::create(&StdEnv, &dir.path().join("3.sst"), truncated)?;
+    assert!(SsTable::open_for_test(truncated).is_err());
+    Ok(())
+}
@@ -290,6 +290,46 @@ fn test_storage_scan_inaccurate_range() {
     );
 }
 
+#[test]
+fn test_storage_prune_non_overlapping_sstables() {
+    use super::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.set(b"1", b"1".to_vec()).unwrap();
+    storage.set(b"3", b"3".to_vec()).unwrap();
+    storage.flush().unwrap();
+    storage.set(b"7", b"7".to_vec()).unwrap();
+    storage.set(b"9", b"9".to_vec()).unwrap();
+    storage.flush().unwrap();
+    let sstables = storage.l0_sstables_for_test();
+    assert_eq!(sstables.len(), 2);
+
+    check_iter_result(
+        storage
+            .scan(Range::from(b"0".to_vec()..b"5".to_vec()))
+            .unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("1")),
+            (Bytes::from("3"), Bytes::from("3")),
+        ],
+    );
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"3");
+    assert!(storage.get(b"5").unwrap().is_none());
+    assert!(sstables[0].num_block_reads() > 0);
+    assert_eq!(sstables[1].num_block_reads(), 0);
+
+    check_iter_result(
+        storage
+            .scan(Range::from(b"7".to_vec()..))
+            .unwrap(),
+        vec![
+            (Bytes::from("7"), Bytes::from("7")),
+            (Bytes::from("9"), Bytes::from("9")),
+        ],
+    );
+    assert!(sstables[1].num_block_reads() > 0);
+}
+
 #[cfg(test)]
 fn key_of(idx: usize) -> Vec<u8> {
     format!("key_{:04}", idx * 3).into_bytes()
@@ -327,4 +367,1232 @@ fn test_storage_concurrent_operation() {
     for i in 0..1000 {
         assert_eq!(&storage.get(&key_of(i)).unwrap().unwrap(), &value_of(i));
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_storage_reopen() {
+    use super::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.set(b"1", b"233".to_vec()).unwrap();
+    storage.set(b"2", b"2333".to_vec()).unwrap();
+    storage.flush().unwrap();
+    storage.delete(b"2").unwrap();
+    storage.set(b"3", b"23333".to_vec()).unwrap();
+    storage.flush().unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(
+        storage.scan(Range::from(..)).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("233")),
+            (Bytes::from("3"), Bytes::from("23333")),
+        ],
+    );
+    storage.set(b"4", b"233333".to_vec()).unwrap();
+    storage.flush().unwrap();
+    assert_eq!(storage.l0_sstables_for_test().len(), 3);
+}
+
+#[test]
+fn test_storage_ingest_external_files() {
+    use super::lsm_storage::LsmStorage;
+    use super::sst_file_writer::SstFileWriter;
+    let dir = tempdir().unwrap();
+    let external_dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.set(b"1", b"1".to_vec()).unwrap();
+    storage.set(b"2", b"2".to_vec()).unwrap();
+    storage.flush().unwrap();
+    storage.set(b"5", b"5".to_vec()).unwrap();
+
+    // Overlaps the L0 table.
+    let mut writer = SstFileWriter::create(external_dir.path().join("a.sst"));
+    writer.put(b"2", b"22").unwrap();
+    writer.put(b"3", b"33").unwrap();
+    writer.finish().unwrap();
+    // Overlaps the memtable.
+    let mut writer = SstFileWriter::create(external_dir.path().join("b.sst"));
+    writer.put(b"4", b"44").unwrap();
+    writer.put(b"5", b"55").unwrap();
+    writer.finish().unwrap();
+    // Overlaps nothing.
+    let mut writer = SstFileWriter::create(external_dir.path().join("c.sst"));
+    writer.put(b"7", b"77").unwrap();
+    writer.delete(b"8").unwrap();
+    writer.finish().unwrap();
+
+    storage.ingest_external_files(&[
+        external_dir.path().join("c.sst"),
+        external_dir.path().join("a.sst"),
+        external_dir.path().join("b.sst"),
+    ]).unwrap();
+    // The memtable is flushed; "a.sst" and "b.sst" go to L0 and "c.sst" to the bottommost level.
+    assert_eq!(storage.l0_sstables_for_test().len(), 4);
+    let expected = vec![
+        (Bytes::from("1"), Bytes::from("1")),
+        (Bytes::from("2"), Bytes::from("22")),
+        (Bytes::from("3"), Bytes::from("33")),
+        (Bytes::from("4"), Bytes::from("44")),
+        (Bytes::from("5"), Bytes::from("55")),
+        (Bytes::from("7"), Bytes::from("77")),
+    ];
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+    assert_eq!(&storage.get(b"5").unwrap().unwrap()[..], b"55");
+    assert!(storage.get(b"8").unwrap().is_none());
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected);
+    storage.set(b"7", b"777".to_vec()).unwrap();
+    storage.flush().unwrap();
+    assert_eq!(&storage.get(b"7").unwrap().unwrap()[..], b"777");
+}
+
+#[test]
+fn test_storage_ingest_invalid_files() {
+    use super::lsm_storage::LsmStorage;
+    use super::sst_file_writer::SstFileWriter;
+    let dir = tempdir().unwrap();
+    let external_dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+
+    let mut writer = SstFileWriter::create(external_dir.path().join("a.sst"));
+    writer.put(b"1", b"1").unwrap();
+    writer.put(b"3", b"3").unwrap();
+    writer.finish().unwrap();
+    let mut writer = SstFileWriter::create(external_dir.path().join("b.sst"));
+    writer.put(b"2", b"2").unwrap();
+    writer.finish().unwrap();
+    std::fs::write(external_dir.path().join("c.sst"), b"garbage").unwrap();
+
+    assert!(storage.ingest_external_files(&[
+        external_dir.path().join("a.sst"),
+        external_dir.path().join("b.sst"),
+    ]).is_err());
+    assert!(storage.ingest_external_files(&[external_dir.path().join("c.sst")]).is_err());
+    assert!(storage.ingest_external_files(&[external_dir.path().join("d.sst")]).is_err());
+    assert!(storage.scan(Range::from(..)).unwrap().next().is_none());
+}
+
+#[test]
+fn test_storage_checkpoint() {
+    use super::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let checkpoint_dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.set(b"1", b"1".to_vec()).unwrap();
+    storage.set(b"2", b"2".to_vec()).unwrap();
+    storage.flush().unwrap();
+    storage.delete(b"1").unwrap();
+    storage.set(b"3", b"3".to_vec()).unwrap();
+
+    let checkpoint_path = checkpoint_dir.path().join("checkpoint");
+    storage.checkpoint(&checkpoint_path).unwrap();
+    assert!(storage.checkpoint(&checkpoint_path).is_err());
+    storage.set(b"4", b"4".to_vec()).unwrap();
+    storage.flush().unwrap();
+
+    let checkpoint = LsmStorage::open(&checkpoint_path).unwrap();
+    check_iter_result(
+        checkpoint.scan(Range::from(..)).unwrap(),
+        vec![
+            (Bytes::from("2"), Bytes::from("2")),
+            (Bytes::from("3"), Bytes::from("3")),
+        ],
+    );
+    checkpoint.set(b"5", b"5".to_vec()).unwrap();
+    checkpoint.flush().unwrap();
+    assert!(storage.get(b"5").unwrap().is_none());
+    assert_eq!(&storage.get(b"4").unwrap().unwrap()[..], b"4");
+}
+
+#[test]
+fn test_storage_incremental_backup() {
+    use super::backup::BackupEngine;
+    use super::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let backup_dir = tempdir().unwrap();
+    let restore_dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let engine = BackupEngine::open(backup_dir.path()).unwrap();
+    let num_shared = || std::fs::read_dir(backup_dir.path().join("shared")).unwrap().count();
+
+    storage.set(b"1", b"1".to_vec()).unwrap();
+    storage.flush().unwrap();
+    storage.set(b"2", b"2".to_vec()).unwrap();
+    assert_eq!(engine.create_backup(&storage).unwrap(), 1);
+    assert_eq!(num_shared(), 2);
+
+    storage.set(b"3", b"3".to_vec()).unwrap();
+    assert_eq!(engine.create_backup(&storage).unwrap(), 2);
+    assert_eq!(num_shared(), 3);
+    assert_eq!(engine.list_backups().unwrap(), vec![1, 2]);
+
+    engine.restore_backup(1, restore_dir.path().join("1")).unwrap();
+    engine.restore_backup(2, restore_dir.path().join("2")).unwrap();
+    assert!(engine.restore_backup(3, restore_dir.path().join("3")).is_err());
+    let restored = LsmStorage::open(restore_dir.path().join("1")).unwrap();
+    check_iter_result(
+        restored.scan(Range::from(..)).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("1")),
+            (Bytes::from("2"), Bytes::from("2")),
+        ],
+    );
+    let restored = LsmStorage::open(restore_dir.path().join("2")).unwrap();
+    check_iter_result(
+        restored.scan(Range::from(..)).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("1")),
+            (Bytes::from("2"), Bytes::from("2")),
+            (Bytes::from("3"), Bytes::from("3")),
+        ],
+    );
+}
+
+#[test]
+fn test_storage_backup_column_families() {
+    use super::backup::BackupEngine;
+    let dir = tempdir().unwrap();
+    let backup_dir = tempdir().unwrap();
+    let restore_dir = tempdir().unwrap();
+    let storage = open_column_family_storage(&dir);
+    let engine = BackupEngine::open(backup_dir.path()).unwrap();
+    storage.set(b"1", b"default".to_vec()).unwrap();
+    storage.column_family("meta").unwrap().set(b"1", b"meta".to_vec()).unwrap();
+    storage.column_family("blobs").unwrap().set(b"1", vec![1; 10000]).unwrap();
+    let backup_id = engine.create_backup(&storage).unwrap();
+    drop(storage);
+
+    // Every column family is restored, blob files included.
+    engine.restore_backup(backup_id, restore_dir.path()).unwrap();
+    let restored = open_column_family_storage(&restore_dir);
+    assert_eq!(restored.get(b"1").unwrap().unwrap(), b"default");
+    let meta = restored.column_family("meta").unwrap();
+    assert_eq!(meta.get(b"1").unwrap().unwrap(), b"meta");
+    let blobs = restored.column_family("blobs").unwrap();
+    assert_eq!(blobs.get(b"1").unwrap().unwrap(), vec![1; 10000]);
+}
+
+#[cfg(test)]
+fn open_blob_storage(dir: &tempfile::TempDir) -> super::lsm_storage::LsmStorage {
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+    LsmStorage::open_with_options(
+        dir,
+        LsmStorageOptions { blob_threshold: Some(100), ..LsmStorageOptions::default() },
+    ).unwrap()
+}
+
+#[test]
+fn test_storage_blob_values() {
+    let dir = tempdir().unwrap();
+    let storage = open_blob_storage(&dir);
+    let large_value = |idx: u8| vec![idx; 10000];
+    storage.set(b"1", b"small".to_vec()).unwrap();
+    storage.set(b"2", large_value(2)).unwrap();
+    storage.set(b"3", large_value(3)).unwrap();
+    storage.flush().unwrap();
+    assert!(dir.path().join("00001.blob").exists());
+    assert!(std::fs::metadata(dir.path().join("00001.sst")).unwrap().len() < 1000);
+    storage.delete(b"3").unwrap();
+    storage.set(b"4", large_value(4)).unwrap();
+
+    let expected = vec![
+        (Bytes::from("1"), Bytes::from("small")),
+        (Bytes::from("2"), Bytes::from(large_value(2))),
+        (Bytes::from("4"), Bytes::from(large_value(4))),
+    ];
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+    assert_eq!(storage.get(b"2").unwrap().unwrap(), large_value(2));
+    assert!(storage.get(b"3").unwrap().is_none());
+    storage.flush().unwrap();
+    drop(storage);
+
+    let storage = open_blob_storage(&dir);
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+    let mut reversed = expected;
+    reversed.reverse();
+    check_iter_result(Box::new(storage.scan(Range::from(..)).unwrap().rev()), reversed);
+}
+
+#[test]
+fn test_storage_blob_gc() {
+    let dir = tempdir().unwrap();
+    let storage = open_blob_storage(&dir);
+    let large_value = |idx: u8| vec![idx; 10000];
+    for idx in 0..4 {
+        storage.set(&[b'0' + idx], large_value(idx)).unwrap();
+    }
+    storage.flush().unwrap();
+    assert_eq!(storage.gc_blob_files(0.5).unwrap(), 0);
+
+    // Overwrite half of the blob values.
+    storage.set(b"0", b"small".to_vec()).unwrap();
+    storage.delete(b"1").unwrap();
+    assert_eq!(storage.gc_blob_files(0.6).unwrap(), 0);
+    assert_eq!(storage.gc_blob_files(0.5).unwrap(), 1);
+    assert!(!dir.path().join("00001.blob").exists());
+    // The live values are copied, followed by the trailer.
+    assert_eq!(std::fs::metadata(dir.path().join("00002.blob")).unwrap().len(), 20000 + 21);
+
+    let expected = vec![
+        (Bytes::from("0"), Bytes::from("small")),
+        (Bytes::from("2"), Bytes::from(large_value(2))),
+        (Bytes::from("3"), Bytes::from(large_value(3))),
+    ];
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+    storage.flush().unwrap();
+    drop(storage);
+
+    let storage = open_blob_storage(&dir);
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected);
+    assert_eq!(storage.get(b"3").unwrap().unwrap(), large_value(3));
+}
+
+#[test]
+fn test_storage_blob_compaction() {
+    let dir = tempdir().unwrap();
+    let storage = open_blob_storage(&dir);
+    let large_value = |idx: u8| vec![idx; 10000];
+    for idx in 0..4 {
+        storage.set(&[b'0' + idx], large_value(idx)).unwrap();
+    }
+    storage.flush().unwrap();
+    storage.set(b"0", large_value(9)).unwrap();
+    storage.delete(b"1").unwrap();
+    storage.flush().unwrap();
+    let blob_files = || {
+        let mut files = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".blob"))
+            .map(|name| (std::fs::metadata(dir.path().join(&name)).unwrap().len(), name))
+            .collect::<Vec<_>>();
+        files.sort_by(|(_, a), (_, b)| a.cmp(b));
+        files
+    };
+    let before = blob_files();
+    assert_eq!(before.len(), 2);
+
+    // Compaction carries the blob pointers over, leaving the blob files untouched.
+    storage.compact_range(Range::from(..)).unwrap();
+    assert_eq!(storage.levels_for_test()[5].len(), 1);
+    assert_eq!(blob_files(), before);
+    let expected = vec![
+        (Bytes::from("0"), Bytes::from(large_value(9))),
+        (Bytes::from("2"), Bytes::from(large_value(2))),
+        (Bytes::from("3"), Bytes::from(large_value(3))),
+    ];
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+
+    // The values of the entries the compaction dropped are reclaimed by blob GC.
+    assert_eq!(storage.gc_blob_files(0.5).unwrap(), 1);
+    assert!(!dir.path().join("00001.blob").exists());
+    assert_eq!(blob_files()[0], before[1]);
+    assert_eq!(blob_files()[1], (20000 + 21, "00004.blob".to_string()));
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+    drop(storage);
+
+    let storage = open_blob_storage(&dir);
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected);
+    assert_eq!(storage.gc_blob_files(0.5).unwrap(), 0);
+}
+
+#[test]
+fn test_storage_wal_recovery() {
+    use super::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.set(b"1", b"233".to_vec()).unwrap();
+    storage.set(b"2", b"2333".to_vec()).unwrap();
+    storage.flush().unwrap();
+    storage.delete(b"1").unwrap();
+    storage.set(b"3", b"23333".to_vec()).unwrap();
+    drop(storage);
+
+    // Writes not flushed before the shutdown are replayed from the WAL.
+    let storage = LsmStorage::open(&dir).unwrap();
+    let expected = vec![
+        (Bytes::from("2"), Bytes::from("2333")),
+        (Bytes::from("3"), Bytes::from("23333")),
+    ];
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+    storage.flush().unwrap();
+    assert_eq!(storage.l0_sstables_for_test().len(), 2);
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected);
+    assert_eq!(storage.l0_sstables_for_test().len(), 2);
+}
+
+#[cfg(test)]
+fn open_column_family_storage(dir: &tempfile::TempDir) -> super::lsm_storage::LsmStorage {
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+    LsmStorage::open_with_column_families(
+        dir,
+        LsmStorageOptions::default(),
+        &[
+            ("meta", LsmStorageOptions::default()),
+            ("blobs", LsmStorageOptions { blob_threshold: Some(100), ..LsmStorageOptions::default() }),
+        ],
+    ).unwrap()
+}
+
+#[test]
+fn test_storage_column_families() {
+    let dir = tempdir().unwrap();
+    let storage = open_column_family_storage(&dir);
+    let meta = storage.column_family("meta").unwrap();
+    let blobs = storage.column_family("blobs").unwrap();
+    assert!(storage.column_family("missing").is_err());
+
+    // Column families are independent keyspaces.
+    storage.set(b"1", b"default".to_vec()).unwrap();
+    meta.set(b"1", b"meta".to_vec()).unwrap();
+    blobs.set(b"1", vec![1; 10000]).unwrap();
+    meta.delete(b"1").unwrap();
+    assert_eq!(storage.get(b"1").unwrap().unwrap(), b"default");
+    assert!(meta.get(b"1").unwrap().is_none());
+    assert_eq!(blobs.get(b"1").unwrap().unwrap(), vec![1; 10000]);
+
+    // Flushing one column family leaves the others in their memtables, with per-family options.
+    blobs.flush().unwrap();
+    assert!(dir.path().join("column_families/blobs/00001.blob").exists());
+    assert!(!dir.path().join("00001.sst").exists());
+    drop((meta, blobs, storage));
+
+    let storage = open_column_family_storage(&dir);
+    assert_eq!(storage.get(b"1").unwrap().unwrap(), b"default");
+    assert!(storage.column_family("meta").unwrap().get(b"1").unwrap().is_none());
+    assert_eq!(storage.column_family("blobs").unwrap().get(b"1").unwrap().unwrap(), vec![1; 10000]);
+    drop(storage);
+
+    // Column families with writes in the WAL must be opened.
+    assert!(super::lsm_storage::LsmStorage::open(&dir).is_err());
+}
+
+#[test]
+fn test_storage_checkpoint_write_batches() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::lsm_storage::WriteBatch;
+    let dir = tempdir().unwrap();
+    let storage = open_column_family_storage(&dir);
+    let checkpoint_dirs = (0..5).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+    let stop = AtomicBool::new(false);
+
+    // Batches writing to two column families land in a checkpoint together, or not at all.
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut i = 0;
+            while !stop.load(Ordering::Relaxed) {
+                let mut batch = WriteBatch::new();
+                batch.set("default", &key_of(i), value_of(i));
+                batch.set("meta", &key_of(i), value_of(i));
+                storage.write(batch).unwrap();
+                i += 1;
+            }
+        });
+        for checkpoint_dir in checkpoint_dirs.iter() {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            storage.checkpoint(checkpoint_dir.path().join("checkpoint")).unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+    });
+    for checkpoint_dir in checkpoint_dirs.iter() {
+        let checkpoint = super::lsm_storage::LsmStorage::open_with_column_families(
+            checkpoint_dir.path().join("checkpoint"),
+            super::lsm_storage::LsmStorageOptions::default(),
+            &[("meta", super::lsm_storage::LsmStorageOptions::default())],
+        ).unwrap();
+        let default = checkpoint.scan(Range::from(..)).unwrap().collect::<Vec<_>>();
+        let meta = checkpoint.column_family("meta").unwrap()
+            .scan(Range::from(..)).unwrap()
+            .collect::<Vec<_>>();
+        assert!(!default.is_empty());
+        assert_eq!(default.len(), meta.len());
+        for (default, meta) in default.into_iter().zip(meta) {
+            assert_eq!(default.unwrap(), meta.unwrap());
+        }
+    }
+}
+
+#[test]
+fn test_storage_write_batch() {
+    use super::lsm_storage::WriteBatch;
+    let dir = tempdir().unwrap();
+    let storage = open_column_family_storage(&dir);
+    let mut batch = WriteBatch::new();
+    batch.set("default", b"1", b"233".to_vec());
+    batch.set("meta", b"1", b"2333".to_vec());
+    batch.delete("meta", b"2");
+    storage.write(batch).unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.set("meta", b"3", b"23333".to_vec());
+    batch.set("missing", b"3", b"23333".to_vec());
+    assert!(storage.write(batch).is_err());
+    assert!(storage.column_family("meta").unwrap().get(b"3").unwrap().is_none());
+    drop(storage);
+
+    let storage = open_column_family_storage(&dir);
+    let meta = storage.column_family("meta").unwrap();
+    assert_eq!(storage.get(b"1").unwrap().unwrap(), b"233");
+    check_iter_result(
+        meta.scan(Range::from(..)).unwrap(),
+        vec![(Bytes::from("1"), Bytes::from("2333"))],
+    );
+
+    // WAL segments are deleted once every column family has flushed them.
+    let wal_segments = || std::fs::read_dir(dir.path()).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".wal"))
+        .count();
+    storage.flush().unwrap();
+    assert!(wal_segments() > 1);
+    meta.flush().unwrap();
+    assert_eq!(wal_segments(), 1);
+
+    let checkpoint_dir = tempdir().unwrap();
+    std::fs::remove_dir(checkpoint_dir.path()).unwrap();
+    storage.checkpoint(checkpoint_dir.path()).unwrap();
+    drop((meta, storage));
+    let storage = open_column_family_storage(&checkpoint_dir);
+    assert_eq!(storage.column_family("meta").unwrap().get(b"1").unwrap().unwrap(), b"2333");
+}
+
+#[test]
+fn test_storage_prefix_filter() {
+    use std::sync::Arc;
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+    use super::prefix::FixedPrefix;
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        prefix_extractor: Some(Arc::new(FixedPrefix(4))),
+        ..LsmStorageOptions::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    storage.set(b"aaaa_1", b"1".to_vec()).unwrap();
+    storage.set(b"cccc_1", b"1".to_vec()).unwrap();
+    storage.flush().unwrap();
+    storage.set(b"bbbb_1", b"2".to_vec()).unwrap();
+    storage.set(b"bbbb_2", b"2".to_vec()).unwrap();
+    storage.flush().unwrap();
+    drop(storage);
+
+    // The first table covers the key range of prefix `bbbb`, but its filter rules it out.
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let sstables = storage.l0_sstables_for_test();
+    check_iter_result(
+        storage.scan_prefix(b"bbbb").unwrap(),
+        vec![
+            (Bytes::from("bbbb_1"), Bytes::from("2")),
+            (Bytes::from("bbbb_2"), Bytes::from("2")),
+        ],
+    );
+    assert_eq!(sstables[0].num_block_reads(), 0);
+    assert_eq!(sstables[1].num_block_reads(), 1);
+
+    // Prefixes shorter than the extracted one cannot use the filters.
+    check_iter_result(
+        storage.scan_prefix(b"bb").unwrap(),
+        vec![
+            (Bytes::from("bbbb_1"), Bytes::from("2")),
+            (Bytes::from("bbbb_2"), Bytes::from("2")),
+        ],
+    );
+    assert_eq!(sstables[0].num_block_reads(), 1);
+    drop(storage);
+
+    // Filters built by another extractor are ignored.
+    let storage = LsmStorage::open_with_options(&dir, LsmStorageOptions {
+        prefix_extractor: Some(Arc::new(FixedPrefix(3))),
+        ..LsmStorageOptions::default()
+    }).unwrap();
+    let sstables = storage.l0_sstables_for_test();
+    assert_eq!(storage.scan_prefix(b"bbbb").unwrap().count(), 2);
+    assert_eq!(sstables[0].num_block_reads(), 1);
+}
+
+#[test]
+fn test_storage_read_only() {
+    use crate::error::Error;
+    use super::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    assert!(LsmStorage::open_read_only(dir.path().join("missing")).is_err());
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.set(b"1", b"233".to_vec()).unwrap();
+    storage.flush().unwrap();
+    storage.set(b"2", b"2333".to_vec()).unwrap();
+
+    let list_dir = || {
+        let mut files = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    let files = list_dir();
+    let read_only = LsmStorage::open_read_only(&dir).unwrap();
+    check_iter_result(
+        read_only.scan(Range::from(..)).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("233")),
+            (Bytes::from("2"), Bytes::from("2333")),
+        ],
+    );
+    assert_eq!(read_only.set(b"3", b"23333".to_vec()), Err(Error::ReadOnly));
+    assert_eq!(read_only.delete(b"1"), Err(Error::ReadOnly));
+    assert_eq!(read_only.flush(), Err(Error::ReadOnly));
+    assert!(read_only.catch_up().is_err());
+    assert_eq!(list_dir(), files);
+}
+
+#[test]
+fn test_storage_secondary() {
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default;
+    let primary = LsmStorage::open_with_column_families(
+        &dir, options(), &[("meta", options())]
+    ).unwrap();
+    primary.set(b"1", b"233".to_vec()).unwrap();
+    primary.flush().unwrap();
+
+    let secondary = LsmStorage::open_as_secondary(&dir, options(), &[("meta", options())]).unwrap();
+    let secondary_meta = secondary.column_family("meta").unwrap();
+    assert_eq!(secondary.get(b"1").unwrap().unwrap(), b"233");
+
+    primary.set(b"2", b"2333".to_vec()).unwrap();
+    primary.delete(b"1").unwrap();
+    primary.column_family("meta").unwrap().set(b"1", b"meta".to_vec()).unwrap();
+    assert_eq!(secondary.get(b"1").unwrap().unwrap(), b"233");
+    assert!(secondary_meta.get(b"1").unwrap().is_none());
+    secondary.catch_up().unwrap();
+    assert!(secondary.get(b"1").unwrap().is_none());
+    assert_eq!(secondary.get(b"2").unwrap().unwrap(), b"2333");
+    assert_eq!(secondary_meta.get(b"1").unwrap().unwrap(), b"meta");
+
+    // Flushes delete WAL segments, which the manifests cover by then.
+    primary.flush().unwrap();
+    primary.column_family("meta").unwrap().flush().unwrap();
+    primary.set(b"3", b"23333".to_vec()).unwrap();
+    secondary.catch_up().unwrap();
+    check_iter_result(
+        secondary.scan(Range::from(..)).unwrap(),
+        vec![
+            (Bytes::from("2"), Bytes::from("2333")),
+            (Bytes::from("3"), Bytes::from("23333")),
+        ],
+    );
+    assert_eq!(secondary.l0_sstables_for_test().len(), 2);
+    assert_eq!(secondary_meta.get(b"1").unwrap().unwrap(), b"meta");
+}
+
+/// An `LsmStorage` on a `FaultInjectionEnv` that simulates a crash and reopens itself after
+/// every few writes, and flushes every few dozen. Writes are synced, so every acknowledged write
+/// must survive the crashes.
+#[cfg(test)]
+struct CrashingStorage {
+    env: super::env::FaultInjectionEnv,
+    storage: parking_lot::RwLock<super::lsm_storage::LsmStorage>,
+    writes: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl CrashingStorage {
+    const CRASH_INTERVAL: usize = 3;
+    const FLUSH_INTERVAL: usize = 64;
+
+    fn open(
+        env: &super::env::FaultInjectionEnv,
+    ) -> crate::error::Result<super::lsm_storage::LsmStorage> {
+        use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+        let options = LsmStorageOptions {
+            sync_writes: true,
+            env: std::sync::Arc::new(env.clone()),
+            ..LsmStorageOptions::default()
+        };
+        LsmStorage::open_with_options("/db", options)
+    }
+
+    fn after_write(&self) -> crate::error::Result<()> {
+        let writes = self.writes.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+        if writes % Self::FLUSH_INTERVAL == 0 {
+            self.storage.read().flush()?;
+        }
+        if writes % Self::CRASH_INTERVAL == 0 {
+            let mut storage = self.storage.write();
+            self.env.crash();
+            *storage = Self::open(&self.env)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl std::fmt::Display for CrashingStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CrashingStorage({})", self.storage.read())
+    }
+}
+
+#[cfg(test)]
+impl KvStore for CrashingStorage {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> crate::error::Result<()> {
+        self.storage.read().set(key, value)?;
+        self.after_write()
+    }
+
+    fn get(&self, key: &[u8]) -> crate::error::Result<Option<Vec<u8>>> {
+        self.storage.read().get(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> crate::error::Result<()> {
+        self.storage.read().delete(key)?;
+        self.after_write()
+    }
+
+    fn scan(&self, range: Range) -> crate::error::Result<KvScan> {
+        self.storage.read().scan(range)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> crate::error::Result<KvScan> {
+        self.storage.read().scan_prefix(prefix)
+    }
+
+    fn flush(&self) -> crate::error::Result<()> {
+        self.storage.read().flush()
+    }
+}
+
+#[cfg(test)]
+impl crate::storage::kv::TestSuite<CrashingStorage> for CrashingStorage {
+    fn setup() -> crate::error::Result<Self> {
+        let env = super::env::FaultInjectionEnv::new();
+        let storage = parking_lot::RwLock::new(Self::open(&env)?);
+        Ok(Self { env, storage, writes: std::sync::atomic::AtomicUsize::new(0) })
+    }
+}
+
+#[test]
+fn test_storage_crash_recovery_suite() -> crate::error::Result<()> {
+    use crate::storage::kv::TestSuite;
+    CrashingStorage::test()
+}
+
+#[test]
+fn test_storage_crash_recovery_torn_writes() {
+    use std::collections::BTreeMap;
+    use rand::{Rng, SeedableRng};
+    use super::env::FaultInjectionEnv;
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+
+    let env = FaultInjectionEnv::new();
+    let open = || LsmStorage::open_with_options("/db", LsmStorageOptions {
+        env: std::sync::Arc::new(env.clone()),
+        ..LsmStorageOptions::default()
+    }).unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(2333);
+    let mut model = BTreeMap::new();
+    for _ in 0..20 {
+        // Unsynced writes may be lost, so every state since the last flush is a valid outcome.
+        let storage = open();
+        let mut states = vec![model.clone()];
+        for _ in 0..rng.gen_range(1..200) {
+            let key = key_of(rng.gen_range(0..100));
+            match rng.gen_bool(0.8) {
+                true => {
+                    let value = value_of(rng.gen_range(0..1000));
+                    storage.set(&key, value.clone()).unwrap();
+                    model.insert(key, value);
+                }
+                false => {
+                    storage.delete(&key).unwrap();
+                    model.remove(&key);
+                }
+            }
+            if rng.gen_bool(0.02) {
+                storage.flush().unwrap();
+                states.clear();
+            }
+            states.push(model.clone());
+        }
+        env.crash_torn(&mut rng);
+        drop(storage);
+
+        // Recovery must restore one of the states, keeping the writes before it and none after.
+        let storage = open();
+        let recovered = storage.scan(Range::from(..)).unwrap()
+            .collect::<crate::error::Result<BTreeMap<_, _>>>().unwrap();
+        assert!(states.contains(&recovered), "recovered an inconsistent state");
+        model = recovered;
+    }
+}
+
+#[test]
+fn test_storage_crash_recovery_torn_manifest() {
+    use rand::SeedableRng;
+    use super::env::FaultInjectionEnv;
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+
+    let env = FaultInjectionEnv::new();
+    let open = || LsmStorage::open_with_options("/db", LsmStorageOptions {
+        sync_writes: true,
+        env: std::sync::Arc::new(env.clone()),
+        ..LsmStorageOptions::default()
+    }).unwrap();
+    let manifest_path = std::path::Path::new("/db/MANIFEST");
+    let mut rng = rand::rngs::StdRng::seed_from_u64(2333);
+    for round in 0..10 {
+        // The record of the flush is appended to the manifest but never synced, so the crash
+        // keeps a random part of it.
+        let storage = open();
+        storage.set(&key_of(round * 2), value_of(round * 2)).unwrap();
+        env.set_fail_syncs(Some(manifest_path));
+        assert!(storage.flush().is_err());
+        env.set_fail_syncs(None);
+        env.crash_torn(&mut rng);
+        drop(storage);
+
+        // Records appended after recovering from the torn one must be readable by the next open.
+        let storage = open();
+        storage.set(&key_of(round * 2 + 1), value_of(round * 2 + 1)).unwrap();
+        storage.flush().unwrap();
+        env.crash();
+        drop(storage);
+        let storage = open();
+        for i in 0..round * 2 + 2 {
+            assert_eq!(storage.get(&key_of(i)).unwrap(), Some(value_of(i)), "key {}", i);
+        }
+    }
+}
+
+#[test]
+fn test_storage_crash_recovery_faults() {
+    use super::env::{Env, FaultInjectionEnv};
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+
+    let env = FaultInjectionEnv::new();
+    let open = || LsmStorage::open_with_options("/db", LsmStorageOptions {
+        sync_writes: true,
+        env: std::sync::Arc::new(env.clone()),
+        ..LsmStorageOptions::default()
+    }).unwrap();
+    let storage = open();
+    storage.set(b"1", b"1".to_vec()).unwrap();
+    storage.flush().unwrap();
+    storage.set(b"2", b"2".to_vec()).unwrap();
+
+    // Failed writes are not applied, and acknowledged ones survive the failure.
+    env.set_fail_writes(true);
+    assert!(storage.set(b"3", b"3".to_vec()).is_err());
+    assert!(storage.get(b"3").unwrap().is_none());
+    assert!(storage.flush().is_err());
+    env.set_fail_writes(false);
+    env.crash();
+    drop(storage);
+    let storage = open();
+    let expected = vec![
+        (Bytes::from("1"), Bytes::from("1")),
+        (Bytes::from("2"), Bytes::from("2")),
+    ];
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected);
+
+    // A corrupted batch length in the WAL reads as a torn batch, which is dropped.
+    storage.set(b"3", b"3".to_vec()).unwrap();
+    storage.set(b"4", b"4".to_vec()).unwrap();
+    drop(storage);
+    let segment = env.list_dir(std::path::Path::new("/db")).unwrap().into_iter()
+        .filter(|name| name.ends_with(".wal"))
+        .max()
+        .unwrap();
+    let segment_path = std::path::Path::new("/db").join(segment);
+    let batch_len = env.read_file(&segment_path).unwrap().len() / 2;
+    env.corrupt(&segment_path, batch_len).unwrap();
+    let storage = open();
+    assert_eq!(storage.get(b"3").unwrap().unwrap(), b"3");
+    assert!(storage.get(b"4").unwrap().is_none());
+}
+
+#[test]
+fn test_storage_rate_limiter() {
+    use std::sync::Arc;
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+    use super::rate_limiter::{AutoTune, RateLimiter};
+    let dir = tempdir().unwrap();
+    let limiter = Arc::new(RateLimiter::new_auto_tuned(AutoTune {
+        min_rate: 1 << 20, max_rate: 1 << 30, max_debt: 1 << 20
+    }));
+    let storage = LsmStorage::open_with_options(&dir, LsmStorageOptions {
+        rate_limiter: Some(limiter.clone()),
+        ..LsmStorageOptions::default()
+    }).unwrap();
+
+    // Flushing 300 KB at 1 MB/s is throttled.
+    for i in 0..300 {
+        storage.set(&key_of(i), vec![0; 1000]).unwrap();
+    }
+    storage.flush().unwrap();
+    let stats = limiter.stats();
+    assert!(stats.total_bytes >= 300_000);
+    assert!(!stats.throttled.is_zero());
+
+    // The rate grows with the L0 SsTables waiting to be compacted.
+    let debt = storage.l0_sstables_for_test()[0].table_size();
+    assert_eq!(limiter.rate(), (1 << 20) + ((1 << 30) - (1 << 20)) * debt / (1 << 20));
+
+    // Foreground writes are not limited.
+    let total_bytes = limiter.stats().total_bytes;
+    storage.set(b"1", b"1".to_vec()).unwrap();
+    assert_eq!(limiter.stats().total_bytes, total_bytes);
+}
+
+#[test]
+fn test_storage_l0_compaction() {
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+    let dir = tempdir().unwrap();
+    let options = || LsmStorageOptions {
+        l0_compaction_trigger: Some(4),
+        ..LsmStorageOptions::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    for round in 0..3 {
+        for i in 0..100 {
+            storage.set(&key_of(i), value_of(i + round)).unwrap();
+        }
+        storage.flush().unwrap();
+    }
+    for i in 0..50 {
+        storage.delete(&key_of(i)).unwrap();
+    }
+    assert_eq!(storage.l0_sstables_for_test().len(), 3);
+
+    // The fourth flush compacts L0 into L1, dropping tombstones as nothing lies below.
+    storage.flush().unwrap();
+    assert!(storage.l0_sstables_for_test().is_empty());
+    let l1 = &storage.levels_for_test()[0];
+    assert_eq!(l1.len(), 1);
+    assert_eq!(l1[0].properties().num_entries, 50);
+    assert_eq!(l1[0].properties().num_tombstones, 0);
+    let expected = (50..100)
+        .map(|i| (Bytes::from(key_of(i)), Bytes::from(value_of(i + 2))))
+        .collect::<Vec<_>>();
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+
+    // Compacted SsTables are deleted, and the new layout survives reopening.
+    assert!(!dir.path().join("00001.sst").exists());
+    drop(storage);
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    assert_eq!(storage.levels_for_test()[0].len(), 1);
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected);
+}
+
+#[test]
+fn test_storage_subcompactions() {
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+    let dir = tempdir().unwrap();
+    let options = || LsmStorageOptions {
+        blob_threshold: Some(500),
+        target_file_size: 8 << 10,
+        l0_compaction_trigger: Some(4),
+        max_subcompactions: 4,
+        ..LsmStorageOptions::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    for round in 0..4 {
+        for i in (round..1000).step_by(4) {
+            let value = match i % 10 {
+                0 => vec![i as u8; 1000],
+                _ => value_of(i),
+            };
+            storage.set(&key_of(i), value).unwrap();
+        }
+        storage.flush().unwrap();
+    }
+
+    // The key range was split between subcompactions, each writing several SsTables.
+    assert!(storage.l0_sstables_for_test().is_empty());
+    let l1 = storage.levels_for_test()[0].clone();
+    assert!(l1.len() >= 4, "{} SsTables in L1", l1.len());
+    for pair in l1.windows(2) {
+        assert!(pair[0].properties().last_key < pair[1].properties().first_key);
+    }
+    let expected = (0..1000)
+        .map(|i| match i % 10 {
+            0 => (Bytes::from(key_of(i)), Bytes::from(vec![i as u8; 1000])),
+            _ => (Bytes::from(key_of(i)), Bytes::from(value_of(i))),
+        })
+        .collect::<Vec<_>>();
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+    drop(storage);
+
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    assert_eq!(storage.levels_for_test()[0].len(), l1.len());
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected);
+}
+
+#[test]
+fn test_storage_compact_range() {
+    use super::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for round in 0..5 {
+        for i in 0..100 {
+            storage.set(&key_of(i), value_of(i + round)).unwrap();
+        }
+        storage.flush().unwrap();
+    }
+    for i in 20..60 {
+        storage.delete(&key_of(i)).unwrap();
+    }
+    assert_eq!(storage.l0_sstables_for_test().len(), 5);
+    assert!(storage.levels_for_test()[0].is_empty());
+
+    // Every SsTable overlaps the deleted range, so all of them end up in the bottommost level.
+    storage.compact_range(Range::from(key_of(20)..key_of(60))).unwrap();
+    assert!(storage.l0_sstables_for_test().is_empty());
+    let levels = storage.levels_for_test();
+    assert!(levels[..5].iter().all(|level| level.is_empty()));
+    assert_eq!(levels[5].len(), 1);
+    assert_eq!(levels[5][0].properties().num_tombstones, 0);
+    assert_eq!(levels[5][0].properties().num_entries, 60);
+    let expected = (0..20).chain(60..100)
+        .map(|i| (Bytes::from(key_of(i)), Bytes::from(value_of(i + 4))))
+        .collect::<Vec<_>>();
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+
+    // Ranges without SsTables compact nothing.
+    storage.compact_range(Range::from(b"zzz".to_vec()..)).unwrap();
+    assert_eq!(storage.levels_for_test()[5].len(), 1);
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected);
+    storage.delete(&key_of(0)).unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(storage.compact_range_async(Range::from(..))).unwrap();
+    assert_eq!(storage.levels_for_test()[5][0].properties().num_entries, 59);
+    assert!(storage.get(&key_of(0)).unwrap().is_none());
+}
+
+#[test]
+fn test_storage_encryption_key_rotation() {
+    use std::sync::Arc;
+    use super::encryption::InMemoryKeyProvider;
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+    let dir = tempdir().unwrap();
+    let provider = Arc::new(InMemoryKeyProvider::new(1, [1; 32]));
+    let options = || LsmStorageOptions {
+        blob_threshold: Some(20),
+        key_provider: Some(provider.clone()),
+        ..LsmStorageOptions::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    for i in 0..100 {
+        storage.set(&key_of(i), value_of(i).repeat(3)).unwrap();
+    }
+    storage.flush().unwrap();
+    assert_eq!(storage.l0_sstables_for_test()[0].key_id(), Some(1));
+
+    // No file of the storage holds plaintext.
+    let needle = value_of(7);
+    for entry in walkdir(dir.path()) {
+        let data = std::fs::read(&entry).unwrap();
+        assert!(!data.windows(needle.len()).any(|window| window == needle), "{:?}", entry);
+    }
+
+    // Compaction rewrites the SsTables with the current key, and blob garbage collection the
+    // blob files, after which the old key can go.
+    provider.rotate(2, [2; 32]);
+    storage.set(&key_of(100), value_of(100)).unwrap();
+    storage.compact_range(Range::from(..)).unwrap();
+    let levels = storage.levels_for_test();
+    assert!(levels.iter().flatten().all(|sstable| sstable.key_id() == Some(2)));
+    assert_eq!(storage.gc_blob_files(1.0).unwrap(), 1);
+    provider.remove(1).unwrap();
+    let expected = (0..100)
+        .map(|i| (Bytes::from(key_of(i)), Bytes::from(value_of(i).repeat(3))))
+        .chain([(Bytes::from(key_of(100)), Bytes::from(value_of(100)))])
+        .collect::<Vec<_>>();
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected.clone());
+    drop(storage);
+
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    check_iter_result(storage.scan(Range::from(..)).unwrap(), expected);
+    drop(storage);
+    let storage = LsmStorage::open(&dir);
+    assert!(storage.is_err());
+}
+
+#[cfg(test)]
+fn walkdir(path: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(path).unwrap() {
+        let path = entry.unwrap().path();
+        match path.is_dir() {
+            true => files.extend(walkdir(&path)),
+            false => files.push(path),
+        }
+    }
+    files
+}
+
+#[test]
+fn test_storage_approximate_sizes() {
+    let dir = tempdir().unwrap();
+    let storage = open_blob_storage(&dir);
+    for i in 0..1000 {
+        storage.set(&key_of(i), value_of(i)).unwrap();
+    }
+    // The memtable entries are counted exactly.
+    assert_eq!(storage.approximate_count(Range::from(key_of(100)..key_of(300))).unwrap(), 200);
+    storage.flush().unwrap();
+
+    // SsTables are estimated at block granularity.
+    assert_eq!(storage.approximate_count(Range::from(..)).unwrap(), 1000);
+    let count = storage.approximate_count(Range::from(key_of(200)..key_of(400))).unwrap();
+    assert!((150..=450).contains(&count), "{}", count);
+    let size = storage.approximate_size(Range::from(..)).unwrap();
+    assert!((24_000..40_000).contains(&size), "{}", size);
+    assert_eq!(storage.approximate_count(Range::from(b"z".to_vec()..)).unwrap(), 0);
+
+    let points = storage.split_points(Range::from(..), 4).unwrap();
+    assert_eq!(points.len(), 3);
+    for (idx, point) in points.iter().enumerate() {
+        let expected = (idx + 1) * 250;
+        assert!(key_of(expected - 150) <= *point && *point <= key_of(expected + 150));
+    }
+    let points = storage.split_points(Range::from(key_of(500)..), 2).unwrap();
+    assert!(points.len() == 1 && points[0] > key_of(500));
+
+    // Values in blob files count towards the size of the SsTable blocks.
+    storage.set(b"large", vec![0; 100_000]).unwrap();
+    storage.flush().unwrap();
+    assert!(storage.approximate_size(Range::from(..)).unwrap() > size + 100_000);
+}
+
+#[test]
+fn test_storage_repair() {
+    use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+    let dir = tempdir().unwrap();
+    let options = || LsmStorageOptions { block_size: 64, ..LsmStorageOptions::default() };
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    for i in 0..100 {
+        storage.set(&key_of(i), value_of(i)).unwrap();
+    }
+    storage.compact_range(Range::from(..)).unwrap();
+    for i in 0..50 {
+        storage.set(&key_of(i), value_of(i + 1)).unwrap();
+    }
+    storage.flush().unwrap();
+    // Only in the WAL.
+    storage.set(&key_of(100), value_of(100)).unwrap();
+    storage.delete(&key_of(0)).unwrap();
+    let bottom = storage.levels_for_test()[5][0].clone();
+    drop(storage);
+
+    // Corrupts a block of the bottommost SsTable, among keys that were not overwritten.
+    let block_metas = bottom.block_metas();
+    let lost = block_metas.len() - 2;
+    let sst_path = dir.path().join(format!("{:05}.sst", bottom.id()));
+    let mut data = std::fs::read(&sst_path).unwrap();
+    data[block_metas[lost].offset + 1] ^= 0xff;
+    std::fs::write(&sst_path, data).unwrap();
+    let (lost_from, lost_to) = (&block_metas[lost].first_key, &block_metas[lost + 1].first_key);
+    let expected = |i: usize| match i {
+        0 => None,
+        1..50 => Some(value_of(i + 1)),
+        _ if (&lost_from[..]..&lost_to[..]).contains(&&key_of(i)[..]) => None,
+        _ => Some(value_of(i)),
+    };
+    assert!((50..100).any(|i| expected(i).is_none()));
+
+    let report = LsmStorage::repair_with_options(&dir, options()).unwrap();
+    assert!(!report.is_lossless());
+    assert_eq!(report.wal_segments, 1);
+    assert_eq!(report.column_families.len(), 1);
+    let family = &report.column_families[0];
+    assert!(family.manifest_recovered);
+    assert_eq!(family.sstables.len(), 3);
+    assert_eq!(family.sstables[0], bottom.id());
+    assert_eq!(family.salvaged_sstables, vec![bottom.id()]);
+    assert_eq!(family.lost_blocks.len(), 1);
+    assert_eq!(family.lost_blocks[0].block_idx, lost);
+    assert_eq!(family.lost_blocks[0].first_key, lost_from.to_vec());
+    assert!(family.lost_sstables.is_empty());
+    assert_eq!(family.wal_entries, 2);
+    assert_eq!(report.lost_dir, std::path::Path::new("lost").join("1"));
+    let lost_sst_path = dir.path().join("lost").join("1").join(format!("{:05}.sst", bottom.id()));
+    assert!(lost_sst_path.exists());
+
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    assert_eq!(storage.l0_sstables_for_test().len(), 3);
+    for i in 0..100 {
+        assert_eq!(storage.get(&key_of(i)).unwrap(), expected(i), "key {}", i);
+    }
+    assert_eq!(storage.get(&key_of(100)).unwrap(), Some(value_of(100)));
+    drop(storage);
+
+    // Without a manifest, SsTables are ordered by ID, which nothing rewrote here.
+    std::fs::remove_file(dir.path().join("MANIFEST")).unwrap();
+    let report = LsmStorage::repair_with_options(&dir, options()).unwrap();
+    assert!(report.is_lossless());
+    assert!(!report.column_families[0].manifest_recovered);
+    assert_eq!(report.column_families[0].sstables.len(), 3);
+    // The second repair does not overwrite the files moved by the first one.
+    assert_eq!(report.lost_dir, std::path::Path::new("lost").join("2"));
+    assert!(lost_sst_path.exists());
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    for i in 0..=100 {
+        let expected = if i == 100 { Some(value_of(100)) } else { expected(i) };
+        assert_eq!(storage.get(&key_of(i)).unwrap(), expected, "key {}", i);
+    }
+}
+
+/// Runs storages in temporary directories, with small memtables and SsTables.
+#[cfg(test)]
+#[derive(Default)]
+struct LsmEngine {
+    dir: Option<tempfile::TempDir>,
+}
+
+#[cfg(test)]
+impl LsmEngine {
+    fn open(&self) -> crate::error::Result<super::lsm_storage::LsmStorage> {
+        use super::lsm_storage::{LsmStorage, LsmStorageOptions};
+        let options = LsmStorageOptions {
+            block_size: 64,
+            target_file_size: 256,
+            ..LsmStorageOptions::default()
+        };
+        LsmStorage::open_with_options(self.dir.as_ref().expect("a storage was created"), options)
+    }
+}
+
+#[cfg(test)]
+impl crate::storage::kv::TestEngine for LsmEngine {
+    type Store = super::lsm_storage::LsmStorage;
+
+    fn create(&mut self) -> crate::error::Result<Self::Store> {
+        self.dir = Some(tempdir()?);
+        self.open()
+    }
+
+    fn reopen(&mut self, store: Self::Store) -> crate::error::Result<Self::Store> {
+        drop(store);
+        self.open()
+    }
+
+    fn compact(&mut self, store: &Self::Store) -> crate::error::Result<()> {
+        store.compact_range(Range::from(..))
+    }
+}
+
+#[test]
+fn test_storage_model() {
+    use crate::storage::kv::ModelTest;
+    for seed in 0..10 {
+        ModelTest { seed, ..Default::default() }.run(&mut LsmEngine::default()).unwrap();
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use bytes::{Buf, BufMut};
+use parking_lot::{Mutex, RwLock};
+
+use crate::error::{Error, Result};
+
+use super::checksum::crc32;
+use super::encryption::{BlockCipher, KeyProvider};
+use super::env::{Env, WritableFile};
+
+/// Magic number starting encrypted segments.
+const ENCRYPTED_MAGIC: &[u8; 8] = b"FEATHWAL";
+
+/// A write logged to the WAL: a stored value (empty for a tombstone) for a key of a column family.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalEntry {
+    pub family: String,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Batches of writes read from the WAL, along with the IDs of their segments.
+pub type WalBatches = Vec<(u64, Vec<WalEntry>)>;
+
+/// The segment currently appended to.
+struct WalSegment {
+    id: u64,
+    file: Mutex<Box<dyn WritableFile>>,
+    /// Encrypts the batches, if the segment is encrypted.
+    cipher: Option<BlockCipher>,
+    /// Number of batches logged to the segment, which is the position of the next batch.
+    batches: AtomicU64,
+    /// Set when an append failed, possibly leaving part of a batch at the end of the segment.
+    /// Replay stops at such a batch, so later batches go to a new segment.
+    broken: AtomicBool,
+}
+
+/// The oldest WAL segments holding writes of a column family that are not yet in SsTables.
+#[derive(Clone, Copy)]
+struct UnflushedSegments {
+    /// For writes in the current memtable.
+    memtable: Option<u64>,
+    /// For writes in the memtable being flushed.
+    flushing: Option<u64>,
+}
+
+/// A write-ahead log shared by all column families of an `LsmStorage`, made of numbered segment
+/// files. Each memtable flush starts a new segment, and a segment is deleted once the writes of
+/// every column family in it are in SsTables.
+pub struct Wal {
+    env: Arc<dyn Env>,
+    path: PathBuf,
+    /// Whether every batch is synced before being applied.
+    sync_writes: bool,
+    /// Provides the key new segments are encrypted with, if encryption is enabled.
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    /// Writers hold the read lock while applying a batch to the memtables, so that rotating the
+    /// segment (with the write lock) cuts the log exactly between two memtables.
+    current: RwLock<WalSegment>,
+    unflushed: Mutex<HashMap<String, UnflushedSegments>>,
+}
+
+/// Data alignment:
+///
+/// ```text
+///     | batch_len (4B) | checksum (4B) | entry | ... | entry | ...
+///     entry: | family_len (2B) | family | key_len (2B) | key | value_len (4B) | value |
+/// ```
+///
+/// The checksum is the CRC-32 of the entries of the batch. Encrypted segments start with the ID
+/// of their key, and each batch is encrypted as a block whose position is its ordinal in the
+/// segment, the checksum covering the encrypted batch:
+///
+/// ```text
+///     | magic (8B) | key id (4B) | batch_len (4B) | checksum (4B) | encrypted batch | ...
+/// ```
+impl Wal {
+    /// Opens the WAL in the given directory, returning the logged batches along with the IDs of
+    /// their segments. New batches are logged to a fresh segment, and synced before being applied
+    /// if `sync_writes` is set. New segments are encrypted with the current key of the provider,
+    /// if any.
+    pub fn open(
+        env: Arc<dyn Env>,
+        path: impl AsRef<Path>,
+        sync_writes: bool,
+        key_provider: Option<Arc<dyn KeyProvider>>,
+    ) -> Result<(Self, WalBatches)> {
+        let path = path.as_ref().to_path_buf();
+        let batches = Self::read(env.as_ref(), &path, key_provider.as_deref())?;
+        let segment_id = Self::segment_ids(env.as_ref(), &path)?.last().map_or(1, |id| id + 1);
+        let current = RwLock::new(
+            Self::create_segment(env.as_ref(), &path, segment_id, key_provider.as_deref())?
+        );
+        let unflushed = Mutex::new(HashMap::new());
+        Ok((Self { env, path, sync_writes, key_provider, current, unflushed }, batches))
+    }
+
+    /// Reads the batches logged to the WAL in the given directory, without opening it for
+    /// writing. A torn batch at the end of a segment is ignored. Encrypted segments need the
+    /// provider of their key.
+    pub fn read(
+        env: &dyn Env,
+        path: impl AsRef<Path>,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<WalBatches> {
+        let path = path.as_ref();
+        let mut batches = vec![];
+        for segment_id in Self::segment_ids(env, path)? {
+            // The segment may be deleted by the process writing the WAL in the meantime.
+            let segment_path = Self::path_of_segment(path, segment_id);
+            let data = match env.read_file(&segment_path) {
+                Ok(data) => data,
+                Err(_) if !env.exists(&segment_path) => continue,
+                Err(e) => return Err(e),
+            };
+            for batch in Self::decode_segment(segment_id, &data, key_provider)? {
+                batches.push((segment_id, batch));
+            }
+        }
+        Ok(batches)
+    }
+
+    fn path_of_segment(path: &Path, id: u64) -> PathBuf {
+        path.join(format!("{:05}.wal", id))
+    }
+
+    /// IDs of the segments in the directory, in ascending order.
+    fn segment_ids(env: &dyn Env, path: &Path) -> Result<Vec<u64>> {
+        let mut ids = vec![];
+        for file_name in env.list_dir(path)? {
+            if let Some(Ok(id)) = file_name.strip_suffix(".wal").map(|id| id.parse::<u64>()) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn create_segment(
+        env: &dyn Env,
+        path: &Path,
+        id: u64,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<WalSegment> {
+        let cipher = key_provider.map(BlockCipher::new).transpose()?;
+        let mut file = env.create_writable(&Self::path_of_segment(path, id))?;
+        if let Some(cipher) = &cipher {
+            let mut header = ENCRYPTED_MAGIC.to_vec();
+            header.put_u32(cipher.key_id());
+            file.append(&header)?;
+        }
+        Ok(WalSegment {
+            id,
+            file: Mutex::new(file),
+            cipher,
+            batches: AtomicU64::new(0),
+            broken: AtomicBool::new(false),
+        })
+    }
+
+    fn encode_batch(entries: &[WalEntry], cipher: Option<&BlockCipher>, position: u64) -> Vec<u8> {
+        let mut batch = Vec::new();
+        for entry in entries {
+            batch.put_u16(entry.family.len() as u16);
+            batch.put_slice(entry.family.as_bytes());
+            batch.put_u16(entry.key.len() as u16);
+            batch.put_slice(&entry.key);
+            batch.put_u32(entry.value.len() as u32);
+            batch.put_slice(&entry.value);
+        }
+        if let Some(cipher) = cipher {
+            batch = cipher.encrypt(position, &batch);
+        }
+        let mut buffer = Vec::with_capacity(batch.len() + 8);
+        buffer.put_u32(batch.len() as u32);
+        buffer.put_u32(crc32(&batch));
+        buffer.extend(batch);
+        buffer
+    }
+
+    fn decode_segment(
+        segment_id: u64,
+        mut data: &[u8],
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<Vec<Vec<WalEntry>>> {
+        let mut cipher = None;
+        if data.starts_with(ENCRYPTED_MAGIC) && data.len() >= ENCRYPTED_MAGIC.len() + 4 {
+            data.advance(ENCRYPTED_MAGIC.len());
+            let key_id = data.get_u32();
+            let key_provider = key_provider.ok_or_else(|| Error::Value(format!(
+                "WAL segment {} is encrypted with key {}, but no key provider is set",
+                segment_id, key_id
+            )))?;
+            cipher = Some(BlockCipher::open(key_provider, key_id)?);
+        }
+        let mut batches = vec![];
+        while data.remaining() >= 8 {
+            let batch_len = data.get_u32() as usize;
+            let checksum = data.get_u32();
+            // A batch failing its checksum or authentication was torn like a truncated one.
+            if data.remaining() < batch_len || crc32(&data[..batch_len]) != checksum {
+                break;
+            }
+            let batch = match &cipher {
+                Some(cipher) => cipher.decrypt(batches.len() as u64, &data[..batch_len]).ok(),
+                None => Some(data[..batch_len].to_vec()),
+            };
+            match batch.and_then(|batch| Self::decode_batch(&batch)) {
+                Some(batch) => batches.push(batch),
+                None => break,
+            }
+            data.advance(batch_len);
+        }
+        Ok(batches)
+    }
+
+    fn decode_batch(mut batch: &[u8]) -> Option<Vec<WalEntry>> {
+        let mut entries = vec![];
+        while batch.has_remaining() {
+            let family_len = Self::take_len(&mut batch, 2)?;
+            let family = String::from_utf8(batch.get(..family_len)?.to_vec()).ok()?;
+            batch.advance(family_len);
+            let key_len = Self::take_len(&mut batch, 2)?;
+            let key = batch.get(..key_len)?.to_vec();
+            batch.advance(key_len);
+            let value_len = Self::take_len(&mut batch, 4)?;
+            let value = batch.get(..value_len)?.to_vec();
+            batch.advance(value_len);
+            entries.push(WalEntry { family, key, value });
+        }
+        Some(entries)
+    }
+
+    fn take_len(batch: &mut &[u8], size: usize) -> Option<usize> {
+        match (size, batch.remaining() >= size) {
+            (2, true) => Some(batch.get_u16() as usize),
+            (4, true) => Some(batch.get_u32() as usize),
+            _ => None,
+        }
+    }
+
+    /// Logs a batch of writes, then applies it with `apply` before a new segment can be started.
+    pub fn log<R>(
+        &self,
+        entries: Vec<WalEntry>,
+        apply: impl FnOnce(Vec<WalEntry>) -> R,
+    ) -> Result<R> {
+        let mut current = self.current.read();
+        if current.broken.load(Ordering::Relaxed) {
+            drop(current);
+            self.replace_broken_segment()?;
+            current = self.current.read();
+        }
+        {
+            let mut file = current.file.lock();
+            // Positions of encrypted batches must follow the ones replay counts, so a failed batch
+            // takes none.
+            let position = current.batches.load(Ordering::Relaxed);
+            let batch = Self::encode_batch(&entries, current.cipher.as_ref(), position);
+            let logged = file.append(&batch).and_then(|()| match self.sync_writes {
+                true => file.sync(),
+                false => Ok(()),
+            });
+            if let Err(err) = logged {
+                current.broken.store(true, Ordering::Relaxed);
+                return Err(err);
+            }
+            current.batches.store(position + 1, Ordering::Relaxed);
+        }
+        for entry in entries.iter() {
+            self.note_unflushed(&entry.family, current.id);
+        }
+        Ok(apply(entries))
+    }
+
+    /// Records that a column family has unflushed writes in the given segment.
+    pub fn note_unflushed(&self, family: &str, segment_id: u64) {
+        let mut unflushed = self.unflushed.lock();
+        let segments = unflushed
+            .entry(family.to_string())
+            .or_insert(UnflushedSegments { memtable: None, flushing: None });
+        segments.memtable = Some(segments.memtable.map_or(segment_id, |id| id.min(segment_id)));
+    }
+
+    /// Starts a new segment when column families begin flushing their memtables, calling `swap`
+    /// to swap the memtables while no writes are logged. Returns the ID of the new segment: all
+    /// writes of the column families before it are in the memtables being flushed.
+    pub fn rotate<R>(&self, families: &[&str], swap: impl FnOnce() -> R) -> Result<(u64, R)> {
+        let mut current = self.current.write();
+        // Sync the full segment, so that a crash can only lose writes at the end of the log.
+        current.file.lock().sync()?;
+        let segment_id = current.id + 1;
+        *current = self.create_next_segment(segment_id)?;
+        {
+            let mut unflushed = self.unflushed.lock();
+            for family in families {
+                if let Some(segments) = unflushed.get_mut(*family) {
+                    segments.flushing = segments.memtable.take();
+                }
+            }
+        }
+        Ok((segment_id, swap()))
+    }
+
+    /// Makes sure new writes go to a segment with an ID of at least `segment_id`, so that they are
+    /// not mistaken for flushed writes when the segments they follow were lost.
+    pub fn skip_to(&self, segment_id: u64) -> Result<()> {
+        let mut current = self.current.write();
+        if current.id < segment_id {
+            *current = self.create_next_segment(segment_id)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a new segment if the current one is broken by a failed append.
+    fn replace_broken_segment(&self) -> Result<()> {
+        let mut current = self.current.write();
+        if current.broken.load(Ordering::Relaxed) {
+            let segment_id = current.id + 1;
+            *current = self.create_next_segment(segment_id)?;
+        }
+        Ok(())
+    }
+
+    fn create_next_segment(&self, segment_id: u64) -> Result<WalSegment> {
+        let key_provider = self.key_provider.as_deref();
+        Self::create_segment(self.env.as_ref(), &self.path, segment_id, key_provider)
+    }
+
+    /// Records that a column family finished flushing, deleting the segments no longer needed.
+    pub fn flushed(&self, family: &str) -> Result<()> {
+        let current = self.current.read();
+        let min_unflushed = {
+            let mut unflushed = self.unflushed.lock();
+            if let Some(segments) = unflushed.get_mut(family) {
+                segments.flushing = None;
+            }
+            unflushed
+                .values()
+                .flat_map(|segments| segments.memtable.into_iter().chain(segments.flushing))
+                .fold(current.id, u64::min)
+        };
+        for segment_id in Self::segment_ids(self.env.as_ref(), &self.path)? {
+            if segment_id < min_unflushed {
+                self.env.remove_file(&Self::path_of_segment(&self.path, segment_id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Syncs the current segment to the disk.
+    pub fn sync(&self) -> Result<()> {
+        self.current.read().file.lock().sync()
+    }
+}
+
+
+#[cfg(test)]
+use tempfile::tempdir;
+
+#[cfg(test)]
+use super::env::StdEnv;
+
+#[cfg(test)]
+fn entry_of(family: &str, key: &[u8], value: &[u8]) -> WalEntry {
+    WalEntry { family: family.into(), key: key.to_vec(), value: value.to_vec() }
+}
+
+#[test]
+fn test_wal_recover() {
+    let dir = tempdir().unwrap();
+    let (wal, batches) = Wal::open(Arc::new(StdEnv), dir.path(), false, None).unwrap();
+    assert!(batches.is_empty());
+    wal.log(vec![entry_of("default", b"1", b"1")], |_| ()).unwrap();
+    wal.log(vec![entry_of("default", b"2", b""), entry_of("meta", b"3", b"3")], |_| ()).unwrap();
+    drop(wal);
+
+    // A torn batch at the end of a segment is ignored.
+    let path = Wal::path_of_segment(dir.path(), 1);
+    let mut data = std::fs::read(&path).unwrap();
+    data.extend([0, 0, 0, 32, 0, 7]);
+    std::fs::write(&path, data).unwrap();
+
+    let (wal, batches) = Wal::open(Arc::new(StdEnv), dir.path(), false, None).unwrap();
+    assert_eq!(batches, vec![
+        (1, vec![entry_of("default", b"1", b"1")]),
+        (1, vec![entry_of("default", b"2", b""), entry_of("meta", b"3", b"3")]),
+    ]);
+    wal.log(vec![entry_of("meta", b"4", b"4")], |_| ()).unwrap();
+    drop(wal);
+    let (_, batches) = Wal::open(Arc::new(StdEnv), dir.path(), false, None).unwrap();
+    assert_eq!(batches.len(), 3);
+    assert_eq!(batches[2], (2, vec![entry_of("meta", b"4", b"4")]));
+}
+
+#[test]
+fn test_wal_checksum() {
+    let dir = tempdir().unwrap();
+    let (wal, _) = Wal::open(Arc::new(StdEnv), dir.path(), false, None).unwrap();
+    wal.log(vec![entry_of("default", b"key1", b"value1")], |_| ()).unwrap();
+    wal.log(vec![entry_of("default", b"key2", b"value2")], |_| ()).unwrap();
+    wal.log(vec![entry_of("default", b"key3", b"value3")], |_| ()).unwrap();
+    drop(wal);
+
+    // A corrupted value still decodes, but fails the checksum, so replay stops at its batch.
+    let path = Wal::path_of_segment(dir.path(), 1);
+    let mut data = std::fs::read(&path).unwrap();
+    let offset = data.windows(6).position(|window| window == b"value2").unwrap();
+    data[offset] ^= 1;
+    std::fs::write(&path, data).unwrap();
+    let batches = Wal::read(&StdEnv, dir.path(), None).unwrap();
+    assert_eq!(batches, vec![(1, vec![entry_of("default", b"key1", b"value1")])]);
+}
+
+#[test]
+fn test_wal_rotate() {
+    let dir = tempdir().unwrap();
+    let (wal, _) = Wal::open(Arc::new(StdEnv), dir.path(), false, None).unwrap();
+    wal.log(vec![entry_of("a", b"1", b"1"), entry_of("b", b"1", b"1")], |_| ()).unwrap();
+
+    // The first segment is kept until both column families have flushed it.
+    let (segment_id, _) = wal.rotate(&["a"], || ()).unwrap();
+    assert_eq!(segment_id, 2);
+    wal.flushed("a").unwrap();
+    assert_eq!(Wal::segment_ids(&StdEnv, dir.path()).unwrap(), vec![1, 2]);
+    wal.log(vec![entry_of("a", b"2", b"2")], |_| ()).unwrap();
+    wal.rotate(&["b"], || ()).unwrap();
+    wal.flushed("b").unwrap();
+    assert_eq!(Wal::segment_ids(&StdEnv, dir.path()).unwrap(), vec![2, 3]);
+    wal.rotate(&["a"], || ()).unwrap();
+    wal.flushed("a").unwrap();
+    assert_eq!(Wal::segment_ids(&StdEnv, dir.path()).unwrap(), vec![4]);
+}
+
+#[test]
+fn test_wal_encryption() {
+    use super::encryption::InMemoryKeyProvider;
+    let dir = tempdir().unwrap();
+    let provider = Arc::new(InMemoryKeyProvider::new(1, [1; 32]));
+    let open = || Wal::open(Arc::new(StdEnv), dir.path(), false, Some(provider.clone())).unwrap();
+    let (wal, _) = open();
+    wal.log(vec![entry_of("default", b"key1", b"value1")], |_| ()).unwrap();
+    wal.log(vec![entry_of("default", b"key2", b"value2")], |_| ()).unwrap();
+    drop(wal);
+    let data = std::fs::read(Wal::path_of_segment(dir.path(), 1)).unwrap();
+    assert!(!data.windows(6).any(|window| window == b"value1"));
+
+    // Segments without their key cannot be read, and new segments use the current key.
+    assert!(Wal::read(&StdEnv, dir.path(), None).is_err());
+    provider.rotate(2, [2; 32]);
+    let (wal, batches) = open();
+    assert_eq!(batches, vec![
+        (1, vec![entry_of("default", b"key1", b"value1")]),
+        (1, vec![entry_of("default", b"key2", b"value2")]),
+    ]);
+    wal.log(vec![entry_of("default", b"key3", b"value3")], |_| ()).unwrap();
+    drop(wal);
+
+    // A batch failing authentication is torn.
+    let path = Wal::path_of_segment(dir.path(), 2);
+    let mut data = std::fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 1;
+    std::fs::write(&path, data).unwrap();
+    let batches = Wal::read(&StdEnv, dir.path(), Some(provider.as_ref())).unwrap();
+    assert_eq!(batches.len(), 2);
+}
+
+#[test]
+fn test_wal_failed_append() {
+    use super::encryption::InMemoryKeyProvider;
+    use super::env::FaultInjectionEnv;
+    let env = FaultInjectionEnv::new();
+    env.create_dir_all(Path::new("/db")).unwrap();
+    let provider = Arc::new(InMemoryKeyProvider::new(1, [1; 32]));
+    let open = || Wal::open(Arc::new(env.clone()), "/db", true, Some(provider.clone())).unwrap();
+    let (wal, _) = open();
+    wal.log(vec![entry_of("default", b"key1", b"value1")], |_| ()).unwrap();
+    env.set_fail_writes(true);
+    assert!(wal.log(vec![entry_of("default", b"key2", b"value2")], |_| ()).is_err());
+    env.set_fail_writes(false);
+
+    // Batches acknowledged after the failure are in a new segment, and survive a crash.
+    wal.log(vec![entry_of("default", b"key3", b"value3")], |_| ()).unwrap();
+    wal.log(vec![entry_of("default", b"key4", b"value4")], |_| ()).unwrap();
+    env.crash();
+    drop(wal);
+    let (_, batches) = open();
+    assert_eq!(batches, vec![
+        (1, vec![entry_of("default", b"key1", b"value1")]),
+        (2, vec![entry_of("default", b"key3", b"value3")]),
+        (2, vec![entry_of("default", b"key4", b"value4")]),
+    ]);
+}
@@ -1,4 +1,10 @@
+pub mod async_store;
+pub mod bitcask;
+pub mod conformance;
+pub mod disk_b_plus_tree;
+pub mod instrumented;
 pub mod lsm_tree;
+pub mod sharded;
 pub mod std_b_plus_tree;
 
 use std::fmt::Display;
@@ -6,7 +12,21 @@ use std::ops::{Bound, RangeBounds};
 
 use crate::error::Result;
 
-pub use lsm_tree::lsm_storage::LsmStorage;
+pub use async_store::{AsyncKvStore, AsyncLsmStorage, KvStream};
+pub use bitcask::{Bitcask, BitcaskOptions};
+pub use conformance::{ModelFailure, ModelOp, ModelTest, ScanOrder, TestEngine};
+pub use disk_b_plus_tree::tree::{DiskBPlusTree, DiskBPlusTreeOptions};
+pub use instrumented::{
+    format_raw_key, InstrumentedKvStore, InstrumentedKvStoreOptions, KeyFormatter, KvOp, KvOpStats,
+    KvTrace,
+};
+pub use lsm_tree::backup::BackupEngine;
+pub use lsm_tree::encryption::{InMemoryKeyProvider, KeyProvider};
+pub use lsm_tree::lsm_storage::{ColumnFamily, LsmStorage, LsmStorageOptions, WriteBatch};
+pub use lsm_tree::rate_limiter::{AutoTune, RateLimiter, RateLimiterStats};
+pub use lsm_tree::repair::{ColumnFamilyRepair, LostBlock, RepairReport};
+pub use lsm_tree::sst_file_writer::SstFileWriter;
+pub use sharded::{Partitioner, ShardedKvStore};
 pub use std_b_plus_tree::StdBPlusTree;
 
 pub trait KvStore: Display + Send + Sync {
@@ -22,8 +42,63 @@ pub trait KvStore: Display + Send + Sync {
     /// Iterates over an ordered range of key/value pairs.
     fn scan(&self, range: Range) -> Result<KvScan>;
 
+    /// Iterates over the ordered key/value pairs whose keys start with `prefix`.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvScan> {
+        self.scan(Range::prefix(prefix))
+    }
+
     /// Flushes any buffered data to the underlying storage medium.
     fn flush(&self) -> Result<()>;
+
+    /// Estimates the size in bytes of the keys and values in a range. Scans the range, unless
+    /// the store has a cheaper estimate.
+    fn approximate_size(&self, range: Range) -> Result<u64> {
+        self.scan(range)?.try_fold(0, |size, entry| {
+            entry.map(|(key, value)| size + (key.len() + value.len()) as u64)
+        })
+    }
+
+    /// Estimates the number of keys in a range. Scans the range, unless the store has a
+    /// cheaper estimate.
+    fn approximate_count(&self, range: Range) -> Result<u64> {
+        self.scan(range)?.try_fold(0, |count, entry| entry.map(|_| count + 1))
+    }
+
+    /// Returns up to `n - 1` sorted keys within a range, dividing it into `n` parts of about
+    /// the same size. There are fewer keys if the range is too small to be divided. Scans the
+    /// range, unless the store has a cheaper estimate.
+    fn split_points(&self, range: Range, n: usize) -> Result<Vec<Vec<u8>>> {
+        let samples = self.scan(range)?
+            .map(|entry| entry.map(|(key, value)| {
+                let size = (key.len() + value.len()) as u64;
+                (key, size)
+            }))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(split_by_size(samples, n))
+    }
+}
+
+/// Picks up to `n - 1` keys dividing samples into `n` parts of about the same size, where each
+/// sample is the size of the data from its key up to the next sample. The first part is never
+/// empty, and the keys are distinct.
+pub(crate) fn split_by_size(mut samples: Vec<(Vec<u8>, u64)>, n: usize) -> Vec<Vec<u8>> {
+    samples.sort_by(|a, b| a.0.cmp(&b.0));
+    let total = samples.iter().map(|(_, size)| size).sum::<u64>() as u128;
+    let mut points: Vec<Vec<u8>> = Vec::with_capacity(n.saturating_sub(1));
+    let mut size_before = 0_u128;
+    for (key, size) in samples {
+        // The next point starts the part at `total * (points + 1) / n` bytes.
+        let target = total * (points.len() + 1) as u128 / n.max(1) as u128;
+        if points.len() + 1 < n
+            && size_before > 0
+            && size_before >= target
+            && points.last().is_none_or(|last| *last < key)
+        {
+            points.push(key);
+        }
+        size_before += size as u128;
+    }
+    points
 }
 
 #[derive(Clone)]
@@ -49,6 +124,20 @@ impl Range {
             },
         }
     }
+
+    /// The range of keys starting with `prefix`.
+    pub fn prefix(prefix: &[u8]) -> Self {
+        // The end bound is the prefix with its last byte below 0xff incremented, if any.
+        let end = match prefix.iter().rposition(|b| *b != 0xff) {
+            Some(idx) => {
+                let mut end = prefix[..=idx].to_vec();
+                end[idx] += 1;
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        Self { start: Bound::Included(prefix.to_vec()), end }
+    }
 }
 
 impl RangeBounds<Vec<u8>> for Range {
@@ -82,6 +171,24 @@ trait TestSuite<S: KvStore> {
         Self::test_scan()?;
         Self::test_set()?;
         Self::test_random()?;
+        Self::test_approximate()?;
+        Ok(())
+    }
+
+    fn test_approximate() -> Result<()> {
+        let s = Self::setup()?;
+        assert_eq!(0, s.approximate_count(Range::from(..))?);
+        assert!(s.split_points(Range::from(..), 4)?.is_empty());
+        for i in 0..100_u8 {
+            s.set(&[i], vec![i; 10])?;
+        }
+        assert_eq!(100, s.approximate_count(Range::from(..))?);
+        assert_eq!(20, s.approximate_count(Range::from(vec![10]..vec![30]))?);
+        assert_eq!(1100, s.approximate_size(Range::from(..))?);
+
+        let points = s.split_points(Range::from(vec![20]..), 4)?;
+        assert_eq!(points, vec![vec![40], vec![60], vec![80]]);
+        assert!(s.split_points(Range::from(vec![20]..vec![22]), 4)?.len() <= 1);
         Ok(())
     }
 
@@ -198,6 +305,21 @@ trait TestSuite<S: KvStore> {
             ],
             s.scan(Range::from(..))?.collect::<Result<Vec<_>>>()?
         );
+
+        // Prefix ranges
+        assert_eq!(
+            vec![
+                (b"b".to_vec(), vec![0x02]),
+                (b"ba".to_vec(), vec![0x02, 0x01]),
+                (b"bb".to_vec(), vec![0x02, 0x02]),
+            ],
+            s.scan_prefix(b"b")?.collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            vec![(b"bb".to_vec(), vec![0x02, 0x02])],
+            s.scan_prefix(b"bb")?.rev().collect::<Result<Vec<_>>>()?
+        );
+        assert!(s.scan_prefix(b"d")?.next().is_none());
         Ok(())
     }
 
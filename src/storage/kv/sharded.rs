@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+
+use super::{split_by_size, KvScan, KvStore, Range};
+use crate::error::{Error, Result};
+
+/// How the keys of a `ShardedKvStore` are assigned to its shards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Partitioner {
+    /// Spreads the keys evenly by their hash. Scans read every shard and merge their entries.
+    Hash,
+    /// Splits the key space at the given sorted keys: shard `i` holds the keys at or above
+    /// `split_keys[i - 1]` and below `split_keys[i]`. Scans only read the shards overlapping
+    /// their range, one after another.
+    Range(Vec<Vec<u8>>),
+}
+
+/// A key-value store partitioning its keys across inner stores, so that writes to different
+/// shards do not contend on the locks of a single store.
+pub struct ShardedKvStore {
+    shards: Vec<Box<dyn KvStore>>,
+    partitioner: Partitioner,
+}
+
+impl ShardedKvStore {
+    /// Creates a store hash-partitioning its keys across the given shards.
+    pub fn hash(shards: Vec<Box<dyn KvStore>>) -> Result<Self> {
+        if shards.is_empty() {
+            return Err(Error::Value("A sharded store needs at least one shard".into()));
+        }
+        Ok(Self { shards, partitioner: Partitioner::Hash })
+    }
+
+    /// Creates a store range-partitioning its keys across the given shards, at sorted split
+    /// keys, one fewer than the shards.
+    pub fn range(shards: Vec<Box<dyn KvStore>>, split_keys: Vec<Vec<u8>>) -> Result<Self> {
+        if shards.len() != split_keys.len() + 1 {
+            return Err(Error::Value(format!(
+                "{} shards need {} split keys, got {}",
+                shards.len(), shards.len().saturating_sub(1), split_keys.len()
+            )));
+        }
+        if split_keys.windows(2).any(|keys| keys[0] >= keys[1]) {
+            return Err(Error::Value("Split keys must be sorted and distinct".into()));
+        }
+        Ok(Self { shards, partitioner: Partitioner::Range(split_keys) })
+    }
+
+    /// Gets the inner stores.
+    pub fn shards(&self) -> &[Box<dyn KvStore>] {
+        &self.shards
+    }
+
+    /// Gets the partitioning of the keys.
+    pub fn partitioner(&self) -> &Partitioner {
+        &self.partitioner
+    }
+
+    /// Gets the index of the shard holding a key.
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        match &self.partitioner {
+            Partitioner::Hash => (fnv1a(key) % self.shards.len() as u64) as usize,
+            Partitioner::Range(split_keys) => {
+                split_keys.partition_point(|split_key| split_key.as_slice() <= key)
+            }
+        }
+    }
+
+    /// Gets the indexes of the shards that may hold keys of a range.
+    fn shards_in(&self, range: &Range) -> std::ops::Range<usize> {
+        match &self.partitioner {
+            Partitioner::Hash => 0..self.shards.len(),
+            Partitioner::Range(_) => {
+                let first = match range.start_bound() {
+                    Bound::Included(key) | Bound::Excluded(key) => self.shard_of(key),
+                    Bound::Unbounded => 0,
+                };
+                let last = match range.end_bound() {
+                    Bound::Included(key) | Bound::Excluded(key) => self.shard_of(key),
+                    Bound::Unbounded => self.shards.len() - 1,
+                };
+                first..(last + 1).max(first)
+            }
+        }
+    }
+
+    /// Combines the scans of the shards: merged by key for hash partitioning, and chained for
+    /// range partitioning, whose shards hold consecutive ranges.
+    fn combine_scans(&self, scans: Vec<KvScan>) -> KvScan {
+        match &self.partitioner {
+            Partitioner::Hash => Box::new(ShardedScan::new(scans)),
+            Partitioner::Range(_) => Box::new(scans.into_iter().flatten()),
+        }
+    }
+}
+
+/// 64-bit FNV-1a hash. Unlike the standard library hashers it is stable across releases, so
+/// shards of persistent stores keep their keys.
+fn fnv1a(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Display for ShardedKvStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sharded")
+    }
+}
+
+impl KvStore for ShardedKvStore {
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.shards[self.shard_of(key)].set(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.shards[self.shard_of(key)].get(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        self.shards[self.shard_of(key)].delete(key)
+    }
+
+    fn scan(&self, range: Range) -> Result<KvScan> {
+        let scans = self.shards[self.shards_in(&range)].iter()
+            .map(|shard| shard.scan(range.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self.combine_scans(scans))
+    }
+
+    /// Forwards the prefix scan to the shards, so that they can use their prefix filters.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvScan> {
+        let scans = self.shards[self.shards_in(&Range::prefix(prefix))].iter()
+            .map(|shard| shard.scan_prefix(prefix))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self.combine_scans(scans))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shards.iter().try_for_each(|shard| shard.flush())
+    }
+
+    fn approximate_size(&self, range: Range) -> Result<u64> {
+        self.shards.iter().map(|shard| shard.approximate_size(range.clone())).sum()
+    }
+
+    fn approximate_count(&self, range: Range) -> Result<u64> {
+        self.shards.iter().map(|shard| shard.approximate_count(range.clone())).sum()
+    }
+
+    /// Combines the split points of the shards, each dividing its share of the range into `n`
+    /// parts of about the same size, so that no shard is scanned.
+    fn split_points(&self, range: Range, n: usize) -> Result<Vec<Vec<u8>>> {
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => key.clone(),
+            Bound::Unbounded => vec![],
+        };
+        // Samples starting at the same key are summed, e.g. the first parts of hash shards.
+        let mut samples = BTreeMap::new();
+        for idx in self.shards_in(&range) {
+            let size = self.shards[idx].approximate_size(range.clone())?;
+            let points = self.shards[idx].split_points(range.clone(), n)?;
+            let part_size = size / (points.len() as u64 + 1);
+            let first_key = match &self.partitioner {
+                Partitioner::Range(split_keys) if idx > 0 => {
+                    split_keys[idx - 1].clone().max(start.clone())
+                }
+                _ => start.clone(),
+            };
+            for key in std::iter::once(first_key).chain(points) {
+                *samples.entry(key).or_default() += part_size;
+            }
+        }
+        Ok(split_by_size(samples.into_iter().collect(), n))
+    }
+}
+
+/// The scan of a shard, with the entries taken from either end but not returned yet.
+struct ShardScan {
+    scan: KvScan,
+    front: Option<(Vec<u8>, Vec<u8>)>,
+    back: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ShardScan {
+    fn peek_front(&mut self) -> Result<Option<&Vec<u8>>> {
+        if self.front.is_none() {
+            // Once the scan is exhausted, the last entry may have been taken by the back.
+            self.front = match self.scan.next().transpose()? {
+                Some(entry) => Some(entry),
+                None => self.back.take(),
+            };
+        }
+        Ok(self.front.as_ref().map(|(key, _)| key))
+    }
+
+    fn peek_back(&mut self) -> Result<Option<&Vec<u8>>> {
+        if self.back.is_none() {
+            self.back = match self.scan.next_back().transpose()? {
+                Some(entry) => Some(entry),
+                None => self.front.take(),
+            };
+        }
+        Ok(self.back.as_ref().map(|(key, _)| key))
+    }
+}
+
+/// Merges the scans of hash-partitioned shards into key order. The shards hold disjoint keys,
+/// so the merge only picks the lowest (or highest) next key among them.
+struct ShardedScan {
+    scans: Vec<ShardScan>,
+}
+
+impl ShardedScan {
+    fn new(scans: Vec<KvScan>) -> Self {
+        Self {
+            scans: scans.into_iter()
+                .map(|scan| ShardScan { scan, front: None, back: None })
+                .collect(),
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut next: Option<(usize, &Vec<u8>)> = None;
+        for (idx, scan) in self.scans.iter_mut().enumerate() {
+            if let Some(key) = scan.peek_front()? {
+                if next.is_none_or(|(_, next_key)| key < next_key) {
+                    next = Some((idx, key));
+                }
+            }
+        }
+        Ok(next.map(|(idx, _)| idx).and_then(|idx| self.scans[idx].front.take()))
+    }
+
+    fn try_next_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut next: Option<(usize, &Vec<u8>)> = None;
+        for (idx, scan) in self.scans.iter_mut().enumerate() {
+            if let Some(key) = scan.peek_back()? {
+                if next.is_none_or(|(_, next_key)| key > next_key) {
+                    next = Some((idx, key));
+                }
+            }
+        }
+        Ok(next.map(|(idx, _)| idx).and_then(|idx| self.scans[idx].back.take()))
+    }
+}
+
+impl Iterator for ShardedScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+impl DoubleEndedIterator for ShardedScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_back().transpose()
+    }
+}
+
+#[cfg(test)]
+use super::StdBPlusTree;
+
+#[cfg(test)]
+fn std_shards(n: usize) -> Vec<Box<dyn KvStore>> {
+    (0..n).map(|_| Box::new(StdBPlusTree::new()) as Box<dyn KvStore>).collect()
+}
+
+#[cfg(test)]
+impl super::TestSuite<ShardedKvStore> for ShardedKvStore {
+    fn setup() -> Result<Self> {
+        ShardedKvStore::hash(std_shards(4))
+    }
+}
+
+
+
+#[test]
+fn tests() -> Result<()> {
+    use super::TestSuite;
+    ShardedKvStore::test()
+}
+
+#[test]
+fn test_sharded_scans() -> Result<()> {
+    let key = |i: u32| i.to_be_bytes().to_vec();
+    let hashed = ShardedKvStore::hash(std_shards(5))?;
+    let ranged = ShardedKvStore::range(std_shards(3), vec![key(100), key(200)])?;
+    for i in 0..300 {
+        hashed.set(&key(i), key(i))?;
+        ranged.set(&key(i), key(i))?;
+    }
+    assert_eq!(ranged.shard_of(&key(99)), 0);
+    assert_eq!(ranged.shard_of(&key(100)), 1);
+    assert_eq!(ranged.shard_of(&key(250)), 2);
+    assert!(hashed.shards().iter().all(|shard| shard.scan(Range::from(..)).unwrap().count() > 0));
+
+    for store in [&hashed, &ranged] {
+        let keys = |scan: KvScan| scan.map(|r| r.map(|(k, _)| k)).collect::<Result<Vec<_>>>();
+        assert_eq!(keys(store.scan(Range::from(..))?)?, (0..300).map(key).collect::<Vec<_>>());
+        assert_eq!(
+            keys(Box::new(store.scan(Range::from(key(50)..=key(200)))?.rev()))?,
+            (50..=200).rev().map(key).collect::<Vec<_>>(),
+        );
+        assert_eq!(keys(store.scan(Range::from(key(100)..key(100)))?)?, Vec::<Vec<u8>>::new());
+
+        // Both ends meet without repeating or skipping entries.
+        let mut scan = store.scan(Range::from(key(10)..key(20)))?;
+        let mut front = vec![];
+        let mut back = vec![];
+        loop {
+            match (scan.next().transpose()?, scan.next_back().transpose()?) {
+                (None, None) => break,
+                (f, b) => {
+                    front.extend(f.map(|(k, _)| k));
+                    back.extend(b.map(|(k, _)| k));
+                }
+            }
+        }
+        front.extend(back.into_iter().rev());
+        assert_eq!(front, (10..20).map(key).collect::<Vec<_>>());
+    }
+
+    assert!(ShardedKvStore::hash(vec![]).is_err());
+    assert!(ShardedKvStore::range(std_shards(3), vec![key(1)]).is_err());
+    assert!(ShardedKvStore::range(std_shards(3), vec![key(2), key(1)]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_sharded_scan_prefix_and_split_points() -> Result<()> {
+    let key = |i: u32| format!("key_{:04}", i).into_bytes();
+    let hashed = ShardedKvStore::hash(std_shards(4))?;
+    let ranged = ShardedKvStore::range(std_shards(3), vec![key(250), key(500)])?;
+    for i in 0..1000 {
+        hashed.set(&key(i), vec![0; 100])?;
+        ranged.set(&key(i), vec![0; 100])?;
+    }
+    for store in [&hashed, &ranged] {
+        let keys = |scan: KvScan| scan.map(|r| r.map(|(k, _)| k)).collect::<Result<Vec<_>>>();
+        assert_eq!(keys(store.scan_prefix(b"key_02")?)?, (200..300).map(key).collect::<Vec<_>>());
+        assert_eq!(
+            keys(Box::new(store.scan_prefix(b"key_049")?.rev()))?,
+            (490..500).rev().map(key).collect::<Vec<_>>(),
+        );
+        assert!(store.scan_prefix(b"other")?.next().is_none());
+
+        // The parts are about a quarter of the range each, in any of the shards.
+        let points = store.split_points(Range::from(..), 4)?;
+        assert_eq!(points.len(), 3, "{:?}", points);
+        let mut start = Bound::Unbounded;
+        for end in points.into_iter().map(Bound::Excluded).chain([Bound::Unbounded]) {
+            let count = store.scan(Range::from((start, end.clone())))?.count();
+            assert!((200..=300).contains(&count), "{} keys in a part", count);
+            start = match end {
+                Bound::Excluded(key) => Bound::Included(key),
+                _ => Bound::Unbounded,
+            };
+        }
+        let points = store.split_points(Range::from(key(100)..key(200)), 2)?;
+        assert_eq!(points.len(), 1);
+        assert!(points[0] > key(130) && points[0] < key(170), "{:?}", points);
+    }
+    Ok(())
+}
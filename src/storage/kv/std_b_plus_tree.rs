@@ -1,21 +1,163 @@
-use parking_lot::RwLock;
+use bytes::{Buf, BufMut};
+use parking_lot::{Mutex, RwLock};
 
 use super::{Range, KvScan, KvStore};
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::storage::log::{LogReader, LogWriter};
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+/// Number of entries a scan copies each time it takes the read lock.
+const SCAN_BATCH_SIZE: usize = 1024;
+
+/// Name of the snapshot file in the directory of a persistent tree.
+const SNAPSHOT_FILE: &str = "snapshot";
+/// Name of the operation log file in the directory of a persistent tree.
+const OPLOG_FILE: &str = "oplog";
+
+const OP_DELETE: u8 = 0x00;
+const OP_SET: u8 = 0x01;
+
 /// In-memory key-value store using the Rust standard library B-tree implementation.
+///
+/// A tree opened from a directory with `open` also appends its writes to an operation log,
+/// which is replayed over the last snapshot of the directory when it is opened again. Writes
+/// are durable once flushed.
+///
+/// Snapshot and operation log records are written to a `LogWriter`:
+///
+/// ```text
+///     snapshot: | key_len (4B) | key | value |
+///     oplog:    | op (1B) | key_len (4B) | key | value |
+/// ```
 pub struct StdBPlusTree {
     data: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+    /// Directory and operation log of a persistent tree. It is locked while the data is
+    /// write-locked, so the log order is the order of the writes.
+    oplog: Option<(PathBuf, Mutex<LogWriter>)>,
 }
 
 impl StdBPlusTree {
     /// Creates a new Memory key-value storage engine.
     pub fn new() -> Self {
-        Self { data: Arc::new(RwLock::new(BTreeMap::new())) }
+        Self { data: Arc::new(RwLock::new(BTreeMap::new())), oplog: None }
+    }
+
+    /// Opens a persistent tree in a directory, creating it if it does not exist. The operation
+    /// log is replayed over the snapshot, and compacted into a new snapshot.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        std::fs::create_dir_all(&path)?;
+        let snapshot_path = path.join(SNAPSHOT_FILE);
+        let mut data = match snapshot_path.exists() {
+            true => Self::read_snapshot(&snapshot_path)?,
+            false => BTreeMap::new(),
+        };
+        let oplog_path = path.join(OPLOG_FILE);
+        if oplog_path.exists() {
+            // A torn record at the end is a write that was never flushed.
+            for record in LogReader::open(&oplog_path)?.iter()? {
+                let (_, record) = record?;
+                let mut record = record.as_slice();
+                let invalid = || Error::Internal("Invalid StdBPlusTree operation log".into());
+                if record.len() < 5 {
+                    return Err(invalid());
+                }
+                let op = record.get_u8();
+                let (key, value) = Self::decode_entry(record).ok_or_else(invalid)?;
+                match op {
+                    OP_SET => data.insert(key, value),
+                    OP_DELETE => data.remove(&key),
+                    _ => return Err(invalid()),
+                };
+            }
+        }
+        // The replayed writes are only in the log until the new snapshot is written.
+        Self::write_snapshot(&data, &snapshot_path)?;
+        let oplog = LogWriter::create(&oplog_path)?;
+        Ok(Self { data: Arc::new(RwLock::new(data)), oplog: Some((path, Mutex::new(oplog))) })
+    }
+
+    /// Restores an in-memory tree from a snapshot file written by `snapshot`.
+    pub fn restore(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self { data: Arc::new(RwLock::new(Self::read_snapshot(path.as_ref())?)), oplog: None })
+    }
+
+    /// Writes all the entries of the tree to a snapshot file, which can be restored with
+    /// `restore`. The file is replaced atomically.
+    pub fn snapshot(&self, path: impl AsRef<Path>) -> Result<()> {
+        Self::write_snapshot(&self.data.read(), path.as_ref())
+    }
+
+    /// Writes a snapshot of a persistent tree into its directory and truncates its operation
+    /// log, which otherwise grows with every write. Does nothing for an in-memory tree.
+    pub fn checkpoint(&self) -> Result<()> {
+        let Some((path, oplog)) = &self.oplog else { return Ok(()) };
+        // Writes wait for the snapshot, so the log only holds the writes following it.
+        let data = self.data.read();
+        let mut oplog = oplog.lock();
+        Self::write_snapshot(&data, &path.join(SNAPSHOT_FILE))?;
+        *oplog = LogWriter::create(path.join(OPLOG_FILE))?;
+        Ok(())
+    }
+
+    fn write_snapshot(data: &BTreeMap<Vec<u8>, Vec<u8>>, path: &Path) -> Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut writer = LogWriter::create(&tmp_path)?;
+        for (key, value) in data {
+            writer.append(&Self::encode_entry(None, key, value))?;
+        }
+        writer.sync()?;
+        std::fs::rename(&tmp_path, path)?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(path: &Path) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        let mut data = BTreeMap::new();
+        for record in LogReader::open(path)?.iter()? {
+            let (_, record) = record?;
+            let (key, value) = Self::decode_entry(&record).ok_or_else(|| {
+                Error::Internal("Invalid StdBPlusTree snapshot".into())
+            })?;
+            data.insert(key, value);
+        }
+        Ok(data)
+    }
+
+    fn encode_entry(op: Option<u8>, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut record = Vec::with_capacity(1 + 4 + key.len() + value.len());
+        if let Some(op) = op {
+            record.put_u8(op);
+        }
+        record.put_u32(key.len() as u32);
+        record.put_slice(key);
+        record.put_slice(value);
+        record
+    }
+
+    fn decode_entry(mut record: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        if record.len() < 4 {
+            return None;
+        }
+        let key_len = record.get_u32() as usize;
+        let key = record.get(..key_len)?.to_vec();
+        Some((key, record[key_len..].to_vec()))
+    }
+
+    /// Appends a write to the operation log of a persistent tree.
+    fn log(&self, op: u8, key: &[u8], value: &[u8]) -> Result<()> {
+        if let Some((_, oplog)) = &self.oplog {
+            oplog.lock().append(&Self::encode_entry(Some(op), key, value))?;
+        }
+        Ok(())
     }
 }
 
@@ -27,7 +169,9 @@ impl Display for StdBPlusTree {
 
 impl KvStore for StdBPlusTree {
     fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        self.data.write().insert(key.to_vec(), value);
+        let mut data = self.data.write();
+        self.log(OP_SET, key, &value)?;
+        data.insert(key.to_vec(), value);
         Ok(())
     }
 
@@ -36,28 +180,122 @@ impl KvStore for StdBPlusTree {
     }
 
     fn delete(&self, key: &[u8]) -> Result<()> {
-        self.data.write().remove(key);
+        let mut data = self.data.write();
+        self.log(OP_DELETE, key, &[])?;
+        data.remove(key);
         Ok(())
     }
 
     fn scan(&self, range: Range) -> Result<KvScan> {
-        // FIXME Since the range iterator returns borrowed items it would require a read-lock for
-        // the duration of the iteration. This is too coarse, so we buffer the entire iteration
-        // here. An iterator with an arc-mutex should be used instead, which is able to resume
-        // iteration by grabbing the lock again.
-        Ok(Box::new(
-            self.data
-                .read()
-                .range(range)
-                .map(|(k, v)| Ok((k.clone(), v.clone())))
-                .collect::<Vec<_>>()
-                .into_iter()
-        ))
+        Ok(Box::new(StdBPlusTreeIter::new(self.data.clone(), range)))
     }
 
     fn flush(&self) -> Result<()> {
+        if let Some((_, oplog)) = &self.oplog {
+            oplog.lock().sync()?;
+        }
         Ok(())
     }
+
+    fn approximate_size(&self, range: Range) -> Result<u64> {
+        if is_empty_range(range.start_bound(), range.end_bound()) {
+            return Ok(0);
+        }
+        Ok(self.data.read().range(range)
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum())
+    }
+
+    fn approximate_count(&self, range: Range) -> Result<u64> {
+        if is_empty_range(range.start_bound(), range.end_bound()) {
+            return Ok(0);
+        }
+        Ok(self.data.read().range(range).count() as u64)
+    }
+}
+
+/// Whether a range is empty. `BTreeMap::range` panics on such ranges when they are inverted or
+/// both ends are excluded.
+fn is_empty_range(start: Bound<&Vec<u8>>, end: Bound<&Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// A scan over a `StdBPlusTree`. Since the `BTreeMap` range iterator borrows the map, holding
+/// it would keep the read lock for the whole iteration; instead, each end of the scan copies
+/// batches of entries, taking the lock again for each batch and resuming past the last key it
+/// copied. Writes between batches are seen by the entries not copied yet.
+pub struct StdBPlusTreeIter {
+    data: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+    /// The range not copied by either end yet.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    /// Entries copied by the front, in ascending order.
+    front: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// Entries copied by the back, in descending order.
+    back: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl StdBPlusTreeIter {
+    fn new(data: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>, range: Range) -> Self {
+        Self {
+            data,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        }
+    }
+
+    /// Copies the next batch of entries to the front, or to the back in reverse.
+    fn fill(&mut self, reverse: bool) {
+        if is_empty_range(self.start.as_ref(), self.end.as_ref()) {
+            return;
+        }
+        let data = self.data.read();
+        let range = data.range::<Vec<u8>, _>((self.start.as_ref(), self.end.as_ref()));
+        let copy = |(key, value): (&Vec<u8>, &Vec<u8>)| (key.clone(), value.clone());
+        match reverse {
+            false => {
+                self.front.extend(range.take(SCAN_BATCH_SIZE).map(copy));
+                if let Some((key, _)) = self.front.back() {
+                    self.start = Bound::Excluded(key.clone());
+                }
+            }
+            true => {
+                self.back.extend(range.rev().take(SCAN_BATCH_SIZE).map(copy));
+                if let Some((key, _)) = self.back.back() {
+                    self.end = Bound::Excluded(key.clone());
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for StdBPlusTreeIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front.is_empty() {
+            self.fill(false);
+        }
+        // Once the range is exhausted, the remaining entries were copied by the back.
+        self.front.pop_front().or_else(|| self.back.pop_back()).map(Ok)
+    }
+}
+
+impl DoubleEndedIterator for StdBPlusTreeIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.back.is_empty() {
+            self.fill(true);
+        }
+        self.back.pop_front().or_else(|| self.front.pop_back()).map(Ok)
+    }
 }
 
 #[cfg(test)]
@@ -72,3 +310,138 @@ fn tests() -> Result<()> {
     use super::TestSuite;
     StdBPlusTree::test()
 }
+
+#[test]
+fn test_scan_batches() -> Result<()> {
+    let tree = StdBPlusTree::new();
+    let key = |i: u32| i.to_be_bytes().to_vec();
+    for i in 0..3000 {
+        tree.set(&key(i), vec![])?;
+    }
+
+    // Both ends take several batches and meet in the middle.
+    let mut scan = tree.scan(Range::from(key(10)..=key(2990)))?;
+    assert_eq!(scan.next().transpose()?.map(|(k, _)| k), Some(key(10)));
+    assert_eq!(scan.next_back().transpose()?.map(|(k, _)| k), Some(key(2990)));
+
+    // Writes are not blocked by the scan, and are seen past the copied batches.
+    tree.delete(&key(11))?;
+    tree.delete(&key(1500))?;
+    tree.set(&key(1500), b"new".to_vec())?;
+    let mut keys = vec![];
+    while let Some((k, v)) = scan.next().transpose()? {
+        if k == key(1500) {
+            assert_eq!(v, b"new");
+        }
+        keys.push(k);
+        if let Some((k, _)) = scan.next_back().transpose()? {
+            keys.push(k);
+        }
+    }
+    assert_eq!(keys.len(), 2979);
+    keys.sort();
+    keys.dedup();
+    assert_eq!(keys.len(), 2979);
+    assert_eq!(keys[0], key(11));
+
+    // Empty and inverted ranges.
+    assert_eq!(tree.scan(Range::from(key(5)..key(5)))?.count(), 0);
+    assert_eq!(tree.scan(Range::from(key(6)..key(5)))?.rev().count(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_restore() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("snapshot");
+    let tree = StdBPlusTree::new();
+    tree.set(b"a", b"1".to_vec())?;
+    tree.set(b"b", vec![])?;
+    tree.set(b"", b"empty key".to_vec())?;
+    tree.snapshot(&path)?;
+    tree.set(b"c", b"3".to_vec())?;
+
+    let restored = StdBPlusTree::restore(&path)?;
+    assert_eq!(restored.scan(Range::from(..))?.collect::<Result<Vec<_>>>()?, vec![
+        (b"".to_vec(), b"empty key".to_vec()),
+        (b"a".to_vec(), b"1".to_vec()),
+        (b"b".to_vec(), vec![]),
+    ]);
+    assert!(StdBPlusTree::restore(dir.path().join("missing")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_open_recovery() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let tree = StdBPlusTree::open(dir.path())?;
+    tree.set(b"a", b"1".to_vec())?;
+    tree.set(b"b", b"2".to_vec())?;
+    tree.checkpoint()?;
+    tree.set(b"c", b"3".to_vec())?;
+    tree.delete(b"a")?;
+    tree.set(b"b", b"22".to_vec())?;
+    tree.flush()?;
+    drop(tree);
+
+    // The operation log is replayed over the snapshot, ignoring a torn record at its end.
+    let mut oplog = std::fs::OpenOptions::new().append(true).open(dir.path().join(OPLOG_FILE))?;
+    std::io::Write::write_all(&mut oplog, &[0, 0, 0, 9, OP_SET])?;
+    let tree = StdBPlusTree::open(dir.path())?;
+    assert_eq!(tree.scan(Range::from(..))?.collect::<Result<Vec<_>>>()?, vec![
+        (b"b".to_vec(), b"22".to_vec()),
+        (b"c".to_vec(), b"3".to_vec()),
+    ]);
+    assert_eq!(std::fs::metadata(dir.path().join(OPLOG_FILE))?.len(), 0);
+
+    tree.delete(b"c")?;
+    tree.flush()?;
+    drop(tree);
+    let tree = StdBPlusTree::open(dir.path())?;
+    assert_eq!(tree.get(b"b")?, Some(b"22".to_vec()));
+    assert_eq!(tree.get(b"c")?, None);
+
+    // An open failing to write the snapshot keeps the operation log.
+    tree.set(b"d", b"4".to_vec())?;
+    tree.flush()?;
+    drop(tree);
+    std::fs::create_dir(dir.path().join("snapshot.tmp"))?;
+    assert!(StdBPlusTree::open(dir.path()).is_err());
+    std::fs::remove_dir(dir.path().join("snapshot.tmp"))?;
+    let tree = StdBPlusTree::open(dir.path())?;
+    assert_eq!(tree.get(b"d")?, Some(b"4".to_vec()));
+    Ok(())
+}
+
+/// Runs persistent trees in temporary directories.
+#[cfg(test)]
+#[derive(Default)]
+struct StdBPlusTreeEngine {
+    dir: Option<tempfile::TempDir>,
+}
+
+#[cfg(test)]
+impl super::TestEngine for StdBPlusTreeEngine {
+    type Store = StdBPlusTree;
+
+    fn create(&mut self) -> Result<StdBPlusTree> {
+        StdBPlusTree::open(self.dir.insert(tempfile::tempdir()?).path())
+    }
+
+    fn reopen(&mut self, store: StdBPlusTree) -> Result<StdBPlusTree> {
+        drop(store);
+        StdBPlusTree::open(self.dir.as_ref().expect("a tree was created").path())
+    }
+
+    fn compact(&mut self, store: &StdBPlusTree) -> Result<()> {
+        store.checkpoint()
+    }
+}
+
+#[test]
+fn test_std_b_plus_tree_model() -> Result<()> {
+    for seed in 0..10 {
+        super::ModelTest { seed, ..Default::default() }.run(&mut StdBPlusTreeEngine::default())?;
+    }
+    Ok(())
+}
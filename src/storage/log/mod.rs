@@ -0,0 +1,233 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::storage::kv::lsm_tree::checksum::crc32;
+
+/// Size of the length prefix and checksum of each record.
+const RECORD_HEADER_SIZE: u64 = 8;
+
+/// The location of a record payload in a log file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordPos {
+    /// Offset of the payload, after the header.
+    pub offset: u64,
+    /// Length of the payload.
+    pub len: u32,
+}
+
+/// An append-only log file of length-prefixed records, each with the CRC-32 of its payload. A
+/// crash may leave a torn record at the end of the file, which readers ignore.
+///
+/// Data alignment:
+///
+/// ```text
+///     | len (4B) | checksum (4B) | payload | len (4B) | checksum (4B) | payload | ...
+/// ```
+pub struct LogWriter {
+    file: File,
+    path: PathBuf,
+    size: u64,
+    /// Set when the part of a record left by a failed append could not be cut off. Appending
+    /// after it would misplace the records, so the writer fails until the file is rotated.
+    broken: bool,
+    /// Makes appends fail after writing the given number of bytes of the record.
+    #[cfg(test)]
+    fail_writes_after: Option<usize>,
+}
+
+impl LogWriter {
+    /// Creates a log file, truncating it if it exists.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(&path)?;
+        Ok(Self {
+            file,
+            path,
+            size: 0,
+            broken: false,
+            #[cfg(test)]
+            fail_writes_after: None,
+        })
+    }
+
+    /// Appends a record, returning the location of its payload. The record is only durable once
+    /// synced. A failed append leaves the file as it was before it.
+    pub fn append(&mut self, payload: &[u8]) -> Result<RecordPos> {
+        if self.broken {
+            return Err(Error::Internal(format!(
+                "Log file {} is broken by a failed append", self.path.display()
+            )));
+        }
+        let mut record = Vec::with_capacity(payload.len() + RECORD_HEADER_SIZE as usize);
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&crc32(payload).to_be_bytes());
+        record.extend_from_slice(payload);
+        if let Err(err) = self.write_record(&record) {
+            // Cut off the part of the record that was written, so the next one starts at `size`.
+            self.broken = self.file.set_len(self.size).is_err();
+            return Err(err.into());
+        }
+        let pos = RecordPos { offset: self.size + RECORD_HEADER_SIZE, len: payload.len() as u32 };
+        self.size += record.len() as u64;
+        Ok(pos)
+    }
+
+    fn write_record(&self, record: &[u8]) -> std::io::Result<()> {
+        #[cfg(test)]
+        if let Some(len) = self.fail_writes_after {
+            self.file.write_all_at(&record[..len.min(record.len())], self.size)?;
+            return Err(std::io::Error::other("Injected write failure"));
+        }
+        self.file.write_all_at(record, self.size)
+    }
+
+    /// Syncs the appended records to the disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Gets the size of the file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Gets the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Reads the records of a log file, which may still be appended to.
+pub struct LogReader {
+    file: File,
+}
+
+impl LogReader {
+    /// Opens a log file for reading.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self { file: File::open(path)? })
+    }
+
+    /// Reads the payload of a record, failing if it does not match its checksum.
+    pub fn read(&self, pos: RecordPos) -> Result<Vec<u8>> {
+        let mut record = vec![0; pos.len as usize + 4];
+        self.file.read_exact_at(&mut record, pos.offset - 4)?;
+        let payload = record.split_off(4);
+        if crc32(&payload) != u32::from_be_bytes(record.try_into().unwrap()) {
+            return Err(Error::Internal(format!(
+                "Checksum mismatch for the record at offset {}", pos.offset
+            )));
+        }
+        Ok(payload)
+    }
+
+    /// Iterates over the records of the file, up to the end of the file when the iteration
+    /// starts or to a torn record. A last record failing its checksum is torn, while others fail
+    /// the iteration.
+    pub fn iter(&self) -> Result<LogIter<'_>> {
+        Ok(LogIter { reader: self, offset: 0, size: self.file.metadata()?.len() })
+    }
+}
+
+/// Iterator over the records of a log file, along with their locations.
+pub struct LogIter<'a> {
+    reader: &'a LogReader,
+    offset: u64,
+    size: u64,
+}
+
+impl LogIter<'_> {
+    fn try_next(&mut self) -> Result<Option<(RecordPos, Vec<u8>)>> {
+        if self.offset + RECORD_HEADER_SIZE > self.size {
+            return Ok(None);
+        }
+        let mut len = [0; 4];
+        self.reader.file.read_exact_at(&mut len, self.offset)?;
+        let pos = RecordPos {
+            offset: self.offset + RECORD_HEADER_SIZE,
+            len: u32::from_be_bytes(len),
+        };
+        let end = pos.offset + pos.len as u64;
+        if end > self.size {
+            return Ok(None);
+        }
+        let payload = match self.reader.read(pos) {
+            Ok(payload) => payload,
+            Err(Error::Internal(_)) if end == self.size => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        self.offset = end;
+        Ok(Some((pos, payload)))
+    }
+}
+
+impl Iterator for LogIter<'_> {
+    type Item = Result<(RecordPos, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+
+
+#[test]
+fn test_log() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("log");
+    let mut writer = LogWriter::create(&path)?;
+    let first = writer.append(b"first")?;
+    let second = writer.append(b"")?;
+    let third = writer.append(b"third")?;
+    writer.sync()?;
+    assert_eq!(first, RecordPos { offset: 8, len: 5 });
+    assert_eq!(second, RecordPos { offset: 21, len: 0 });
+    assert_eq!(writer.size(), 34);
+
+    let reader = LogReader::open(&path)?;
+    assert_eq!(reader.read(third)?, b"third");
+    let records = reader.iter()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(records, vec![
+        (first, b"first".to_vec()),
+        (second, vec![]),
+        (third, b"third".to_vec()),
+    ]);
+
+    // A torn record at the end is ignored, whether it is cut short or its payload is wrong.
+    let mut file = OpenOptions::new().append(true).open(&path)?;
+    std::io::Write::write_all(&mut file, &[0, 0, 0, 9, b'x'])?;
+    assert_eq!(reader.iter()?.count(), 3);
+    file.set_len(writer.size())?;
+    let fourth = writer.append(b"fourth")?;
+    OpenOptions::new().write(true).open(&path)?.write_all_at(&[0; 6], fourth.offset)?;
+    assert_eq!(reader.iter()?.count(), 3);
+    assert!(reader.read(fourth).is_err());
+
+    // A corrupted record before the last one is an error.
+    writer.append(b"fifth")?;
+    assert!(reader.iter()?.any(|record| record.is_err()));
+    Ok(())
+}
+
+#[test]
+fn test_log_failed_append() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("log");
+    let mut writer = LogWriter::create(&path)?;
+    let first = writer.append(b"first")?;
+    writer.fail_writes_after = Some(10);
+    assert!(writer.append(b"second").is_err());
+    writer.fail_writes_after = None;
+
+    // The part of the failed record is cut off, so later records are where they are said to be.
+    let third = writer.append(b"third")?;
+    assert_eq!(third.offset, first.offset + first.len as u64 + RECORD_HEADER_SIZE);
+    let reader = LogReader::open(&path)?;
+    assert_eq!(reader.read(third)?, b"third");
+    let records = reader.iter()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(records, vec![(first, b"first".to_vec()), (third, b"third".to_vec())]);
+    Ok(())
+}